@@ -1,10 +1,13 @@
 global start
+global multiboot_information_address
 extern long_mode_start
 
 section .boottext exec
 bits 32
 start:
  	mov esp, stack_top
+	; ebx holds the physical address of the multiboot2 boot information, cpuid below overwrites it
+	mov [multiboot_information_address], ebx
 
 	call check_multiboot
 	call check_cpuid
@@ -137,6 +140,37 @@ setup_page_tables:
 	or eax, PAGE_TABLE_FLAGS
 	mov [page_table_l2+510*8], eax
 
+	; map the first 4 GiB of physical memory with huge pages to l4 entry 257 (PHYSICAL_MEMORY_OFFSET, SYNCID3)
+	; this way the kernel can access every page frame (e.g. page tables of processes) from any address space
+	mov eax, page_table_l3_physical
+	or eax, PAGE_TABLE_FLAGS
+	mov [page_table_l4+257*8], eax
+
+	mov esi, page_tables_l2_physical ; ESI = base address of first L2 table
+	mov ebx, 0                       ; EBX = L2 table index
+.l2_physical_table_loop:
+	mov eax, esi
+	or eax, PAGE_TABLE_FLAGS
+	mov [page_table_l3_physical + ebx*8], eax
+
+	mov ecx, 0
+.l2_physical_entry_loop:
+		; physical address = (EBX * 512 + ECX) * 2 MiB
+		mov eax, ebx
+		shl eax, 9
+		add eax, ecx
+		shl eax, 21
+		or eax, HUGE_PAGE_ENTRY_FLAGS
+		mov [esi + ecx*8], eax
+		inc ecx
+		cmp ecx, 512
+		jne .l2_physical_entry_loop
+
+	add esi, 4096
+	inc ebx
+	cmp ebx, 4
+	jne .l2_physical_table_loop
+
 	ret
 
 enable_paging:
@@ -155,9 +189,9 @@ enable_paging:
 	or eax, 1 << 8
 	wrmsr
 
-	; enable paging
+	; enable paging and write protection (kernel writes to read-only user pages fault, required for copy-on-write)
 	mov eax, cr0
-	or eax, 1 << 31
+	or eax, (1 << 31) | (1 << 16)
 	mov cr0, eax
 
 	ret
@@ -171,6 +205,8 @@ error:
 	hlt
 
 section .bootbss
+multiboot_information_address:
+	resd 1
 align 4096
 page_table_l4:
 	resb 4096
@@ -187,6 +223,11 @@ page_tables_l1_heap:
 ; special l1 page table which will be used to map non-sequential page frames like ACPI/HPET
 page_table_l1_special:
 	resb 4096
+; tables for the mapping of the physical memory, see setup_page_tables
+page_table_l3_physical:
+	resb 4096
+page_tables_l2_physical:
+	resb 4 * 4096
 ; TODO Why is stack in bss? Does this make sense? Also Stack is pretty small
 ; TODO stack should be moved to end of memory when paging is starting
 stack_bottom:
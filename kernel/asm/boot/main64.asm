@@ -1,5 +1,6 @@
 global long_mode_start
 extern kernel_main
+extern multiboot_information_address
 
 section .boottext exec
 bits 64
@@ -15,6 +16,9 @@ long_mode_start:
     mov rax, QWORD 0xffff800000000000
     add rsp, rax
 
+    ; first argument of kernel_main
+    mov edi, [multiboot_information_address]
+
     mov rax, QWORD kernel_main
 	call rax
     hlt
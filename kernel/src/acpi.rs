@@ -1,12 +1,12 @@
 use core::str;
 use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
 
-use crate::DEBUG;
 use crate::mem_config::{
-    BASE_PAGE_SIZE, HUGE_PAGE_ENTRY_FLAGS, PAGE_ENTRY_FLAGS_KERNELSPACE, PAGE_SIZE,
+    BASE_PAGE_SIZE, HUGE_PAGE_ENTRY_FLAGS, MAX_PAGE_FRAMES, PAGE_ENTRY_FLAGS_KERNELSPACE, PAGE_SIZE,
 };
+use crate::{DEBUG, ERROR};
 
-use crate::{mem, util::compare_str_to_memory};
+use crate::{mem, multiboot2};
 
 // TODO better use lazy static
 pub static HPET_COUNTER_VALUE_ADDRESS: AtomicPtr<AtomicU64> = AtomicPtr::new(core::ptr::null_mut());
@@ -30,18 +30,23 @@ struct XsdpT {
 // https://wiki.osdev.org/RSDT
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
-struct ACPISDTHeader {
-    signature: [u8; 4],
-    length: u32,
-    revision: u8,
-    checksum: u8,
-    oemid: [u8; 6],
-    oemtable_id: [u8; 8],
-    oemrevision: u32,
-    creator_id: u32,
-    creator_revision: u32,
+pub struct ACPISDTHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oemid: [u8; 6],
+    pub oemtable_id: [u8; 8],
+    pub oemrevision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
 }
 
+// size of the RSDP up to and including rsdt_address, covered by the original checksum
+const RSDP_V1_LENGTH: usize = 20;
+// offset of the index of the RTC century register in the FADT
+const FADT_CENTURY_OFFSET: usize = 108;
+
 // https://wiki.osdev.org/HPET
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
@@ -72,102 +77,142 @@ struct GeneralConfigurationRegister {
     config: u64, // only lowest 2 bits are in use
 }
 
+// GRUB passes a copy of the RSDP, so there is no need to search the EBDA and the BIOS ROM
 fn find_xsdp() -> *const XsdpT {
-    let ebda_address = 0x040E as *const u16;
-    let bios_rom_start = 0xE0000 as u32;
-    let bios_rom_end = 0xFFFFF as u32;
-
-    // Search in EBDA
-    let ebda_seg = unsafe { *ebda_address };
-    let ebda: u32 = (ebda_seg as u32) << 4;
-    for address in (ebda..(ebda + 1024)).step_by(16) {
-        if compare_str_to_memory("RSD PTR ", address as usize) {
-            return address as *const XsdpT;
-        }
-    }
+    let xsdp = match multiboot2::rsdp() {
+        Some(address) => address as *const XsdpT,
+        None => panic!("RSDP not found"),
+    };
 
-    // Search in BIOS ROM area
-    for address in (bios_rom_start..bios_rom_end).step_by(16) {
-        if compare_str_to_memory("RSD PTR ", address as usize) {
-            return address as *const XsdpT;
+    unsafe {
+        // the first 20 bytes are the ACPI 1.0 structure, the rest is covered by the extended checksum
+        if !checksum_valid(xsdp as usize, RSDP_V1_LENGTH) {
+            panic!("RSDP checksum invalid");
+        }
+        if (*xsdp).revision >= 2 && !checksum_valid(xsdp as usize, (*xsdp).length as usize) {
+            panic!("XSDP extended checksum invalid");
         }
     }
 
-    panic!("RSDP not found");
+    xsdp
 }
 
-// Xsdt Address
-// 64-bit physical address of the XSDT table. If you detect ACPI Version 2.0 you should use this table instead of RSDT even on IA-32, casting the address to uint32_t.
-fn find_hpet_table() -> *const HPET {
-    let xsdp = find_xsdp();
-    let offset: usize;
+// All bytes of a table including its checksum field have to add up to 0
+fn checksum_valid(address: usize, length: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
 
-    unsafe {
-        if (*xsdp).revision == 0 {
-            let page =
-                mem::allocate_page_frame_for_given_physical_address((*xsdp).rsdt_address as usize);
-            let virt_rsdt_address;
+// Maps the physical address of a table into the physical memory mapping and validates its checksum
+fn table_header(physical_address: u64) -> Option<*const ACPISDTHeader> {
+    if physical_address == 0
+        || physical_address as usize + core::mem::size_of::<ACPISDTHeader>()
+            > MAX_PAGE_FRAMES * PAGE_SIZE
+    {
+        ERROR!("ACPI table at {:#x} is not accessible", physical_address);
+        return None;
+    }
 
-            mem::map_page_in_page_tables(page, 0, 0, 510, 0, PAGE_ENTRY_FLAGS_KERNELSPACE);
+    let header = mem::physical_to_virtual(physical_address as usize) as *const ACPISDTHeader;
+    let length = unsafe { (*header).length } as usize;
 
-            offset = 0xffff_8000_3fc0_0000;
+    if length < core::mem::size_of::<ACPISDTHeader>()
+        || physical_address as usize + length > MAX_PAGE_FRAMES * PAGE_SIZE
+    {
+        ERROR!(
+            "ACPI table at {:#x} has an invalid length",
+            physical_address
+        );
+        return None;
+    }
 
-            virt_rsdt_address = ((*xsdp).rsdt_address as usize % PAGE_SIZE) + offset;
+    if !checksum_valid(header as usize, length) {
+        ERROR!(
+            "ACPI table {:?} at {:#x} has an invalid checksum",
+            str::from_utf8(unsafe { &(*header).signature }),
+            physical_address
+        );
+        return None;
+    }
 
-            DEBUG!("RSDT Address: {:?}", virt_rsdt_address as *const u64);
+    Some(header)
+}
 
-            let rsdt = virt_rsdt_address as *const ACPISDTHeader;
+// Returns the physical addresses of all tables listed in the XSDT, or in the RSDT before ACPI 2.0
+fn table_addresses() -> impl Iterator<Item = u64> {
+    let xsdp = find_xsdp();
 
-            DEBUG!("RSDT: {:?}", str::from_utf8(&(*rsdt).signature));
+    // Xsdt Address
+    // 64-bit physical address of the XSDT table. If you detect ACPI Version 2.0 you should use this table instead of RSDT even on IA-32, casting the address to uint32_t.
+    let (root_address, pointer_size) = unsafe {
+        if (*xsdp).revision >= 2 && (*xsdp).xsdt_address != 0 {
+            ((*xsdp).xsdt_address, 8)
+        } else {
+            ((*xsdp).rsdt_address as u64, 4)
+        }
+    };
+
+    let (pointers, entries) = match table_header(root_address) {
+        Some(root) => {
+            let length = unsafe { (*root).length } as usize;
+            (
+                root as usize + core::mem::size_of::<ACPISDTHeader>(),
+                (length - core::mem::size_of::<ACPISDTHeader>()) / pointer_size,
+            )
+        }
+        None => panic!("ACPI root table invalid"),
+    };
 
-            let entries = ((*rsdt).length as usize - core::mem::size_of::<ACPISDTHeader>()) / 4;
+    // The individual tables are pointed to by 32 bit (RSDT) or 64 bit (XSDT) pointers coming after the header
+    (0..entries).map(move |i| unsafe {
+        if pointer_size == 8 {
+            core::ptr::read_unaligned((pointers as *const u64).add(i))
+        } else {
+            core::ptr::read_unaligned((pointers as *const u32).add(i)) as u64
+        }
+    })
+}
 
-            DEBUG!("RSDT entries: {}", entries);
+// CMOS register of the RTC which holds the century, 0 if there is none
+pub fn rtc_century_register() -> u8 {
+    let _event = core::hint::black_box(crate::instrument!());
 
-            // The individual tables are pointed to 32bit pointers coming after the header
-            let table_ptrs =
-                (virt_rsdt_address as usize + core::mem::size_of::<ACPISDTHeader>()) as *const u32;
+    if !crate::config::get().acpi {
+        return 0;
+    }
 
-            DEBUG!(
-                "RSDT table_ptrs: {:?} (size of ACPISDTHeader: {:?})",
-                table_ptrs,
-                core::mem::size_of::<ACPISDTHeader>()
-            );
+    match find_table(b"FACP") {
+        Some(fadt) if unsafe { (*fadt).length } as usize > FADT_CENTURY_OFFSET => unsafe {
+            *(fadt as *const u8).add(FADT_CENTURY_OFFSET)
+        },
+        _ => 0,
+    }
+}
 
-            for i in 0..entries {
-                let header = core::ptr::read_unaligned(table_ptrs.add(i));
-                let virt_header: *const ACPISDTHeader;
-                if PAGE_SIZE == BASE_PAGE_SIZE {
-                    virt_header = ((header as usize % PAGE_SIZE) + offset) as *const ACPISDTHeader;
-                } else {
-                    virt_header = ((header as usize % PAGE_SIZE) + offset) as *const ACPISDTHeader;
-                }
+// Looks up a table like "APIC" (MADT), "FACP" (FADT), "HPET" or "MCFG" by its signature
+pub fn find_table(signature: &[u8; 4]) -> Option<*const ACPISDTHeader> {
+    let _event = core::hint::black_box(crate::instrument!());
 
-                DEBUG!(
-                    "ACPI Entry: {:?}",
-                    str::from_utf8(&(*virt_header).signature)
-                );
+    table_addresses()
+        .filter_map(table_header)
+        .find(|&header| unsafe { (*header).signature } == *signature)
+}
 
-                for j in 0..=3 {
-                    if "HPET".as_bytes()[j] != (*virt_header).signature[j] {
-                        break;
-                    }
-                    if j == 3 {
-                        return (virt_header as usize + core::mem::size_of::<ACPISDTHeader>())
-                            as *const HPET;
-                    }
-                }
-            }
-        } else {
-            // Implement ACPI 2.0+
-            panic!("You system seems to use ACPI 2.0 or newer, which is not implemented yet");
-        }
+fn find_hpet_table() -> *const HPET {
+    match find_table(b"HPET") {
+        Some(header) => (header as usize + core::mem::size_of::<ACPISDTHeader>()) as *const HPET,
+        None => panic!("HPET table not found"),
     }
-
-    panic!("HPET table not found");
 }
 
 pub fn init_acpi() {
+    for header in table_addresses().filter_map(table_header) {
+        DEBUG!(
+            "ACPI Entry: {:?}",
+            str::from_utf8(unsafe { &(*header).signature })
+        );
+    }
+
     let hpet = find_hpet_table();
     let offset: usize;
 
@@ -176,12 +221,16 @@ pub fn init_acpi() {
             mem::allocate_page_frame_for_given_physical_address((*hpet).base_address as usize);
 
         if PAGE_SIZE == BASE_PAGE_SIZE {
-            mem::map_page_in_page_tables(page, 0, 0, 510, 1, PAGE_ENTRY_FLAGS_KERNELSPACE);
             offset = 0xffff_8000_3fc0_1000;
         } else {
-            mem::map_page_in_page_tables(page, 0, 0, 508, 0, PAGE_ENTRY_FLAGS_KERNELSPACE);
             offset = 0xffff_8000_3fa0_0000;
         }
+        mem::map_page_in_page_tables(
+            mem::get_kernel_cr3(),
+            offset,
+            page,
+            PAGE_ENTRY_FLAGS_KERNELSPACE as usize,
+        );
 
         let capabilities = (((*hpet).base_address as usize % PAGE_SIZE) + offset)
             as *const GeneralCapabilitiesAndIdRegister;
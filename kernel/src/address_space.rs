@@ -0,0 +1,429 @@
+use crate::{
+    ERROR,
+    filesystem::FileHandle,
+    mem::{self, allocate_zeroed_page_frame},
+    mem_config::*,
+    memory_area::{MemoryAreaKind, MemoryAreas, PROT_EXEC, PROT_READ, PROT_WRITE},
+    process::PageTable,
+};
+extern crate alloc;
+use alloc::vec::Vec;
+
+// huge pages would need different page table walks
+const _: () = assert!(PAGE_SIZE != HUGE_PAGE_SIZE);
+
+// Page table entry flags for the pages of a memory area with the given protection
+// Inaccessible pages are kept present for the kernel, so their content survives until the protection changes again
+fn page_entry_flags(protection: u32) -> usize {
+    let mut flags = PAGE_ENTRY_PRESENT;
+
+    if protection & (PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        flags |= PAGE_ENTRY_USER;
+    }
+    if protection & PROT_WRITE != 0 {
+        flags |= PAGE_ENTRY_WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags |= PAGE_ENTRY_NO_EXECUTE;
+    }
+
+    flags
+}
+
+// size bytes of the program file at file_offset belong to the given virtual address
+#[derive(Debug, Clone, Copy)]
+struct FileSegment {
+    address: usize,
+    file_offset: usize,
+    size: usize,
+}
+
+/** The page tables and memory areas of a process; the upper half is shared with the kernel */
+pub struct AddressSpace {
+    // physical address of the l4 table, i.e. the value to load into cr3
+    l4_table: usize,
+    memory_areas: MemoryAreas,
+
+    // the pages of the code and data areas are read from the program file on their first access
+    program_file: Option<FileHandle>,
+    file_segments: Vec<FileSegment>,
+
+    // the maximum size of the stack area in bytes
+    stack_limit: usize,
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        self.clear();
+        mem::free_page_frame(self.l4_table);
+    }
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let l4_table = allocate_zeroed_page_frame();
+
+        // the upper half of the address space (kernel and physical memory mapping) is the same in every process
+        let kernel_l4_table = mem::physical_to_virtual(mem::get_kernel_cr3()) as *const PageTable;
+        let new_l4_table = mem::physical_to_virtual(l4_table) as *mut PageTable;
+
+        for i in (PAGE_TABLE_ENTRIES / 2)..PAGE_TABLE_ENTRIES {
+            unsafe {
+                (*new_l4_table).entry[i] = (*kernel_l4_table).entry[i];
+            }
+        }
+
+        Self {
+            l4_table,
+            memory_areas: MemoryAreas::new(),
+            program_file: None,
+            file_segments: Vec::new(),
+            stack_limit: USER_STACK_DEFAULT_LIMIT,
+        }
+    }
+
+    pub fn get_cr3(&self) -> usize {
+        self.l4_table
+    }
+
+    // whether the page tables are loaded on this CPU
+    pub fn is_active(&self) -> bool {
+        mem::get_cr3() & ENTRY_MASK == self.l4_table
+    }
+
+    pub fn memory_areas(&self) -> &MemoryAreas {
+        &self.memory_areas
+    }
+
+    pub fn memory_areas_mut(&mut self) -> &mut MemoryAreas {
+        &mut self.memory_areas
+    }
+
+    pub fn set_program_file(&mut self, file: FileHandle) {
+        self.program_file = Some(file);
+    }
+
+    // The bytes [file_offset, file_offset + size) of the program file are loaded to address on demand
+    pub fn add_file_segment(&mut self, address: usize, file_offset: usize, size: usize) {
+        self.file_segments.push(FileSegment {
+            address,
+            file_offset,
+            size,
+        });
+    }
+
+    // Backs the page aligned range [start, end) with zeroed page frames, keeping pages that are already mapped
+    pub fn map_range(&mut self, start: usize, end: usize, protection: u32) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            let entry = mem::get_or_create_page_table_entry(
+                self.l4_table,
+                vaddr,
+                PAGE_ENTRY_FLAGS_USERSPACE as usize,
+            );
+
+            unsafe {
+                if *entry & PAGE_ENTRY_PRESENT == 0 {
+                    *entry = allocate_zeroed_page_frame() | page_entry_flags(protection);
+                }
+            }
+        }
+    }
+
+    // Drops the pages of the page aligned range [start, end)
+    pub fn unmap_range(&mut self, start: usize, end: usize) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let is_active = self.is_active();
+
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            let entry = match mem::get_page_table_entry(self.l4_table, vaddr) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            unsafe {
+                if *entry & PAGE_ENTRY_PRESENT == 0 {
+                    continue;
+                }
+
+                mem::free_page_frame(*entry & ENTRY_MASK);
+                *entry = 0;
+            }
+
+            if is_active {
+                mem::flush_tlb_entry(vaddr);
+            }
+        }
+    }
+
+    // Changes the protection of the page aligned range [start, end), which has to belong to memory areas
+    pub fn protect_range(&mut self, start: usize, end: usize, protection: u32) -> bool {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if !self.memory_areas.protect_range(start, end, protection) {
+            return false;
+        }
+
+        let is_active = self.is_active();
+
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            let entry = match mem::get_page_table_entry(self.l4_table, vaddr) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            unsafe {
+                if *entry & PAGE_ENTRY_PRESENT == 0 {
+                    continue;
+                }
+
+                // a shared page stays read-only until it is copied on the first write
+                let mut flags = page_entry_flags(protection) | (*entry & PAGE_ENTRY_COPY_ON_WRITE);
+                if flags & PAGE_ENTRY_COPY_ON_WRITE != 0 {
+                    flags &= !PAGE_ENTRY_WRITABLE;
+                }
+
+                *entry = (*entry & ENTRY_MASK) | flags;
+            }
+
+            if is_active {
+                mem::flush_tlb_entry(vaddr);
+            }
+        }
+
+        true
+    }
+
+    // Maps the page containing the address if its memory area allows the access, e.g. on the first access to a
+    // page of the heap, or copies a page shared after a fork on the first write; returns false if the access is
+    // invalid
+    pub fn handle_page_fault(&mut self, address: usize, write: bool, execute: bool) -> bool {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let page = address & !PAGE_OFFSET_MASK;
+
+        if self.memory_areas.find(address).is_none() {
+            self.grow_stack(address);
+        }
+
+        let area = match self.memory_areas.find(address) {
+            Some(area) => *area,
+            None => return false,
+        };
+
+        if area.protection & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0
+            || (write && area.protection & PROT_WRITE == 0)
+            || (execute && area.protection & PROT_EXEC == 0)
+        {
+            return false;
+        }
+
+        let entry = mem::get_or_create_page_table_entry(
+            self.l4_table,
+            page,
+            PAGE_ENTRY_FLAGS_USERSPACE as usize,
+        );
+
+        // also the kernel may write to copy-on-write pages, e.g. when a syscall fills a user buffer
+        if unsafe { *entry } & PAGE_ENTRY_PRESENT != 0 {
+            return write && mem::resolve_copy_on_write(page);
+        }
+
+        // whatever is not loaded from the program file stays zero, e.g. the bss
+        let frame = allocate_zeroed_page_frame();
+
+        if let MemoryAreaKind::Code | MemoryAreaKind::Data = area.kind {
+            self.load_program_page(page, frame);
+        }
+
+        unsafe {
+            *entry = frame | page_entry_flags(area.protection);
+        }
+
+        true
+    }
+
+    // Maps the page of the address like a page fault would, unless it is accessible from user mode already
+    pub fn fault_in(&mut self, address: usize, write: bool) -> bool {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let mut required = PAGE_ENTRY_PRESENT | PAGE_ENTRY_USER;
+        if write {
+            required |= PAGE_ENTRY_WRITABLE;
+        }
+
+        match mem::get_page_table_entry(self.l4_table, address) {
+            Some(entry) if unsafe { *entry } & required == required => true,
+            _ => self.handle_page_fault(address, write, false),
+        }
+    }
+
+    pub fn set_stack_limit(&mut self, stack_limit: usize) {
+        self.stack_limit = stack_limit;
+    }
+
+    // Moves the bottom of the stack down to the page of an access just below it, as long as the stack stays within
+    // its limit and the page below the new bottom remains unmapped as guard page
+    fn grow_stack(&mut self, address: usize) {
+        let start = match self.memory_areas.find_kind_mut(MemoryAreaKind::Stack) {
+            Some(stack) => stack.start,
+            None => return,
+        };
+
+        let page = address & !PAGE_OFFSET_MASK;
+        let stack_end = USERSPACE_STACK_TOP_ADDRESS.next_multiple_of(PAGE_SIZE);
+
+        if address >= start
+            || address + USER_STACK_GROWTH_WINDOW < start
+            || stack_end - page > self.stack_limit
+            || page < PAGE_SIZE
+            || !self.memory_areas.is_free(page - PAGE_SIZE, start)
+        {
+            return;
+        }
+
+        if let Some(stack) = self.memory_areas.find_kind_mut(MemoryAreaKind::Stack) {
+            stack.start = page;
+        }
+    }
+
+    // Copies the parts of all file segments within the page to the page frame
+    fn load_program_page(&mut self, page: usize, frame: usize) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let Some(program_file) = &mut self.program_file else {
+            return;
+        };
+
+        for segment in &self.file_segments {
+            let start = segment.address.max(page);
+            let end = (segment.address + segment.size).min(page + PAGE_SIZE);
+
+            if start >= end {
+                continue;
+            }
+
+            program_file.offset = segment.file_offset + (start - segment.address);
+
+            let destination = (mem::physical_to_virtual(frame) + (start - page)) as *mut u8;
+            if program_file.read(destination, end - start) != (end - start) as u64 {
+                ERROR!("Could not read page {:#x} from the program file", page);
+            }
+        }
+    }
+
+    // Duplicates the lower half; all pages are shared copy-on-write afterwards
+    pub fn fork(&mut self) -> AddressSpace {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let mut child = AddressSpace::new();
+
+        let l4_table = mem::physical_to_virtual(self.l4_table) as *mut PageTable;
+        let child_l4_table = mem::physical_to_virtual(child.l4_table) as *mut PageTable;
+
+        for i in 0..(PAGE_TABLE_ENTRIES / 2) {
+            unsafe {
+                (*child_l4_table).entry[i] = AddressSpace::fork_entry(&mut (*l4_table).entry[i], 4);
+            }
+        }
+
+        // our TLB may still contain the pages as writable
+        if self.is_active() {
+            mem::set_cr3(self.l4_table);
+        }
+
+        child.memory_areas = self.memory_areas.clone();
+        child.program_file = self.program_file.clone();
+        child.file_segments = self.file_segments.clone();
+        child.stack_limit = self.stack_limit;
+        child
+    }
+
+    // Returns the entry for the child for an entry of a table of the given level (4 = l4)
+    fn fork_entry(entry: &mut usize, level: usize) -> usize {
+        if *entry & PAGE_ENTRY_PRESENT == 0 {
+            return 0;
+        }
+
+        // user pages are always mapped as 4 KiB pages, see mem::get_or_create_page_table_entry
+        if level > 1 && *entry & PAGE_ENTRY_HUGE != 0 {
+            panic!("Huge page in the lower half cannot be shared copy-on-write");
+        }
+
+        if level == 1 {
+            let frame = *entry & ENTRY_MASK;
+
+            if !mem::share_page_frame(frame) {
+                // the frame cannot be shared anymore, so the child gets its own copy
+                let copy = mem::allocate_page_frame();
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        mem::physical_to_virtual(frame) as *const u8,
+                        mem::physical_to_virtual(copy) as *mut u8,
+                        PAGE_SIZE,
+                    );
+                }
+                return copy | (*entry & !ENTRY_MASK);
+            }
+
+            // also read-only pages, as mprotect may make them writable later
+            *entry = (*entry & !PAGE_ENTRY_WRITABLE) | PAGE_ENTRY_COPY_ON_WRITE;
+
+            return *entry;
+        }
+
+        let table = mem::physical_to_virtual(*entry & ENTRY_MASK) as *mut PageTable;
+        let child_table = allocate_zeroed_page_frame();
+        let child_table_pointer = mem::physical_to_virtual(child_table) as *mut PageTable;
+
+        for i in 0..PAGE_TABLE_ENTRIES {
+            unsafe {
+                (*child_table_pointer).entry[i] =
+                    AddressSpace::fork_entry(&mut (*table).entry[i], level - 1);
+            }
+        }
+
+        child_table | (*entry & !ENTRY_MASK)
+    }
+
+    // Drops all pages and page tables of the lower half
+    // The page tables must not be loaded
+    pub fn clear(&mut self) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let l4_table = mem::physical_to_virtual(self.l4_table) as *mut PageTable;
+
+        for i in 0..(PAGE_TABLE_ENTRIES / 2) {
+            unsafe {
+                AddressSpace::free_entry(&mut (*l4_table).entry[i], 4);
+            }
+        }
+
+        self.memory_areas.clear();
+        self.program_file = None;
+        self.file_segments.clear();
+    }
+
+    fn free_entry(entry: &mut usize, level: usize) {
+        if *entry & PAGE_ENTRY_PRESENT == 0 {
+            return;
+        }
+
+        if level > 1 {
+            let table = mem::physical_to_virtual(*entry & ENTRY_MASK) as *mut PageTable;
+
+            for i in 0..PAGE_TABLE_ENTRIES {
+                unsafe {
+                    AddressSpace::free_entry(&mut (*table).entry[i], level - 1);
+                }
+            }
+        }
+
+        mem::free_page_frame(*entry & ENTRY_MASK);
+        *entry = 0;
+    }
+}
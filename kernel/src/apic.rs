@@ -0,0 +1,467 @@
+// https://wiki.osdev.org/MADT
+// https://wiki.osdev.org/APIC
+// https://wiki.osdev.org/IOAPIC
+
+use crate::acpi::{self, ACPISDTHeader};
+use crate::interrupt::LOCAL_APIC_TIMER_VECTOR;
+use crate::mem_config::{PAGE_ENTRY_FLAGS_MMIO, PAGE_SIZE};
+use crate::util::{out_port_b, read_msr, write_msr};
+use crate::{DEBUG, ERROR};
+use crate::{config, mem, time};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+// The registers are mapped into the special l1 page table next to the HPET, see acpi.rs
+const LOCAL_APIC_VIRTUAL_ADDRESS: usize = 0xffff_8000_3fc0_2000;
+const IO_APIC_VIRTUAL_ADDRESS: usize = 0xffff_8000_3fc0_3000;
+const MAX_IO_APICS: usize = 8;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const IA32_APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers
+const LOCAL_APIC_ID: usize = 0x20;
+const LOCAL_APIC_TASK_PRIORITY: usize = 0x80;
+const LOCAL_APIC_EOI: usize = 0xb0;
+const LOCAL_APIC_SPURIOUS_INTERRUPT_VECTOR: usize = 0xf0;
+const LOCAL_APIC_INTERRUPT_COMMAND_LOW: usize = 0x300;
+const LOCAL_APIC_INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const LOCAL_APIC_TIMER: usize = 0x320;
+const LOCAL_APIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LOCAL_APIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LOCAL_APIC_TIMER_DIVIDE: usize = 0x3e0;
+const LOCAL_APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+const INTERRUPT_COMMAND_INIT: u32 = 0b101 << 8;
+const INTERRUPT_COMMAND_STARTUP: u32 = 0b110 << 8;
+const INTERRUPT_COMMAND_ASSERT: u32 = 1 << 14;
+const INTERRUPT_COMMAND_PENDING: u32 = 1 << 12;
+
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const TIMER_INTERVAL_US: u64 = 10_000;
+
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
+
+// IOAPIC registers
+const IO_APIC_REGISTER_SELECT: usize = 0x00;
+const IO_APIC_WINDOW: usize = 0x10;
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+const LOCAL_APIC_PROCESSOR_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// ISA irqs which are routed through the IOAPIC: timer, keyboard, serial port COM1, primary ATA
+const ROUTED_ISA_IRQS: [u8; 4] = [0, 1, 4, 14];
+
+// irq n is delivered as interrupt vector IRQ_BASE_VECTOR + n, like with the remapped 8259
+const IRQ_BASE_VECTOR: u8 = 32;
+
+static LOCAL_APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+static IO_APIC_COUNT: AtomicUsize = AtomicUsize::new(0);
+// timer ticks per TIMER_INTERVAL_US, the same for all CPUs
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtHeader {
+    header: ACPISDTHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtEntryHeader {
+    entry_type: u8,
+    length: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtLocalApic {
+    header: MadtEntryHeader,
+    processor_id: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtIoApic {
+    header: MadtEntryHeader,
+    io_apic_id: u8,
+    _reserved: u8,
+    address: u32,
+    global_system_interrupt_base: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtInterruptSourceOverride {
+    header: MadtEntryHeader,
+    bus: u8,
+    source: u8,
+    global_system_interrupt: u32,
+    flags: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MadtLocalApicAddressOverride {
+    header: MadtEntryHeader,
+    _reserved: u16,
+    address: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub address: u64,
+    // first global system interrupt handled by this IOAPIC
+    pub global_system_interrupt_base: u32,
+}
+
+// An ISA irq which is connected to a different global system interrupt or uses a non-ISA polarity or trigger mode
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub source: u8,
+    pub global_system_interrupt: u32,
+    pub flags: u16,
+}
+
+/** Interrupt controller information of the ACPI "APIC" table */
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    // APIC ids of all processors which are enabled or can be brought online
+    pub processor_apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApic>,
+    pub interrupt_source_overrides: Vec<InterruptSourceOverride>,
+}
+
+pub fn parse_madt() -> Option<Madt> {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let header = acpi::find_table(b"APIC")?;
+    let madt = unsafe { core::ptr::read_unaligned(header as *const MadtHeader) };
+
+    let mut result = Madt {
+        local_apic_address: madt.local_apic_address as u64,
+        processor_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        interrupt_source_overrides: Vec::new(),
+    };
+
+    let mut entry = header as usize + core::mem::size_of::<MadtHeader>();
+    let end = header as usize + madt.header.length as usize;
+
+    while entry + core::mem::size_of::<MadtEntryHeader>() <= end {
+        let entry_header = unsafe { core::ptr::read_unaligned(entry as *const MadtEntryHeader) };
+        if entry_header.length < 2 || entry + entry_header.length as usize > end {
+            ERROR!("Invalid MADT entry of type {}", entry_header.entry_type);
+            break;
+        }
+
+        unsafe {
+            match entry_header.entry_type {
+                MADT_LOCAL_APIC => {
+                    let local_apic = core::ptr::read_unaligned(entry as *const MadtLocalApic);
+                    if local_apic.flags & (LOCAL_APIC_PROCESSOR_ENABLED | LOCAL_APIC_ONLINE_CAPABLE)
+                        != 0
+                    {
+                        result.processor_apic_ids.push(local_apic.apic_id);
+                    }
+                }
+                MADT_IO_APIC => {
+                    let io_apic = core::ptr::read_unaligned(entry as *const MadtIoApic);
+                    result.io_apics.push(IoApic {
+                        address: io_apic.address as u64,
+                        global_system_interrupt_base: io_apic.global_system_interrupt_base,
+                    });
+                }
+                MADT_INTERRUPT_SOURCE_OVERRIDE => {
+                    let source_override =
+                        core::ptr::read_unaligned(entry as *const MadtInterruptSourceOverride);
+                    result
+                        .interrupt_source_overrides
+                        .push(InterruptSourceOverride {
+                            source: source_override.source,
+                            global_system_interrupt: source_override.global_system_interrupt,
+                            flags: source_override.flags,
+                        });
+                }
+                MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    let address_override =
+                        core::ptr::read_unaligned(entry as *const MadtLocalApicAddressOverride);
+                    result.local_apic_address = address_override.address;
+                }
+                _ => {}
+            }
+        }
+
+        entry += entry_header.length as usize;
+    }
+
+    Some(result)
+}
+
+// Switches from the 8259 PIC to the local APIC and the IOAPICs; returns false if there is no usable MADT
+pub fn init_apic() -> bool {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    if !crate::config::get().acpi {
+        return false;
+    }
+
+    let Some(madt) = parse_madt() else {
+        ERROR!("No MADT found, using the 8259 PIC");
+        return false;
+    };
+
+    if madt.io_apics.is_empty() {
+        ERROR!("No IOAPIC found, using the 8259 PIC");
+        return false;
+    }
+
+    DEBUG!(
+        "MADT: local APIC at {:#x}, {} processors, {} IOAPICs",
+        madt.local_apic_address,
+        madt.processor_apic_ids.len(),
+        madt.io_apics.len()
+    );
+
+    disable_pic();
+
+    map_registers(madt.local_apic_address, LOCAL_APIC_VIRTUAL_ADDRESS);
+    init_local_apic();
+
+    for (i, io_apic) in madt.io_apics.iter().take(MAX_IO_APICS).enumerate() {
+        map_registers(io_apic.address, IO_APIC_VIRTUAL_ADDRESS + i * PAGE_SIZE);
+        mask_all_redirection_entries(i);
+    }
+    IO_APIC_COUNT.store(
+        core::cmp::min(madt.io_apics.len(), MAX_IO_APICS),
+        Ordering::Relaxed,
+    );
+
+    let destination = local_apic_id();
+    for irq in ROUTED_ISA_IRQS {
+        route_isa_irq(&madt, irq, destination);
+    }
+
+    true
+}
+
+// Enables the local APIC of the current CPU
+pub fn init_local_apic() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let apic_base = read_msr(IA32_APIC_BASE_MSR);
+    write_msr(IA32_APIC_BASE_MSR, apic_base | IA32_APIC_BASE_ENABLE);
+
+    // accept all interrupts
+    write_local_apic(LOCAL_APIC_TASK_PRIORITY, 0);
+    write_local_apic(
+        LOCAL_APIC_SPURIOUS_INTERRUPT_VECTOR,
+        LOCAL_APIC_SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_VECTOR as u32,
+    );
+
+    LOCAL_APIC_ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_local_apic_enabled() -> bool {
+    LOCAL_APIC_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn local_apic_id() -> u8 {
+    (read_local_apic(LOCAL_APIC_ID) >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+    write_local_apic(LOCAL_APIC_EOI, 0);
+}
+
+// Measures the frequency of the local APIC timer with the HPET
+pub fn calibrate_timer() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    write_local_apic(LOCAL_APIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write_local_apic(LOCAL_APIC_TIMER, TIMER_MASKED);
+    write_local_apic(LOCAL_APIC_TIMER_INITIAL_COUNT, u32::MAX);
+
+    time::busy_wait_us(TIMER_INTERVAL_US);
+
+    let ticks = u32::MAX - read_local_apic(LOCAL_APIC_TIMER_CURRENT_COUNT);
+    write_local_apic(LOCAL_APIC_TIMER_INITIAL_COUNT, 0);
+
+    DEBUG!(
+        "Local APIC timer: {} ticks per {} us",
+        ticks,
+        TIMER_INTERVAL_US
+    );
+    TIMER_INITIAL_COUNT.store(ticks, Ordering::Relaxed);
+}
+
+// Starts the periodic timer interrupt of the current CPU with the configured tick rate, see calibrate_timer
+pub fn start_timer() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    write_local_apic(LOCAL_APIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write_local_apic(
+        LOCAL_APIC_TIMER,
+        TIMER_PERIODIC | LOCAL_APIC_TIMER_VECTOR as u32,
+    );
+    let ticks_per_interrupt = TIMER_INITIAL_COUNT.load(Ordering::Relaxed) as u64 * 1_000_000
+        / TIMER_INTERVAL_US
+        / config::get().tick_rate as u64;
+    write_local_apic(
+        LOCAL_APIC_TIMER_INITIAL_COUNT,
+        ticks_per_interrupt.min(u32::MAX as u64) as u32,
+    );
+}
+
+// INIT-SIPI-SIPI sequence: https://wiki.osdev.org/SMP#AP_startup
+pub fn send_init(apic_id: u8) {
+    send_interrupt_command(apic_id, INTERRUPT_COMMAND_INIT | INTERRUPT_COMMAND_ASSERT);
+}
+
+// The processor starts in real mode at address page * 4 KiB
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_interrupt_command(
+        apic_id,
+        INTERRUPT_COMMAND_STARTUP | INTERRUPT_COMMAND_ASSERT | page as u32,
+    );
+}
+
+fn send_interrupt_command(apic_id: u8, command: u32) {
+    write_local_apic(LOCAL_APIC_INTERRUPT_COMMAND_HIGH, (apic_id as u32) << 24);
+    write_local_apic(LOCAL_APIC_INTERRUPT_COMMAND_LOW, command);
+
+    while read_local_apic(LOCAL_APIC_INTERRUPT_COMMAND_LOW) & INTERRUPT_COMMAND_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+// The 8259 is still remapped by init_idt, so spurious irqs from it do not look like exceptions
+fn disable_pic() {
+    out_port_b(0x21, 0xff);
+    out_port_b(0xA1, 0xff);
+}
+
+fn map_registers(physical_address: u64, virtual_address: usize) {
+    let page = mem::allocate_page_frame_for_given_physical_address(physical_address as usize);
+    mem::map_page_in_page_tables(
+        mem::get_kernel_cr3(),
+        virtual_address,
+        page,
+        PAGE_ENTRY_FLAGS_MMIO as usize,
+    );
+
+    if !(physical_address as usize).is_multiple_of(PAGE_SIZE) {
+        ERROR!(
+            "APIC registers at {:#x} are not page aligned",
+            physical_address
+        );
+    }
+}
+
+fn read_local_apic(register: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((LOCAL_APIC_VIRTUAL_ADDRESS + register) as *const u32) }
+}
+
+fn write_local_apic(register: usize, value: u32) {
+    unsafe {
+        core::ptr::write_volatile((LOCAL_APIC_VIRTUAL_ADDRESS + register) as *mut u32, value);
+    }
+}
+
+fn read_io_apic(index: usize, register: u32) -> u32 {
+    let base = IO_APIC_VIRTUAL_ADDRESS + index * PAGE_SIZE;
+    unsafe {
+        core::ptr::write_volatile((base + IO_APIC_REGISTER_SELECT) as *mut u32, register);
+        core::ptr::read_volatile((base + IO_APIC_WINDOW) as *const u32)
+    }
+}
+
+fn write_io_apic(index: usize, register: u32, value: u32) {
+    let base = IO_APIC_VIRTUAL_ADDRESS + index * PAGE_SIZE;
+    unsafe {
+        core::ptr::write_volatile((base + IO_APIC_REGISTER_SELECT) as *mut u32, register);
+        core::ptr::write_volatile((base + IO_APIC_WINDOW) as *mut u32, value);
+    }
+}
+
+fn redirection_entry_count(index: usize) -> u32 {
+    ((read_io_apic(index, IO_APIC_VERSION) >> 16) & 0xff) + 1
+}
+
+fn write_redirection_entry(index: usize, entry: u32, value: u64) {
+    write_io_apic(index, IO_APIC_REDIRECTION_TABLE + entry * 2, value as u32);
+    write_io_apic(
+        index,
+        IO_APIC_REDIRECTION_TABLE + entry * 2 + 1,
+        (value >> 32) as u32,
+    );
+}
+
+fn mask_all_redirection_entries(index: usize) {
+    for entry in 0..redirection_entry_count(index) {
+        write_redirection_entry(index, entry, REDIRECTION_MASKED);
+    }
+}
+
+// Delivers the ISA irq as vector IRQ_BASE_VECTOR + irq to the local APIC with the given id
+fn route_isa_irq(madt: &Madt, irq: u8, destination: u8) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    // ISA irqs are edge triggered and active high unless overridden
+    let mut global_system_interrupt = irq as u32;
+    let mut redirection = (IRQ_BASE_VECTOR + irq) as u64 | (destination as u64) << 56;
+
+    if let Some(source_override) = madt
+        .interrupt_source_overrides
+        .iter()
+        .find(|source_override| source_override.source == irq)
+    {
+        global_system_interrupt = source_override.global_system_interrupt;
+
+        // bits 0-1 polarity, bits 2-3 trigger mode; 0b11 means active low or level triggered
+        if source_override.flags & 0b11 == 0b11 {
+            redirection |= REDIRECTION_ACTIVE_LOW;
+        }
+        if (source_override.flags >> 2) & 0b11 == 0b11 {
+            redirection |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+    }
+
+    let io_apic_count = IO_APIC_COUNT.load(Ordering::Relaxed);
+    for (index, io_apic) in madt.io_apics.iter().take(io_apic_count).enumerate() {
+        let base = io_apic.global_system_interrupt_base;
+        if global_system_interrupt >= base
+            && global_system_interrupt < base + redirection_entry_count(index)
+        {
+            write_redirection_entry(index, global_system_interrupt - base, redirection);
+            return;
+        }
+    }
+
+    ERROR!(
+        "No IOAPIC handles irq {} (GSI {})",
+        irq,
+        global_system_interrupt
+    );
+}
@@ -0,0 +1,161 @@
+use crate::hdd::{self, LBA_SECTOR_SIZE};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+// the only block device so far
+pub const PRIMARY_ATA_DEVICE: u32 = 0;
+
+// number of blocks kept in memory, e.g. 1 MiB with 1 KiB blocks
+const BLOCK_CACHE_CAPACITY: usize = 1024;
+
+pub static BLOCK_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+pub static BLOCK_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    pub static ref BLOCK_CACHE: Mutex<BlockCache> =
+        Mutex::new(BlockCache::new(BLOCK_CACHE_CAPACITY));
+}
+
+// (device, block number)
+type BlockKey = (u32, u32);
+
+struct CachedBlock {
+    data: Vec<u8>,
+    // modified in memory, but not written back to the device yet
+    dirty: bool,
+    last_used: u64,
+}
+
+/**
+ * Cache of disk blocks with least recently used eviction.
+ *
+ * Writes only modify the cached copy; dirty blocks are written back when they are evicted or on flush.
+ */
+pub struct BlockCache {
+    blocks: BTreeMap<BlockKey, CachedBlock>,
+    // the keys of all cached blocks ordered by their last use
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    capacity: usize,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            capacity,
+        }
+    }
+
+    // Returns the block, reading it from the device if it is not cached yet
+    pub fn read(&mut self, device: u32, block_num: u32, block_size: usize) -> &[u8] {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let key = (device, block_num);
+
+        if self.blocks.contains_key(&key) {
+            BLOCK_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            self.touch(key);
+        } else {
+            BLOCK_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+            let data = read_from_device(block_num, block_size);
+            self.insert(key, data, false);
+        }
+
+        &self.blocks[&key].data
+    }
+
+    // Replaces the whole block
+    pub fn write(&mut self, device: u32, block_num: u32, data: &[u8]) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let key = (device, block_num);
+
+        match self.blocks.get_mut(&key) {
+            Some(block) => {
+                block.data.copy_from_slice(data);
+                block.dirty = true;
+                self.touch(key);
+            }
+            None => self.insert(key, data.to_vec(), true),
+        }
+    }
+
+    // Writes all dirty blocks back to their devices
+    pub fn flush(&mut self) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        for (&(_, block_num), block) in self.blocks.iter_mut().filter(|(_, block)| block.dirty) {
+            write_to_device(block_num, &block.data);
+            block.dirty = false;
+        }
+    }
+
+    fn touch(&mut self, key: BlockKey) {
+        self.tick += 1;
+
+        let block = self.blocks.get_mut(&key).unwrap();
+        self.lru.remove(&block.last_used);
+        block.last_used = self.tick;
+        self.lru.insert(self.tick, key);
+    }
+
+    fn insert(&mut self, key: BlockKey, data: Vec<u8>, dirty: bool) {
+        while self.blocks.len() >= self.capacity {
+            self.evict();
+        }
+
+        self.tick += 1;
+        self.blocks.insert(
+            key,
+            CachedBlock {
+                data,
+                dirty,
+                last_used: self.tick,
+            },
+        );
+        self.lru.insert(self.tick, key);
+    }
+
+    // Removes the least recently used block
+    fn evict(&mut self) {
+        let Some((_, key)) = self.lru.pop_first() else {
+            return;
+        };
+
+        if let Some(block) = self.blocks.remove(&key)
+            && block.dirty
+        {
+            write_to_device(key.1, &block.data);
+        }
+    }
+}
+
+// TODO the device is ignored as long as there is only the primary ATA disk
+fn read_from_device(block_num: u32, block_size: usize) -> Vec<u8> {
+    let mut buffer = alloc::vec![0u8; block_size];
+
+    hdd::hdd_read(
+        (block_num as usize * block_size / LBA_SECTOR_SIZE) as u32,
+        block_size.div_ceil(LBA_SECTOR_SIZE) as u8,
+        &mut buffer,
+        0,
+    );
+
+    buffer
+}
+
+fn write_to_device(block_num: u32, data: &[u8]) {
+    hdd::hdd_write(
+        (block_num as usize * data.len() / LBA_SECTOR_SIZE) as u32,
+        data.len().div_ceil(LBA_SECTOR_SIZE) as u8,
+        data,
+    );
+}
@@ -0,0 +1,111 @@
+use crate::{ERROR, multiboot2};
+use spin::Once;
+
+// Options passed on the kernel command line in grub.cfg, e.g.
+//   multiboot2 /boot/kernel.bin init=/doom loglevel=error console=serial trace=on noacpi
+
+static CONFIG: Once<KernelConfig> = Once::new();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Info,
+    Debug,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Console {
+    // serial port and VGA text mode
+    All,
+    Serial,
+    Vga,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    Us,
+    De,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct KernelConfig {
+    // program started as first process
+    pub init: &'static str,
+    pub log_level: LogLevel,
+    pub console: Console,
+    pub keyboard_layout: KeyboardLayout,
+    // record trace points for profiling
+    pub trace: bool,
+    pub acpi: bool,
+    // start the application processors
+    pub smp: bool,
+    // timer interrupts per second, which is also the resolution of sleeping
+    pub tick_rate: u32,
+    // switch to the VGA graphics mode during boot
+    pub vga: bool,
+    // print the ext2 superblock during boot
+    pub filesystem_info: bool,
+}
+
+impl KernelConfig {
+    const DEFAULT: Self = Self {
+        init: "/dash",
+        log_level: LogLevel::Debug,
+        console: Console::All,
+        keyboard_layout: KeyboardLayout::De,
+        trace: false,
+        acpi: true,
+        smp: true,
+        tick_rate: 100,
+        vga: false,
+        filesystem_info: false,
+    };
+
+    fn parse(command_line: &'static str) -> Self {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let mut config = Self::DEFAULT;
+
+        for option in command_line.split_ascii_whitespace() {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+
+            match (key, value) {
+                ("init", path) if path.starts_with('/') => config.init = path,
+                ("loglevel", "error" | "0") => config.log_level = LogLevel::Error,
+                ("loglevel", "info" | "1") => config.log_level = LogLevel::Info,
+                ("loglevel", "debug" | "2") => config.log_level = LogLevel::Debug,
+                ("console", "serial") => config.console = Console::Serial,
+                ("console", "vga") => config.console = Console::Vga,
+                ("console", "all") => config.console = Console::All,
+                ("keymap", "us") => config.keyboard_layout = KeyboardLayout::Us,
+                ("keymap", "de") => config.keyboard_layout = KeyboardLayout::De,
+                ("trace", "on") => config.trace = true,
+                ("trace", "off") => config.trace = false,
+                ("noacpi", "") => config.acpi = false,
+                ("nosmp", "") => config.smp = false,
+                ("hz", hz) if hz.parse::<u32>().is_ok_and(|hz| (19..=1000).contains(&hz)) => {
+                    config.tick_rate = hz.parse().unwrap()
+                }
+                ("vga", "on") => config.vga = true,
+                ("vga", "off") => config.vga = false,
+                ("fsinfo", "") => config.filesystem_info = true,
+                _ => ERROR!("Ignoring invalid kernel command line option {}", option),
+            }
+        }
+
+        config
+    }
+}
+
+// Parses the command line passed by the boot loader; must be called after multiboot2::init
+pub fn init() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let config = KernelConfig::parse(multiboot2::command_line().unwrap_or(""));
+    CONFIG.call_once(|| config);
+}
+
+// The defaults are used until init was called
+pub fn get() -> KernelConfig {
+    CONFIG.get().copied().unwrap_or(KernelConfig::DEFAULT)
+}
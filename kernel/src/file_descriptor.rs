@@ -0,0 +1,243 @@
+use crate::filesystem::FileHandle;
+use crate::interrupt;
+use crate::pipe::PipeEnd;
+use crate::wait_queue::Waiter;
+use crate::{ERROR, kprint};
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+/// Flag of a file descriptor: close it when the process calls execve
+pub const FD_CLOEXEC: u64 = 1;
+
+pub const MAX_FILE_DESCRIPTORS: u64 = 256;
+
+// result of reading from or writing to an open file
+pub enum IoResult {
+    // number of bytes transferred or u64::MAX on error
+    Done(u64),
+    // nothing can be transferred yet, the process has to wait and try again
+    Wait(Waiter),
+    // writing to a pipe without any readers
+    BrokenPipe,
+}
+
+// An open file (description) that can be shared by several file descriptors, e.g. after dup or fork
+pub enum OpenFile {
+    // keyboard input and screen output
+    Console,
+    File(FileHandle),
+    Pipe(PipeEnd),
+}
+
+impl OpenFile {
+    pub fn read(&mut self, buffer: *mut u8, len: usize) -> IoResult {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        match self {
+            OpenFile::Console => {
+                let waiter = interrupt::STDIN_WAIT_QUEUE.prepare_to_wait();
+
+                match read_stdin_buffer(buffer, len) {
+                    Some(bytes_read) => IoResult::Done(bytes_read),
+                    None => IoResult::Wait(waiter),
+                }
+            }
+            OpenFile::File(file_handle) => IoResult::Done(file_handle.read(buffer, len)),
+            OpenFile::Pipe(pipe_end) => pipe_end.read(buffer, len),
+        }
+    }
+
+    pub fn write(&mut self, buffer: *const u8, len: usize) -> IoResult {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        match self {
+            OpenFile::Console => {
+                match core::str::from_utf8(unsafe { core::slice::from_raw_parts(buffer, len) }) {
+                    Ok(msg) => IoResult::Done(kprint!("{}", msg) as u64),
+                    Err(_) => {
+                        ERROR!("\nCouldnt reconstruct string!\n");
+                        IoResult::Done(u64::MAX)
+                    }
+                }
+            }
+            OpenFile::File(file_handle) => IoResult::Done(file_handle.write(buffer, len)),
+            OpenFile::Pipe(pipe_end) => pipe_end.write(buffer, len),
+        }
+    }
+}
+
+// Moves a complete line or len bytes from the stdin buffer into buffer
+// Returns None if not enough input is available yet
+fn read_stdin_buffer(buffer: *mut u8, len: usize) -> Option<u64> {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    // the keyboard irq must not change the buffer meanwhile
+    interrupt::without_interrupts(|| {
+        let buffer_pos = interrupt::STDIN_BUFFER_POS.load(Ordering::Relaxed);
+
+        let bytes_to_read =
+            match (0..buffer_pos).find(|&i| unsafe { interrupt::STDIN_BUFFER[i] } == '\n') {
+                Some(newline_pos) => core::cmp::min(newline_pos + 1, len),
+                None if buffer_pos >= len => len,
+                None => return None,
+            };
+
+        unsafe {
+            for i in 0..bytes_to_read {
+                *buffer.add(i) = interrupt::STDIN_BUFFER[i] as u8;
+            }
+
+            // keep the rest (including the terminating '\0') for the next read
+            for i in bytes_to_read..=buffer_pos {
+                interrupt::STDIN_BUFFER[i - bytes_to_read] = interrupt::STDIN_BUFFER[i];
+            }
+        }
+        interrupt::STDIN_BUFFER_POS.store(buffer_pos - bytes_to_read, Ordering::Relaxed);
+
+        Some(bytes_to_read as u64)
+    })
+}
+
+#[derive(Clone)]
+struct FileDescriptor {
+    open_file: Arc<Mutex<OpenFile>>,
+    flags: u64,
+}
+
+// The file descriptors of a process; 0, 1 and 2 initially refer to the console
+#[derive(Clone)]
+pub struct FileDescriptorTable {
+    file_descriptors: BTreeMap<u64, FileDescriptor>,
+}
+
+impl FileDescriptorTable {
+    pub fn new() -> Self {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let console = Arc::new(Mutex::new(OpenFile::Console));
+
+        let mut file_descriptors = BTreeMap::new();
+        for fd in 0..3 {
+            file_descriptors.insert(
+                fd,
+                FileDescriptor {
+                    open_file: console.clone(),
+                    flags: 0,
+                },
+            );
+        }
+
+        Self { file_descriptors }
+    }
+
+    // Returns the new file descriptor or u64::MAX if the table is full
+    pub fn install(&mut self, open_file: OpenFile) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        self.insert_lowest(
+            FileDescriptor {
+                open_file: Arc::new(Mutex::new(open_file)),
+                flags: 0,
+            },
+            0,
+        )
+    }
+
+    pub fn get(&self, fd: u64) -> Option<Arc<Mutex<OpenFile>>> {
+        self.file_descriptors
+            .get(&fd)
+            .map(|file_descriptor| file_descriptor.open_file.clone())
+    }
+
+    // duplicates fd to the lowest free file descriptor >= min_fd
+    pub fn dup(&mut self, fd: u64, min_fd: u64) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        match self.file_descriptors.get(&fd) {
+            Some(file_descriptor) => {
+                // the close-on-exec flag is not shared
+                let open_file = file_descriptor.open_file.clone();
+                self.insert_lowest(
+                    FileDescriptor {
+                        open_file,
+                        flags: 0,
+                    },
+                    min_fd,
+                )
+            }
+            None => u64::MAX,
+        }
+    }
+
+    pub fn dup2(&mut self, old_fd: u64, new_fd: u64) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if new_fd >= MAX_FILE_DESCRIPTORS {
+            return u64::MAX;
+        }
+
+        match self.file_descriptors.get(&old_fd) {
+            Some(file_descriptor) => {
+                if old_fd != new_fd {
+                    let open_file = file_descriptor.open_file.clone();
+                    self.file_descriptors.insert(
+                        new_fd,
+                        FileDescriptor {
+                            open_file,
+                            flags: 0,
+                        },
+                    );
+                }
+                new_fd
+            }
+            None => u64::MAX,
+        }
+    }
+
+    pub fn close(&mut self, fd: u64) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        match self.file_descriptors.remove(&fd) {
+            Some(_) => 0,
+            None => u64::MAX,
+        }
+    }
+
+    pub fn get_flags(&self, fd: u64) -> u64 {
+        match self.file_descriptors.get(&fd) {
+            Some(file_descriptor) => file_descriptor.flags,
+            None => u64::MAX,
+        }
+    }
+
+    pub fn set_flags(&mut self, fd: u64, flags: u64) -> u64 {
+        match self.file_descriptors.get_mut(&fd) {
+            Some(file_descriptor) => {
+                file_descriptor.flags = flags & FD_CLOEXEC;
+                0
+            }
+            None => u64::MAX,
+        }
+    }
+
+    pub fn close_on_exec(&mut self) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        self.file_descriptors
+            .retain(|_, file_descriptor| file_descriptor.flags & FD_CLOEXEC == 0);
+    }
+
+    fn insert_lowest(&mut self, file_descriptor: FileDescriptor, min_fd: u64) -> u64 {
+        match (min_fd..MAX_FILE_DESCRIPTORS).find(|fd| !self.file_descriptors.contains_key(fd)) {
+            Some(fd) => {
+                self.file_descriptors.insert(fd, file_descriptor);
+                fd
+            }
+            None => u64::MAX,
+        }
+    }
+}
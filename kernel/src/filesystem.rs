@@ -1,40 +1,116 @@
 extern crate alloc;
 use crate::DEBUG;
 use crate::ERROR;
+use crate::block_cache::{BLOCK_CACHE, PRIMARY_ATA_DEVICE};
+use crate::hdd::LBA_SECTOR_SIZE;
 use crate::hdd_read_struct;
 use crate::kprintln;
+use crate::time;
+use alloc::string::String;
 use alloc::vec::Vec;
 use core::fmt;
 use lazy_static::lazy_static;
+use spin::Mutex;
 
 lazy_static! {
-    pub static ref FILE_SYSTEM: Ext2FileSystem = Ext2FileSystem::new();
+    pub static ref FILE_SYSTEM: Mutex<Ext2FileSystem> = Mutex::new(Ext2FileSystem::new());
 }
 
+const ROOT_INODE: u32 = 2;
+const DIRECT_BLOCKS: usize = 12;
+
+// inode modes
+const EXT2_S_IFMT: u16 = 0xF000;
+const EXT2_S_IFREG: u16 = 0x8000;
+const EXT2_S_IFDIR: u16 = 0x4000;
+
+// file types of directory entries, only stored if the file system has the filetype feature
+const EXT2_FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+const EXT2_FT_REG_FILE: u8 = 1;
+const EXT2_FT_DIR: u8 = 2;
+const EXT2_FT_CHRDEV: u8 = 3;
+const EXT2_FT_BLKDEV: u8 = 4;
+const EXT2_FT_FIFO: u8 = 5;
+const EXT2_FT_SOCK: u8 = 6;
+const EXT2_FT_SYMLINK: u8 = 7;
+
+// types of linux_dirent64 records, see dirent.h in libc
+const DT_UNKNOWN: u8 = 0;
+const DT_FIFO: u8 = 1;
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_BLK: u8 = 6;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+const DT_SOCK: u8 = 12;
+
+// flags of open, see fcntl.h in libc
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+pub const O_DIRECTORY: u32 = 0o200000;
+
 #[derive(Clone)]
 pub struct FileHandle {
+    inode_num: u32,
     inode: Inode,
+    flags: u32,
     pub offset: usize,
 }
 
 impl fmt::Debug for FileHandle {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("FileHandle")
+            .field("inode_num", &self.inode_num)
             .field("offset", &self.offset)
             .finish()
     }
 }
 
 impl FileHandle {
-    pub fn new(filename: &str, _mode: u32) -> Option<FileHandle> {
+    pub fn new(filename: &str, flags: u32) -> Option<FileHandle> {
         let _event = core::hint::black_box(crate::instrument!());
 
-        match FILE_SYSTEM.read_inode_by_path(filename) {
-            Some(inode) => return Some(Self { inode, offset: 0 }),
-            None => {
+        let mut fs = FILE_SYSTEM.lock();
+
+        let inode_num = match fs.find_inode_number_by_path(filename) {
+            Some(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return None,
+            Some(inode_num) => inode_num,
+            None if flags & O_CREAT != 0 => fs.create_file(filename)?,
+            None => return None,
+        };
+
+        let mut inode = fs.read_inode(inode_num);
+
+        if flags & O_DIRECTORY != 0 && !inode.is_directory() {
+            ERROR!("{} is not a directory", filename);
+            return None;
+        }
+
+        if flags & O_ACCMODE != O_RDONLY {
+            if inode.is_directory() {
+                ERROR!("{} is a directory", filename);
                 return None;
             }
+            if flags & O_TRUNC != 0 {
+                fs.truncate(inode_num, &mut inode, 0);
+            }
         }
+
+        // a new file might have been created or an existing one truncated
+        fs.sync();
+
+        Some(Self {
+            inode_num,
+            inode,
+            flags,
+            offset: 0,
+        })
     }
 
     pub fn size(&self) -> u32 {
@@ -44,85 +120,182 @@ impl FileHandle {
     pub fn read(&mut self, buffer: *mut u8, size: usize) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
 
+        if self.flags & O_ACCMODE == O_WRONLY || self.inode.is_directory() {
+            return u64::MAX;
+        }
+
+        let block_size = FILE_SYSTEM.lock().block_size as usize;
+
         let mut bytes_read = 0;
-        let mut buffer_offset = 0;
         let total_size = self.inode.size as usize;
-        let n: usize = FILE_SYSTEM.block_size as usize / core::mem::size_of::<u32>() as usize;
 
         // Don't read past the file's end
-        let remaining = total_size - self.offset;
+        let remaining = total_size.saturating_sub(self.offset);
         let to_read = core::cmp::min(size, remaining);
 
         while bytes_read < to_read {
-            let current_block_idx = self.offset / FILE_SYSTEM.block_size as usize;
-            let offset_in_block = self.offset % FILE_SYSTEM.block_size as usize;
-
-            // Get the block number based on index
-            let block_num = if current_block_idx < 12 {
-                // Direct blocks
-                self.inode.direct_blocks[current_block_idx]
-            } else if current_block_idx < 12 + n {
-                // Single indirect (256 blocks)
-                let indirect_block = FILE_SYSTEM.read_block(self.inode.indirect_block);
-                let idx = current_block_idx - 12;
-                unsafe { *(indirect_block.as_ptr().add(idx * 4) as *const u32) }
-            } else if current_block_idx < 12 + n + n * n {
-                // Double indirect (65,804 blocks total)
-                let double_indirect_block = FILE_SYSTEM.read_block(self.inode.double_indirect);
-                let primary_idx = (current_block_idx - (12 + n)) / n;
-                let secondary_idx = (current_block_idx - (12 + n)) % n;
-
-                if primary_idx < n {
-                    let primary_ptr = unsafe {
-                        *(double_indirect_block.as_ptr().add(primary_idx * 4) as *const u32)
-                    };
-                    let secondary_block = FILE_SYSTEM.read_block(primary_ptr);
-                    unsafe { *(secondary_block.as_ptr().add(secondary_idx * 4) as *const u32) }
-                } else {
-                    break;
+            let current_block_idx = self.offset / block_size;
+            let offset_in_block = self.offset % block_size;
+            let can_read = core::cmp::min(to_read - bytes_read, block_size - offset_in_block);
+
+            // the buffer may be user memory whose page is loaded from a file on the first access, so it is only
+            // written while the file system is unlocked
+            let data = {
+                let fs = FILE_SYSTEM.lock();
+                match fs.get_block_number(&self.inode, current_block_idx) {
+                    0 => None, // a hole in a sparse file
+                    block_num => Some(fs.with_block(block_num, |block| {
+                        block[offset_in_block..offset_in_block + can_read].to_vec()
+                    })),
                 }
-            }
-            // Triple indirect (16,843,020 blocks total)
-            else if current_block_idx < 12 + n + n * n + n * n * n {
-                let triple_indirect_block = FILE_SYSTEM.read_block(self.inode.triple_indirect);
-                let offset = current_block_idx - (12 + n + n * n);
-                let first_idx = offset / (n * n);
-                let second_idx = (offset / n) % n;
-                let third_idx = offset % n;
-
-                let first_ptr =
-                    unsafe { *(triple_indirect_block.as_ptr().add(first_idx * 4) as *const u32) };
-                let second_block = FILE_SYSTEM.read_block(first_ptr);
-                let second_ptr =
-                    unsafe { *(second_block.as_ptr().add(second_idx * 4) as *const u32) };
-                let third_block = FILE_SYSTEM.read_block(second_ptr);
-                unsafe { *(third_block.as_ptr().add(third_idx * 4) as *const u32) }
-            } else {
-                break;
             };
 
-            let block = FILE_SYSTEM.read_block(block_num);
-            let block_remaining = FILE_SYSTEM.block_size as usize - offset_in_block;
-            let can_read = core::cmp::min(to_read - bytes_read, block_remaining);
-
-            unsafe {
-                core::ptr::copy_nonoverlapping(
-                    block.as_ptr().add(offset_in_block),
-                    buffer.add(buffer_offset),
-                    can_read,
-                );
+            match data {
+                Some(data) => unsafe {
+                    core::ptr::copy_nonoverlapping(data.as_ptr(), buffer.add(bytes_read), can_read)
+                },
+                None => unsafe { core::ptr::write_bytes(buffer.add(bytes_read), 0, can_read) },
             }
 
             bytes_read += can_read;
-            buffer_offset += can_read;
             self.offset += can_read;
+        }
 
-            if bytes_read >= to_read {
-                break;
+        bytes_read as u64
+    }
+
+    // Fills buffer with linux_dirent64 records of the directory, starting at the current offset
+    // Returns the number of bytes filled, 0 at the end of the directory or u64::MAX on error
+    pub fn read_directory_entries(&mut self, buffer: *mut u8, size: usize) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if !self.inode.is_directory() {
+            return u64::MAX;
+        }
+
+        let fs = FILE_SYSTEM.lock();
+        let block_size = fs.block_size as usize;
+        let has_file_types =
+            fs.superblock.incompatible_features & EXT2_FEATURE_INCOMPAT_FILETYPE != 0;
+
+        // the records are copied to the buffer once the file system is unlocked, see read
+        let mut records: Vec<u8> = Vec::new();
+        let mut block_num = 0;
+        let mut block = Vec::new();
+
+        while self.offset < self.inode.size as usize {
+            let index = self.offset / block_size;
+            let offset_in_block = self.offset % block_size;
+
+            let current_block_num = fs.get_block_number(&self.inode, index);
+            if current_block_num == 0 {
+                self.offset = (index + 1) * block_size;
+                continue;
             }
+            if current_block_num != block_num {
+                block_num = current_block_num;
+                block = fs.read_block(block_num);
+            }
+
+            let entry = DirectoryEntry::read(&block, offset_in_block);
+            if entry.rec_len == 0 {
+                ERROR!("Corrupt directory entry in block {}", block_num);
+                return u64::MAX;
+            }
+
+            if entry.inode != 0 {
+                let name = entry.name(&block, offset_in_block);
+                // header, name and terminating null byte, 8 byte aligned
+                let record_size = (LINUX_DIRENT64_HEADER_SIZE + name.len() + 1 + 7) & !7;
+
+                if records.len() + record_size > size {
+                    if records.is_empty() {
+                        // the buffer is too small for a single record
+                        return u64::MAX;
+                    }
+                    break;
+                }
+
+                let d_type = match has_file_types {
+                    true => directory_entry_type(entry.file_type),
+                    false => DT_UNKNOWN,
+                };
+
+                let record = LinuxDirent64 {
+                    d_ino: entry.inode as u64,
+                    d_off: (self.offset + entry.rec_len as usize) as i64,
+                    d_reclen: record_size as u16,
+                    d_type,
+                };
+
+                let record_start = records.len();
+                records.resize(record_start + record_size, 0);
+
+                unsafe {
+                    let record_ptr = records.as_mut_ptr().add(record_start);
+                    core::ptr::write_unaligned(record_ptr as *mut LinuxDirent64, record);
+                    core::ptr::copy_nonoverlapping(
+                        name.as_ptr(),
+                        record_ptr.add(LINUX_DIRENT64_HEADER_SIZE),
+                        name.len(),
+                    );
+                }
+            }
+
+            self.offset += entry.rec_len as usize;
         }
 
-        bytes_read as u64
+        drop(fs);
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(records.as_ptr(), buffer, records.len());
+        }
+
+        records.len() as u64
+    }
+
+    pub fn write(&mut self, buffer: *const u8, size: usize) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if self.flags & O_ACCMODE == O_RDONLY {
+            ERROR!("File was not opened for writing");
+            return u64::MAX;
+        }
+
+        // the buffer is copied before locking the file system, see read
+        let data = unsafe { core::slice::from_raw_parts(buffer, size) }.to_vec();
+
+        let mut fs = FILE_SYSTEM.lock();
+
+        // another handle of the same file might have changed it meanwhile
+        self.inode = fs.read_inode(self.inode_num);
+
+        if self.flags & O_APPEND != 0 {
+            self.offset = self.inode.size as usize;
+        }
+
+        let bytes_written = fs.write_file(self.inode_num, &mut self.inode, self.offset, &data);
+        self.offset += bytes_written;
+        fs.sync();
+
+        if bytes_written == 0 && size > 0 {
+            return u64::MAX;
+        }
+        bytes_written as u64
+    }
+
+    pub fn truncate(&mut self, size: usize) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if self.flags & O_ACCMODE == O_RDONLY {
+            return u64::MAX;
+        }
+
+        let mut fs = FILE_SYSTEM.lock();
+        self.inode = fs.read_inode(self.inode_num);
+        fs.truncate(self.inode_num, &mut self.inode, size as u32);
+        fs.sync();
+        0
     }
 
     pub fn fseek(&mut self, offset: usize, origin: u32) -> u64 {
@@ -145,20 +318,75 @@ impl FileHandle {
 
         Stat {
             st_dev: 0,
-            st_ino: 0,
+            st_ino: self.inode_num as u64,
             st_mode: self.inode.mode as u64,
             st_nlink: self.inode.links_count as u64,
             st_uid: self.inode.uid as u64,
             st_gid: self.inode.gid as u64,
             st_rdev: 0,
             st_size: self.inode.size as u64,
-            st_blksize: FILE_SYSTEM.block_size as u64,
+            st_blksize: FILE_SYSTEM.lock().block_size as u64,
             st_blocks: self.inode.blocks as u64,
-            st_atime: self.inode.atime,
-            st_mtime: self.inode.mtime,
-            st_ctime: self.inode.ctime,
+            st_atime: self.inode.atime as u64,
+            st_mtime: self.inode.mtime as u64,
+            st_ctime: self.inode.ctime as u64,
+        }
+    }
+}
+
+pub fn is_directory(path: &str) -> bool {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let fs = FILE_SYSTEM.lock();
+    match fs.find_inode_number_by_path(path) {
+        Some(inode_num) => fs.read_inode(inode_num).is_directory(),
+        None => false,
+    }
+}
+
+// Joins a relative path with the working directory and removes empty, `.` and `..` components
+// `..` of the root directory is the root directory itself
+pub fn resolve_path(working_directory: &str, path: &str) -> String {
+    let base = if path.starts_with('/') {
+        ""
+    } else {
+        working_directory
+    };
+
+    let mut components: Vec<&str> = Vec::new();
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
         }
     }
+
+    let mut resolved = String::new();
+    for component in components {
+        resolved.push('/');
+        resolved.push_str(component);
+    }
+    if resolved.is_empty() {
+        resolved.push('/');
+    }
+    resolved
+}
+
+// Removes a file; directories are not supported
+pub fn unlink(path: &str) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let mut fs = FILE_SYSTEM.lock();
+    let result = match fs.unlink(path) {
+        true => 0,
+        false => u64::MAX,
+    };
+    fs.sync();
+
+    result
 }
 
 //https://slideplayer.com/slide/16554195/96/images/48/Linux+Example:+Ext2/3+Disk+Layout.jpg
@@ -265,11 +493,23 @@ pub struct Stat {
     pub st_size: u64,
     pub st_blksize: u64,
     pub st_blocks: u64,
-    pub st_atime: u32,
-    pub st_mtime: u32,
-    pub st_ctime: u32,
+    // seconds since the epoch, time_t is 64 bit in libc
+    pub st_atime: u64,
+    pub st_mtime: u64,
+    pub st_ctime: u64,
+}
+
+// The header of the records returned by getdents64, followed by the null terminated name
+#[repr(C, packed)]
+struct LinuxDirent64 {
+    d_ino: u64,
+    d_off: i64,
+    d_reclen: u16,
+    d_type: u8,
 }
 
+const LINUX_DIRENT64_HEADER_SIZE: usize = core::mem::size_of::<LinuxDirent64>();
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 struct DirectoryEntry {
@@ -319,48 +559,95 @@ impl Ext2FileSystem {
     fn read_block(&self, block_num: u32) -> Vec<u8> {
         let _event = core::hint::black_box(crate::instrument!());
 
-        let start = (block_num as usize) * (self.block_size as usize);
-        let mut buffer = alloc::vec![0u8; self.block_size as usize];
+        self.with_block(block_num, |block| block.to_vec())
+    }
 
-        crate::hdd::hdd_read(
-            start as u32 / crate::hdd::LBA_SECTOR_SIZE as u32,
-            ((self.block_size + crate::hdd::LBA_SECTOR_SIZE as u32 - 1)
-                / crate::hdd::LBA_SECTOR_SIZE as u32) as u8,
-            &mut buffer,
-            0,
-        );
+    // Calls f with the cached block, avoiding a copy
+    fn with_block<T>(&self, block_num: u32, f: impl FnOnce(&[u8]) -> T) -> T {
+        let mut block_cache = BLOCK_CACHE.lock();
+        f(block_cache.read(PRIMARY_ATA_DEVICE, block_num, self.block_size as usize))
+    }
+
+    fn write_block(&self, block_num: u32, data: &[u8]) {
+        let _event = core::hint::black_box(crate::instrument!());
 
-        buffer
+        BLOCK_CACHE
+            .lock()
+            .write(PRIMARY_ATA_DEVICE, block_num, data);
     }
 
-    fn read_inode(&self, inode_num: u32) -> Inode {
+    // Writes all modified blocks to the disk
+    fn sync(&self) {
         let _event = core::hint::black_box(crate::instrument!());
 
+        BLOCK_CACHE.lock().flush();
+    }
+
+    // Reads data at an arbitrary byte offset through the block cache
+    fn read_bytes(&self, offset: usize, buffer: &mut [u8]) {
+        let block_size = self.block_size as usize;
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let block_num = ((offset + done) / block_size) as u32;
+            let offset_in_block = (offset + done) % block_size;
+            let len = core::cmp::min(block_size - offset_in_block, buffer.len() - done);
+
+            self.with_block(block_num, |block| {
+                buffer[done..done + len]
+                    .copy_from_slice(&block[offset_in_block..offset_in_block + len])
+            });
+            done += len;
+        }
+    }
+
+    // Writes data at an arbitrary byte offset, keeping the rest of the affected blocks
+    fn write_bytes(&self, offset: usize, data: &[u8]) {
+        let block_size = self.block_size as usize;
+
+        let mut done = 0;
+        while done < data.len() {
+            let block_num = ((offset + done) / block_size) as u32;
+            let offset_in_block = (offset + done) % block_size;
+            let len = core::cmp::min(block_size - offset_in_block, data.len() - done);
+
+            let mut block = self.read_block(block_num);
+            block[offset_in_block..offset_in_block + len].copy_from_slice(&data[done..done + len]);
+            self.write_block(block_num, &block);
+            done += len;
+        }
+    }
+
+    fn inode_offset(&self, inode_num: u32) -> usize {
         let group = (inode_num - 1) / self.superblock.inodes_per_group;
         let index = (inode_num - 1) % self.superblock.inodes_per_group;
-        let block_group: &BlockGroupDescriptor = &self.block_groups[group as usize];
 
-        // Calculate the byte offset of the inode within its block group
-        // TODO: 1024 is the size of the superblock, but it is not in every block group
-        let offset_within_block_group = (block_group.inode_table * self.block_size)
-            + (index as u32 * self.superblock.inode_size as u32);
+        // inode_table is the absolute block number of the group's inode table
+        let inode_table = self.block_groups[group as usize].inode_table;
+        inode_table as usize * self.block_size as usize + index as usize * self.inode_size()
+    }
 
-        let offset_from_partition_start =
-            offset_within_block_group + group * self.superblock.blocks_per_group * self.block_size;
+    fn read_inode(&self, inode_num: u32) -> Inode {
+        let _event = core::hint::black_box(crate::instrument!());
 
-        DEBUG!(
-            "Reading inode {} at offset {:#x}",
-            inode_num,
-            offset_from_partition_start
-        );
+        let offset = self.inode_offset(inode_num);
 
-        let inode: Inode = hdd_read_struct!(offset_from_partition_start as usize, Inode);
+        DEBUG!("Reading inode {} at offset {:#x}", inode_num, offset);
+
+        let mut inode: Inode = unsafe { core::mem::zeroed() };
+        self.read_bytes(offset, as_bytes_mut(&mut inode));
 
         //DEBUG!("Inode read successfully: {:?}", inode);
 
         inode
     }
 
+    fn write_inode(&self, inode_num: u32, inode: &Inode) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        self.write_bytes(self.inode_offset(inode_num), as_bytes(inode));
+    }
+
     pub fn debug_print_superblock(&self) {
         let inode_count = self.superblock.inode_count;
         let block_count = self.superblock.block_count;
@@ -377,34 +664,386 @@ impl Ext2FileSystem {
         kprintln!("  Magic: {:#x}", magic);
     }
 
-    pub fn read_inode_by_path(&self, path: &str) -> Option<Inode> {
+    // Returns the inode number of the file or directory at the absolute path
+    // Walks the path component by component; `.` and `..` are ordinary entries of every directory
+    pub fn find_inode_number_by_path(&self, path: &str) -> Option<u32> {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let mut inode_num = ROOT_INODE;
+
+        for component in path.split('/').filter(|s| !s.is_empty()) {
+            let inode = self.read_inode(inode_num);
+            if !inode.is_directory() {
+                return None;
+            }
+            inode_num = self.lookup(&inode, component)?;
+        }
+
+        Some(inode_num)
+    }
+
+    // Searches all blocks of a directory for the entry with the given name
+    fn lookup(&self, directory: &Inode, name: &str) -> Option<u32> {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        for index in 0..self.block_count(directory) {
+            let block_num = self.get_block_number(directory, index);
+            if block_num == 0 {
+                continue;
+            }
+            let block = self.read_block(block_num);
+
+            let mut offset = 0;
+            while offset + DIRECTORY_ENTRY_HEADER_SIZE <= block.len() {
+                let entry = DirectoryEntry::read(&block, offset);
+                if entry.rec_len == 0 {
+                    ERROR!("Corrupt directory entry in block {}", block_num);
+                    break;
+                }
+                if entry.inode != 0 && entry.name(&block, offset) == name.as_bytes() {
+                    return Some(entry.inode);
+                }
+                offset += entry.rec_len as usize;
+            }
+        }
+
+        None
+    }
+
+    // Creates an empty regular file; the parent directory has to exist already
+    pub fn create_file(&mut self, path: &str) -> Option<u32> {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let (parent_path, name) = split_path(path)?;
+        let parent_num = self.find_inode_number_by_path(parent_path)?;
+        let mut parent = self.read_inode(parent_num);
+
+        if !parent.is_directory() || self.lookup(&parent, name).is_some() {
+            return None;
+        }
+
+        let inode_num = self.allocate_inode(self.group_of_inode(parent_num), false)?;
+
+        let mut inode: Inode = unsafe { core::mem::zeroed() };
+        inode.mode = EXT2_S_IFREG | 0o644;
+        inode.links_count = 1;
+        let now = time::get_unix_time();
+        inode.atime = now;
+        inode.ctime = now;
+        inode.mtime = now;
+
+        // clear the whole on-disk inode, it might be larger than our struct
+        let mut inode_bytes = alloc::vec![0u8; self.inode_size()];
+        inode_bytes[..core::mem::size_of::<Inode>()].copy_from_slice(as_bytes(&inode));
+        self.write_bytes(self.inode_offset(inode_num), &inode_bytes);
+
+        if !self.add_directory_entry(parent_num, &mut parent, name, inode_num, EXT2_FT_REG_FILE) {
+            self.free_inode(inode_num, false);
+            return None;
+        }
+
+        DEBUG!("Created {} as inode {}", path, inode_num);
+        Some(inode_num)
+    }
+
+    pub fn unlink(&mut self, path: &str) -> bool {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let Some((parent_path, name)) = split_path(path) else {
+            return false;
+        };
+        let Some(parent_num) = self.find_inode_number_by_path(parent_path) else {
+            return false;
+        };
+        let parent = self.read_inode(parent_num);
+        let Some(inode_num) = self.lookup(&parent, name) else {
+            return false;
+        };
+
+        let mut inode = self.read_inode(inode_num);
+        if inode.is_directory() {
+            ERROR!("Cannot unlink directory {}", path);
+            return false;
+        }
+
+        let Some(links_count) = inode.links_count.checked_sub(1) else {
+            ERROR!("Inode {} of {} has no links", inode_num, path);
+            return false;
+        };
+
+        self.remove_directory_entry(&parent, name);
+
+        // TODO the data should stay available to processes which still have the file opened
+        inode.links_count = links_count;
+        inode.ctime = time::get_unix_time();
+        if inode.links_count == 0 {
+            self.truncate(inode_num, &mut inode, 0);
+            inode.dtime = inode.ctime;
+            self.write_inode(inode_num, &inode);
+            self.free_inode(inode_num, false);
+        } else {
+            self.write_inode(inode_num, &inode);
+        }
+
+        true
+    }
+
+    // Writes data at offset, allocating blocks as needed; returns the number of bytes written
+    pub fn write_file(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        offset: usize,
+        data: &[u8],
+    ) -> usize {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let block_size = self.block_size as usize;
+        let group = self.group_of_inode(inode_num);
+        let mut bytes_written = 0;
+
+        while bytes_written < data.len() {
+            let position = offset + bytes_written;
+            let index = position / block_size;
+            let offset_in_block = position % block_size;
+            let len = core::cmp::min(block_size - offset_in_block, data.len() - bytes_written);
+
+            let mut block_num = self.get_block_number(inode, index);
+            let mut block = if block_num == 0 {
+                block_num = match self.allocate_block(group) {
+                    Some(block_num) => block_num,
+                    None => break,
+                };
+                if !self.set_block_number(inode, index, block_num, group) {
+                    self.free_block(block_num);
+                    break;
+                }
+                inode.blocks += self.sectors_per_block();
+                alloc::vec![0u8; block_size]
+            } else if len == block_size {
+                alloc::vec![0u8; block_size]
+            } else {
+                self.read_block(block_num)
+            };
+
+            block[offset_in_block..offset_in_block + len]
+                .copy_from_slice(&data[bytes_written..bytes_written + len]);
+            self.write_block(block_num, &block);
+
+            bytes_written += len;
+        }
+
+        if offset + bytes_written > inode.size as usize {
+            inode.size = (offset + bytes_written) as u32;
+        }
+        inode.mtime = time::get_unix_time();
+        inode.ctime = inode.mtime;
+        self.write_inode(inode_num, inode);
+
+        bytes_written
+    }
+
+    // Shrinks or grows a file; growing leaves a hole which reads as zeros
+    pub fn truncate(&mut self, inode_num: u32, inode: &mut Inode, size: u32) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let block_size = self.block_size as usize;
+        let n = self.pointers_per_block();
+        let old_block_count = self.block_count(inode);
+        let new_block_count = (size as usize + block_size - 1) / block_size;
+
+        for index in new_block_count..old_block_count {
+            if index >= DIRECT_BLOCKS + n + n * n {
+                break;
+            }
+            let block_num = self.get_block_number(inode, index);
+            if block_num != 0 {
+                self.set_block_number(inode, index, 0, 0);
+                self.free_block(block_num);
+                inode.blocks -= self.sectors_per_block();
+            }
+        }
+
+        // free the indirect blocks which are not needed anymore
+        if new_block_count <= DIRECT_BLOCKS && inode.indirect_block != 0 {
+            self.free_block(inode.indirect_block);
+            inode.indirect_block = 0;
+            inode.blocks -= self.sectors_per_block();
+        }
+
+        if inode.double_indirect != 0 {
+            let first_unused = (new_block_count.saturating_sub(DIRECT_BLOCKS + n) + n - 1) / n;
+            let mut double_indirect_block = self.read_block(inode.double_indirect);
+
+            for i in first_unused..n {
+                let indirect_block = get_block_pointer(&double_indirect_block, i);
+                if indirect_block != 0 {
+                    self.free_block(indirect_block);
+                    set_block_pointer(&mut double_indirect_block, i, 0);
+                    inode.blocks -= self.sectors_per_block();
+                }
+            }
+
+            if first_unused == 0 {
+                self.free_block(inode.double_indirect);
+                inode.double_indirect = 0;
+                inode.blocks -= self.sectors_per_block();
+            } else {
+                self.write_block(inode.double_indirect, &double_indirect_block);
+            }
+        }
+
+        if inode.triple_indirect != 0 && new_block_count < old_block_count {
+            // TODO files using triple indirect blocks cannot be written and are never shrunk
+            ERROR!("Truncating files with triple indirect blocks is not supported");
+        }
+
+        // the rest of the last block must read as zeros if the file grows again
+        let offset_in_block = size as usize % block_size;
+        if offset_in_block != 0 && size < inode.size {
+            let block_num = self.get_block_number(inode, new_block_count - 1);
+            if block_num != 0 {
+                let mut block = self.read_block(block_num);
+                block[offset_in_block..].fill(0);
+                self.write_block(block_num, &block);
+            }
+        }
+
+        inode.size = size;
+        inode.mtime = time::get_unix_time();
+        inode.ctime = inode.mtime;
+        self.write_inode(inode_num, inode);
+    }
+
+    // Inserts an entry into the first gap large enough or into a new block of the directory
+    fn add_directory_entry(
+        &mut self,
+        directory_num: u32,
+        directory: &mut Inode,
+        name: &str,
+        inode_num: u32,
+        file_type: u8,
+    ) -> bool {
         let _event = core::hint::black_box(crate::instrument!());
-        let mut current_inode = 2; // Root directory
-
-        for component in path
-            .trim_start_matches('/')
-            .split('/')
-            .filter(|s| !s.is_empty())
-        {
-            let dir_data = self.read_file(current_inode);
+
+        let needed = directory_entry_size(name.len());
+        let file_type =
+            if self.superblock.incompatible_features & EXT2_FEATURE_INCOMPAT_FILETYPE != 0 {
+                file_type
+            } else {
+                0
+            };
+
+        for index in 0..self.block_count(directory) {
+            let block_num = self.get_block_number(directory, index);
+            if block_num == 0 {
+                continue;
+            }
+            let mut block = self.read_block(block_num);
+
             let mut offset = 0;
+            while offset + DIRECTORY_ENTRY_HEADER_SIZE <= block.len() {
+                let mut entry = DirectoryEntry::read(&block, offset);
+                if entry.rec_len == 0 {
+                    break;
+                }
+
+                let used = if entry.inode == 0 {
+                    0
+                } else {
+                    directory_entry_size(entry.name_len as usize)
+                };
 
-            while offset < dir_data.len() {
-                let entry: &DirectoryEntry =
-                    unsafe { &*(dir_data[offset..].as_ptr() as *const DirectoryEntry) };
-                let name = core::str::from_utf8(
-                    &dir_data[offset + core::mem::size_of::<DirectoryEntry>()
-                        ..offset
-                            + core::mem::size_of::<DirectoryEntry>()
-                            + entry.name_len as usize],
-                )
-                .ok()?;
-
-                if name == component {
-                    current_inode = entry.inode;
-
-                    return Some(self.read_inode(current_inode));
+                if entry.rec_len as usize >= used + needed {
+                    let rec_len = entry.rec_len - used as u16;
+                    if used != 0 {
+                        // split the existing entry
+                        entry.rec_len = used as u16;
+                        entry.write(&mut block, offset);
+                    }
+                    write_directory_entry(
+                        &mut block,
+                        offset + used,
+                        inode_num,
+                        rec_len,
+                        name,
+                        file_type,
+                    );
+                    self.write_block(block_num, &block);
+                    return true;
                 }
+
+                offset += entry.rec_len as usize;
+            }
+        }
+
+        // all blocks are full, so the directory grows by one block
+        let group = self.group_of_inode(directory_num);
+        let index = self.block_count(directory);
+        let Some(block_num) = self.allocate_block(group) else {
+            return false;
+        };
+        if !self.set_block_number(directory, index, block_num, group) {
+            self.free_block(block_num);
+            return false;
+        }
+
+        let mut block = alloc::vec![0u8; self.block_size as usize];
+        write_directory_entry(
+            &mut block,
+            0,
+            inode_num,
+            self.block_size as u16,
+            name,
+            file_type,
+        );
+        self.write_block(block_num, &block);
+
+        directory.blocks += self.sectors_per_block();
+        directory.size += self.block_size;
+        self.write_inode(directory_num, directory);
+
+        true
+    }
+
+    // Removes an entry by merging it into the previous one; returns its inode number
+    fn remove_directory_entry(&mut self, directory: &Inode, name: &str) -> Option<u32> {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        for index in 0..self.block_count(directory) {
+            let block_num = self.get_block_number(directory, index);
+            if block_num == 0 {
+                continue;
+            }
+            let mut block = self.read_block(block_num);
+
+            let mut previous_offset = None;
+            let mut offset = 0;
+            while offset + DIRECTORY_ENTRY_HEADER_SIZE <= block.len() {
+                let mut entry = DirectoryEntry::read(&block, offset);
+                if entry.rec_len == 0 {
+                    break;
+                }
+
+                if entry.inode != 0 && entry.name(&block, offset) == name.as_bytes() {
+                    let inode_num = entry.inode;
+                    match previous_offset {
+                        Some(previous_offset) => {
+                            let mut previous = DirectoryEntry::read(&block, previous_offset);
+                            previous.rec_len += entry.rec_len;
+                            previous.write(&mut block, previous_offset);
+                        }
+                        None => {
+                            // the first entry of a block cannot be merged, so it is only marked unused
+                            entry.inode = 0;
+                            entry.write(&mut block, offset);
+                        }
+                    }
+                    self.write_block(block_num, &block);
+                    return Some(inode_num);
+                }
+
+                previous_offset = Some(offset);
                 offset += entry.rec_len as usize;
             }
         }
@@ -412,13 +1051,389 @@ impl Ext2FileSystem {
         None
     }
 
-    // TODO can we avoid to use heap allocation here?
-    fn read_file(&self, inode_num: u32) -> Vec<u8> {
+    // Maps the index of a block within a file to its block number; 0 means not allocated
+    fn get_block_number(&self, inode: &Inode, index: usize) -> u32 {
+        let n = self.pointers_per_block();
+
+        if index < DIRECT_BLOCKS {
+            return inode.direct_blocks[index];
+        }
+        let index = index - DIRECT_BLOCKS;
+
+        if index < n {
+            return self.read_block_pointer(inode.indirect_block, index);
+        }
+        let index = index - n;
+
+        if index < n * n {
+            let indirect_block = self.read_block_pointer(inode.double_indirect, index / n);
+            return self.read_block_pointer(indirect_block, index % n);
+        }
+        let index = index - n * n;
+
+        if index < n * n * n {
+            let double_indirect_block =
+                self.read_block_pointer(inode.triple_indirect, index / (n * n));
+            let indirect_block = self.read_block_pointer(double_indirect_block, (index / n) % n);
+            return self.read_block_pointer(indirect_block, index % n);
+        }
+
+        0
+    }
+
+    // Stores the block number for the index of a block within a file, allocating indirect blocks as needed
+    // The caller accounts for the data block in inode.blocks, but not for the indirect blocks
+    fn set_block_number(
+        &mut self,
+        inode: &mut Inode,
+        index: usize,
+        block_num: u32,
+        group: u32,
+    ) -> bool {
+        let n = self.pointers_per_block();
+
+        if index < DIRECT_BLOCKS {
+            inode.direct_blocks[index] = block_num;
+            return true;
+        }
+        let index = index - DIRECT_BLOCKS;
+
+        if index < n {
+            let Some(indirect_block) =
+                self.get_or_allocate_indirect_block(inode, inode.indirect_block, block_num, group)
+            else {
+                return block_num == 0;
+            };
+            inode.indirect_block = indirect_block;
+            self.write_block_pointer(indirect_block, index, block_num);
+            return true;
+        }
+        let index = index - n;
+
+        if index < n * n {
+            let Some(double_indirect_block) =
+                self.get_or_allocate_indirect_block(inode, inode.double_indirect, block_num, group)
+            else {
+                return block_num == 0;
+            };
+            inode.double_indirect = double_indirect_block;
+
+            let current = self.read_block_pointer(double_indirect_block, index / n);
+            let Some(indirect_block) =
+                self.get_or_allocate_indirect_block(inode, current, block_num, group)
+            else {
+                return block_num == 0;
+            };
+            if indirect_block != current {
+                self.write_block_pointer(double_indirect_block, index / n, indirect_block);
+            }
+            self.write_block_pointer(indirect_block, index % n, block_num);
+            return true;
+        }
+
+        // TODO support triple indirect blocks, they are only needed for files > 64 MiB with 1 KiB blocks
+        ERROR!("File too large, triple indirect blocks are not supported");
+        false
+    }
+
+    // Returns the existing indirect block or allocates a zeroed one if a block number has to be stored
+    fn get_or_allocate_indirect_block(
+        &mut self,
+        inode: &mut Inode,
+        indirect_block: u32,
+        block_num: u32,
+        group: u32,
+    ) -> Option<u32> {
+        if indirect_block != 0 {
+            return Some(indirect_block);
+        }
+        if block_num == 0 {
+            // nothing to clear
+            return None;
+        }
+
+        let indirect_block = self.allocate_block(group)?;
+        self.write_block(indirect_block, &alloc::vec![0u8; self.block_size as usize]);
+        inode.blocks += self.sectors_per_block();
+        Some(indirect_block)
+    }
+
+    fn read_block_pointer(&self, block_num: u32, index: usize) -> u32 {
+        if block_num == 0 {
+            return 0;
+        }
+        self.with_block(block_num, |block| get_block_pointer(block, index))
+    }
+
+    fn write_block_pointer(&mut self, block_num: u32, index: usize, value: u32) {
+        let mut block = self.read_block(block_num);
+        set_block_pointer(&mut block, index, value);
+        self.write_block(block_num, &block);
+    }
+
+    // Allocates a block, preferably in the given block group; the block is not cleared
+    fn allocate_block(&mut self, group: u32) -> Option<u32> {
         let _event = core::hint::black_box(crate::instrument!());
 
-        let inode = self.read_inode(inode_num);
-        let block = self.read_block(inode.direct_blocks[0]);
-        block[..inode.size as usize].to_vec()
+        let group_count = self.block_groups.len() as u32;
+        let blocks_per_group = self.superblock.blocks_per_group;
+        let first_data_block = self.superblock.first_data_block;
+        let block_count = self.superblock.block_count;
+
+        for i in 0..group_count {
+            let group = (group + i) % group_count;
+            if self.block_groups[group as usize].free_blocks_count == 0 {
+                continue;
+            }
+
+            let first_block = first_data_block + group * blocks_per_group;
+            let blocks_in_group = core::cmp::min(blocks_per_group, block_count - first_block);
+
+            let bitmap_block = self.block_groups[group as usize].block_bitmap;
+            let mut bitmap = self.read_block(bitmap_block);
+
+            if let Some(bit) = allocate_bit(&mut bitmap, 0, blocks_in_group) {
+                self.write_block(bitmap_block, &bitmap);
+                self.block_groups[group as usize].free_blocks_count -= 1;
+                self.superblock.free_blocks -= 1;
+                self.write_block_group_descriptor(group);
+                self.write_superblock();
+                return Some(first_block + bit);
+            }
+        }
+
+        ERROR!("No free blocks left");
+        None
+    }
+
+    fn free_block(&mut self, block_num: u32) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let group =
+            (block_num - self.superblock.first_data_block) / self.superblock.blocks_per_group;
+        let bit = (block_num - self.superblock.first_data_block) % self.superblock.blocks_per_group;
+
+        let bitmap_block = self.block_groups[group as usize].block_bitmap;
+        let mut bitmap = self.read_block(bitmap_block);
+        free_bit(&mut bitmap, bit);
+        self.write_block(bitmap_block, &bitmap);
+
+        self.block_groups[group as usize].free_blocks_count += 1;
+        self.superblock.free_blocks += 1;
+        self.write_block_group_descriptor(group);
+        self.write_superblock();
+    }
+
+    // Allocates an inode, preferably in the given block group
+    fn allocate_inode(&mut self, group: u32, is_directory: bool) -> Option<u32> {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let group_count = self.block_groups.len() as u32;
+        let inodes_per_group = self.superblock.inodes_per_group;
+        let first_inode = self.first_nonreserved_inode();
+
+        for i in 0..group_count {
+            let group = (group + i) % group_count;
+            if self.block_groups[group as usize].free_inodes_count == 0 {
+                continue;
+            }
+
+            // the reserved inodes at the beginning of group 0 must not be used
+            let first_bit = (first_inode - 1).saturating_sub(group * inodes_per_group);
+
+            let bitmap_block = self.block_groups[group as usize].inode_bitmap;
+            let mut bitmap = self.read_block(bitmap_block);
+
+            if let Some(bit) = allocate_bit(&mut bitmap, first_bit, inodes_per_group) {
+                self.write_block(bitmap_block, &bitmap);
+                self.block_groups[group as usize].free_inodes_count -= 1;
+                if is_directory {
+                    self.block_groups[group as usize].used_dirs_count += 1;
+                }
+                self.superblock.free_inodes -= 1;
+                self.write_block_group_descriptor(group);
+                self.write_superblock();
+                return Some(group * inodes_per_group + bit + 1);
+            }
+        }
+
+        ERROR!("No free inodes left");
+        None
+    }
+
+    fn free_inode(&mut self, inode_num: u32, is_directory: bool) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let group = self.group_of_inode(inode_num);
+        let bit = (inode_num - 1) % self.superblock.inodes_per_group;
+
+        let bitmap_block = self.block_groups[group as usize].inode_bitmap;
+        let mut bitmap = self.read_block(bitmap_block);
+        free_bit(&mut bitmap, bit);
+        self.write_block(bitmap_block, &bitmap);
+
+        self.block_groups[group as usize].free_inodes_count += 1;
+        if is_directory {
+            self.block_groups[group as usize].used_dirs_count -= 1;
+        }
+        self.superblock.free_inodes += 1;
+        self.write_block_group_descriptor(group);
+        self.write_superblock();
+    }
+
+    fn write_superblock(&self) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        // TODO the backup copies in the other block groups are not updated
+        self.write_bytes(1024, as_bytes(&self.superblock));
+    }
+
+    fn write_block_group_descriptor(&self, group: u32) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        // the descriptor table starts in the block after the superblock
+        let offset = (self.superblock.first_data_block as usize + 1) * self.block_size as usize
+            + group as usize * core::mem::size_of::<BlockGroupDescriptor>();
+        self.write_bytes(offset, as_bytes(&self.block_groups[group as usize]));
+    }
+
+    fn block_count(&self, inode: &Inode) -> usize {
+        (inode.size as usize + self.block_size as usize - 1) / self.block_size as usize
+    }
+
+    fn pointers_per_block(&self) -> usize {
+        self.block_size as usize / core::mem::size_of::<u32>()
+    }
+
+    // inode.blocks counts 512 byte sectors
+    fn sectors_per_block(&self) -> u32 {
+        self.block_size / LBA_SECTOR_SIZE as u32
+    }
+
+    fn group_of_inode(&self, inode_num: u32) -> u32 {
+        (inode_num - 1) / self.superblock.inodes_per_group
+    }
+
+    // revision 0 file systems have fixed values for these
+    fn inode_size(&self) -> usize {
+        match self.superblock.rev_level {
+            0 => 128,
+            _ => self.superblock.inode_size as usize,
+        }
+    }
+
+    fn first_nonreserved_inode(&self) -> u32 {
+        match self.superblock.rev_level {
+            0 => 11,
+            _ => self.superblock.first_nonreserved_inode,
+        }
+    }
+}
+
+impl Inode {
+    fn is_directory(&self) -> bool {
+        self.mode & EXT2_S_IFMT == EXT2_S_IFDIR
+    }
+}
+
+const DIRECTORY_ENTRY_HEADER_SIZE: usize = core::mem::size_of::<DirectoryEntry>();
+
+impl DirectoryEntry {
+    fn read(block: &[u8], offset: usize) -> DirectoryEntry {
+        unsafe { core::ptr::read_unaligned(block[offset..].as_ptr() as *const DirectoryEntry) }
+    }
+
+    fn write(&self, block: &mut [u8], offset: usize) {
+        block[offset..offset + DIRECTORY_ENTRY_HEADER_SIZE].copy_from_slice(as_bytes(self));
+    }
+
+    fn name<'a>(&self, block: &'a [u8], offset: usize) -> &'a [u8] {
+        let start = offset + DIRECTORY_ENTRY_HEADER_SIZE;
+        &block[start..core::cmp::min(start + self.name_len as usize, block.len())]
+    }
+}
+
+fn directory_entry_type(file_type: u8) -> u8 {
+    match file_type {
+        EXT2_FT_REG_FILE => DT_REG,
+        EXT2_FT_DIR => DT_DIR,
+        EXT2_FT_CHRDEV => DT_CHR,
+        EXT2_FT_BLKDEV => DT_BLK,
+        EXT2_FT_FIFO => DT_FIFO,
+        EXT2_FT_SOCK => DT_SOCK,
+        EXT2_FT_SYMLINK => DT_LNK,
+        _ => DT_UNKNOWN,
+    }
+}
+
+fn write_directory_entry(
+    block: &mut [u8],
+    offset: usize,
+    inode_num: u32,
+    rec_len: u16,
+    name: &str,
+    file_type: u8,
+) {
+    let entry = DirectoryEntry {
+        inode: inode_num,
+        rec_len,
+        name_len: name.len() as u8,
+        file_type,
+    };
+    entry.write(block, offset);
+
+    let start = offset + DIRECTORY_ENTRY_HEADER_SIZE;
+    block[start..start + name.len()].copy_from_slice(name.as_bytes());
+}
+
+// entries are 4 byte aligned
+fn directory_entry_size(name_len: usize) -> usize {
+    (DIRECTORY_ENTRY_HEADER_SIZE + name_len + 3) & !3
+}
+
+// Splits a path into the path of the parent directory and the name of the last component
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    if name.is_empty() || name.len() > 255 {
+        return None;
+    }
+    Some((parent_path, name))
+}
+
+fn get_block_pointer(block: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(block[index * 4..index * 4 + 4].try_into().unwrap())
+}
+
+fn set_block_pointer(block: &mut [u8], index: usize, value: u32) {
+    block[index * 4..index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// Sets the first clear bit in [first, end) and returns its index
+fn allocate_bit(bitmap: &mut [u8], first: u32, end: u32) -> Option<u32> {
+    let bit = (first..end).find(|&bit| bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0)?;
+    bitmap[bit as usize / 8] |= 1 << (bit % 8);
+    Some(bit)
+}
+
+fn free_bit(bitmap: &mut [u8], bit: u32) {
+    if bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0 {
+        ERROR!("Freeing bit {} which is not allocated", bit);
+    }
+    bitmap[bit as usize / 8] &= !(1 << (bit % 8));
+}
+
+// The on-disk representation of the packed structs
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>())
+    }
+}
+
+fn as_bytes_mut<T>(value: &mut T) -> &mut [u8] {
+    unsafe {
+        core::slice::from_raw_parts_mut(value as *mut T as *mut u8, core::mem::size_of::<T>())
     }
 }
 
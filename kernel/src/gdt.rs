@@ -1,5 +1,6 @@
 use crate::ERROR;
 use crate::mem_config::*;
+use crate::per_cpu::{self, MAX_CPUS};
 use core::arch::asm;
 use core::arch::global_asm;
 use core::mem;
@@ -24,7 +25,8 @@ struct GDT {
 
 // https://wiki.osdev.org/GDT_Tutorial#Flat_.2F_Long_Mode_Setup
 const GDT_ENTRY_MAX: usize = 7;
-static mut GDT_ENTRIES: [[u8; 8]; GDT_ENTRY_MAX] = [[0; 8]; GDT_ENTRY_MAX];
+// every CPU needs its own GDT, as the TSS descriptor is marked busy once it is loaded
+static mut GDT_ENTRIES: [[[u8; 8]; GDT_ENTRY_MAX]; MAX_CPUS] = [[[0; 8]; GDT_ENTRY_MAX]; MAX_CPUS];
 
 // https://wiki.osdev.org/TSS#Long_Mode
 #[repr(C)]
@@ -48,9 +50,17 @@ pub struct Tss {
     pub iopb: u16,
 }
 
-pub static mut TSS_ENTRY: Tss = Tss {
+// Stack used by the kernel while handling interrupts and syscalls; it lives in the higher half and is
+// therefore the same in every address space, which allows switching processes at the end of an interrupt
+#[repr(C, align(4096))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+
+static mut KERNEL_STACKS: [KernelStack; MAX_CPUS] =
+    [const { KernelStack([0; KERNEL_STACK_SIZE]) }; MAX_CPUS];
+
+pub static mut TSS_ENTRIES: [Tss; MAX_CPUS] = [Tss {
     reserved1: 0x0,
-    rsp0: (USERSPACE_STACK_TOP_ADDRESS - 511 * PAGE_SIZE) as u64, // TODO HackID1
+    rsp0: 0x0, // set in init_gdt
     rsp1: 0x0,
     rsp2: 0x0,
     reserved2: 0x0,
@@ -64,7 +74,7 @@ pub static mut TSS_ENTRY: Tss = Tss {
     reserved3: 0x0,
     reserved4: 0x0,
     iopb: 0x0,
-};
+}; MAX_CPUS];
 
 #[repr(C)]
 #[repr(packed(2))]
@@ -74,9 +84,20 @@ struct GdtPtrStruct {
 }
 
 pub fn init_gdt() {
+    init_gdt_for_cpu(0);
+}
+
+// Loads the GDT and TSS of the given CPU and sets up its per-CPU data
+pub fn init_gdt_for_cpu(cpu_index: usize) {
     let _event = core::hint::black_box(crate::instrument!());
+
+    let kernel_stack_top =
+        unsafe { addr_of!(KERNEL_STACKS[cpu_index]) } as u64 + KERNEL_STACK_SIZE as u64;
+
     unsafe {
-        GDT_ENTRIES = [
+        TSS_ENTRIES[cpu_index].rsp0 = kernel_stack_top;
+
+        GDT_ENTRIES[cpu_index] = [
             // Null descriptor
             encode_gdt_entry(GDT {
                 base: 0x0,
@@ -114,15 +135,17 @@ pub fn init_gdt() {
             }),
             //  Task State Segment
             encode_gdt_entry(GDT {
-                base: addr_of!(TSS_ENTRY) as *const _ as u32,
-                limit: addr_of!(TSS_ENTRY) as *const _ as u32 + mem::size_of::<Tss>() as u32 - 1,
+                base: addr_of!(TSS_ENTRIES[cpu_index]) as *const _ as u32,
+                limit: addr_of!(TSS_ENTRIES[cpu_index]) as *const _ as u32
+                    + mem::size_of::<Tss>() as u32
+                    - 1,
                 access_byte: 0x89,
                 flags: 0xc,
             }),
             //  Task State Segment, 2nd part --> special treatment for system segment descriptor in long mode
             encode_gdt_entry(GDT {
-                base: (addr_of!(TSS_ENTRY) as *const _ as u64 >> 48) as u32,
-                limit: (addr_of!(TSS_ENTRY) as *const _ as u64 >> 32) as u32,
+                base: (addr_of!(TSS_ENTRIES[cpu_index]) as *const _ as u64 >> 48) as u32,
+                limit: (addr_of!(TSS_ENTRIES[cpu_index]) as *const _ as u64 >> 32) as u32,
                 access_byte: 0x0,
                 flags: 0x0,
             }),
@@ -134,7 +157,8 @@ pub fn init_gdt() {
             //https://stackoverflow.com/a/64311274
             // https://github.com/rust-osdev/x86_64/blob/master/src/addr.rs#L100C9-L100C9
             // Complexity from last link probably not required
-            offset: (((&raw const GDT_ENTRIES as *const _ as u64) << 16) as i64 >> 16) as u64,
+            offset: (((&raw const GDT_ENTRIES[cpu_index] as *const _ as u64) << 16) as i64 >> 16)
+                as u64,
         };
         asm!("cli");
         asm!(
@@ -151,15 +175,27 @@ pub fn init_gdt() {
             ltr ax"
         );
     }
+
+    // the local APIC id as reported by cpuid, the APIC itself might not be mapped yet
+    let apic_id = (core::arch::x86_64::__cpuid(1).ebx >> 24) as u8;
+    per_cpu::init_per_cpu(cpu_index, apic_id, kernel_stack_top);
+
+    // the syscall MSRs refer to the segments of this GDT
+    unsafe extern "C" {
+        fn init_syscalls();
+    }
+    unsafe {
+        init_syscalls();
+    }
 }
 
-fn _init_tss() {
+fn _init_tss(cpu_index: usize) {
     let _event = core::hint::black_box(crate::instrument!());
     unsafe {
         // Initialize the TSS fields
-        TSS_ENTRY = Tss {
+        TSS_ENTRIES[cpu_index] = Tss {
             reserved1: 0x0,
-            rsp0: (USERSPACE_STACK_TOP_ADDRESS - 511 * PAGE_SIZE) as u64, // TODO HackID1
+            rsp0: per_cpu::current().kernel_stack_top,
             rsp1: 0x0,
             rsp2: 0x0,
             reserved2: 0x0,
@@ -11,9 +11,59 @@ static ATA_STATUS_ERR: u8 = 0x01;
 // Commands
 static ATA_CMD_READ: u8 = 0x20;
 static ATA_CMD_WRITE: u8 = 0x30;
+static ATA_CMD_CACHE_FLUSH: u8 = 0xE7;
 
 pub static LBA_SECTOR_SIZE: usize = 512;
 
+fn ata_pio_error(base: u32, status: u8, operation: &str) -> ! {
+    let err = in_port_b(base + 1);
+    if err & 0x04 != 0 {
+        ERROR!("ABRT: command aborted (bad LBA?)");
+    }
+    if err & 0x10 != 0 {
+        ERROR!("IDNF: sector not found (bad LBA?)");
+    }
+    if err & 0x40 != 0 {
+        ERROR!("UNC: uncorrectable data error");
+    }
+    panic!(
+        "HDD {} error: status={:02x}, error={:02x}",
+        operation, status, err
+    );
+}
+
+// Waits until the drive is ready to transfer the data of a sector
+fn ata_pio_wait_for_data(base: u32, operation: &str) {
+    loop {
+        let status = in_port_b(base + 7);
+
+        if status & ATA_STATUS_BSY != 0 {
+            continue; // still busy
+        }
+        if status & ATA_STATUS_ERR != 0 {
+            ata_pio_error(base, status, operation);
+        }
+        if status & ATA_STATUS_DRQ != 0 {
+            break; // ready to transfer data
+        }
+    }
+}
+
+// Waits until the drive has finished the current command
+fn ata_pio_wait_for_completion(base: u32, operation: &str) {
+    loop {
+        let status = in_port_b(base + 7);
+
+        if status & ATA_STATUS_BSY != 0 {
+            continue; // still busy
+        }
+        if status & ATA_STATUS_ERR != 0 {
+            ata_pio_error(base, status, operation);
+        }
+        break;
+    }
+}
+
 pub fn ata_pio_read_sector(base: u32, lba: u32, buffer: &mut [u8], skip: usize) {
     let _event = core::hint::black_box(crate::instrument!());
 
@@ -30,31 +80,9 @@ pub fn ata_pio_read_sector(base: u32, lba: u32, buffer: &mut [u8], skip: usize)
     // Send READ command
     out_port_b(base + 7, ATA_CMD_READ);
 
-    let mut pos = 0;
-
-    loop {
-        let status = in_port_b(base + 7);
+    ata_pio_wait_for_data(base, "read");
 
-        if status & ATA_STATUS_BSY != 0 {
-            continue; // still busy
-        }
-        if status & ATA_STATUS_ERR != 0 {
-            let err = in_port_b(base + 1);
-            if err & 0x04 != 0 {
-                ERROR!("ABRT: command aborted (bad LBA?)");
-            }
-            if err & 0x10 != 0 {
-                ERROR!("IDNF: sector not found (bad LBA?)");
-            }
-            if err & 0x40 != 0 {
-                ERROR!("UNC: uncorrectable data error");
-            }
-            panic!("HDD read error: status={:02x}, error={:02x}", status, err);
-        }
-        if status & ATA_STATUS_DRQ != 0 {
-            break; // ready to transfer data
-        }
-    }
+    let mut pos = 0;
 
     // Read data (max 256 words per sector)
     // always go through the entire sector even if not all data is needed
@@ -129,3 +157,50 @@ macro_rules! hdd_read_struct {
         unsafe { core::ptr::read(struct_bytes.as_ptr() as *const $struct_type) }
     }};
 }
+
+// Writes one sector; missing bytes at the end of a short buffer are written as zeros
+pub fn ata_pio_write_sector(base: u32, lba: u32, buffer: &[u8]) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    while (in_port_b(base + 7) & ATA_STATUS_BSY) != 0 {}
+
+    // Select the drive and set LBA
+    out_port_b(base + 6, (0xE0 | ((lba >> 24) & 0x0F)) as u8); // Drive/Head
+    out_port_b(base + 2, 1); // Sector count
+    out_port_b(base + 3, (lba & 0xFF) as u8); // LBA low
+    out_port_b(base + 4, ((lba >> 8) & 0xFF) as u8); // LBA mid
+    out_port_b(base + 5, ((lba >> 16) & 0xFF) as u8); // LBA high
+
+    // Send WRITE command
+    out_port_b(base + 7, ATA_CMD_WRITE);
+
+    ata_pio_wait_for_data(base, "write");
+
+    for i in 0..LBA_SECTOR_SIZE / 2 {
+        let low = buffer.get(2 * i).copied().unwrap_or(0) as u16;
+        let high = buffer.get(2 * i + 1).copied().unwrap_or(0) as u16;
+        out_port_w(base, low | (high << 8));
+    }
+
+    ata_pio_wait_for_completion(base, "write");
+}
+
+// Makes sure that all written sectors actually reached the disk
+pub fn ata_pio_flush_cache(base: u32) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    out_port_b(base + 7, ATA_CMD_CACHE_FLUSH);
+    ata_pio_wait_for_completion(base, "cache flush");
+}
+
+pub fn hdd_write(lba: u32, sector_count: u8, buffer: &[u8]) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    for i in 0..sector_count as usize {
+        let start = core::cmp::min(i * LBA_SECTOR_SIZE, buffer.len());
+        let end = core::cmp::min(start + LBA_SECTOR_SIZE, buffer.len());
+        ata_pio_write_sector(ATA_PRIMARY_BASE, lba + i as u32, &buffer[start..end]);
+    }
+
+    ata_pio_flush_cache(ATA_PRIMARY_BASE);
+}
@@ -4,19 +4,36 @@
 use crate::DEBUG;
 use crate::ERROR;
 use crate::USERLAND;
-use crate::keyboard;
+use crate::apic;
+use crate::keyboard::{self, KeyEvent};
 use crate::kprint;
+use crate::mem;
+use crate::mem_config::KERNEL_HIGHER_HALF_BASE;
+use crate::per_cpu::{self, PerCpu};
 use crate::profiling;
+use crate::timer;
+use crate::user_memory;
 use crate::userland;
-use crate::util::out_port_b;
+use crate::util::{self, out_port_b};
+use crate::wait_queue::WaitQueue;
 use core::arch::asm;
 use core::arch::global_asm;
 use core::sync::atomic::AtomicUsize;
+use lazy_static::lazy_static;
 
-global_asm!(include_str!("interrupt.S"));
+extern crate alloc;
+use alloc::sync::Arc;
 
-#[unsafe(no_mangle)]
-pub static mut SCHEDULING_BLOCKED: u8 = 0;
+global_asm!(
+    include_str!("interrupt.S"),
+    PER_CPU_STACK_FRAME = const core::mem::offset_of!(PerCpu, stack_frame),
+    PER_CPU_PUSHED_REGISTERS = const core::mem::offset_of!(PerCpu, pushed_registers),
+    PER_CPU_INT_NO = const core::mem::offset_of!(PerCpu, int_no),
+    PER_CPU_SCHEDULING_BLOCKED = const core::mem::offset_of!(PerCpu, scheduling_blocked),
+);
+
+/// Interrupt vector of the local APIC timer, which drives the scheduler on the application processors
+pub const LOCAL_APIC_TIMER_VECTOR: u8 = 48;
 
 #[repr(C, packed(2))]
 #[derive(Clone, Copy)]
@@ -47,18 +64,86 @@ static mut IDT_ENTRIES: [IdtEntryStruct; 256] = [IdtEntryStruct {
     reserved: 0,
 }; 256];
 
-pub static mut STDIN_BUFFER: [char; 0x1000] = ['\0'; 0x1000];
+pub const STDIN_BUFFER_SIZE: usize = 0x1000;
+pub static mut STDIN_BUFFER: [char; STDIN_BUFFER_SIZE] = ['\0'; STDIN_BUFFER_SIZE];
 pub static STDIN_BUFFER_POS: AtomicUsize = AtomicUsize::new(0);
 
+lazy_static! {
+    // woken up by the keyboard irq whenever STDIN_BUFFER changed
+    pub static ref STDIN_WAIT_QUEUE: Arc<WaitQueue> = Arc::new(WaitQueue::new());
+}
+
+// runs f with interrupts disabled and restores the interrupt flag afterwards
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq; pop {}; cli", out(reg) rflags);
+    }
+
+    let result = f();
+
+    if rflags & 0x200 != 0 {
+        unsafe {
+            asm!("sti");
+        }
+    }
+
+    result
+}
+
+// Feeds the key event queue and the stdin buffer
+fn handle_key_event(event: KeyEvent) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    keyboard::queue_key_event(&event);
+
+    if !event.pressed {
+        return;
+    }
+
+    let Some(character) = event.character else {
+        return;
+    };
+
+    if character == 'l' {
+        profiling::log_tracepoints();
+    }
+
+    let buffer_pos = STDIN_BUFFER_POS.load(core::sync::atomic::Ordering::Relaxed);
+
+    // backspace removes the last character of the line which has not been read yet
+    if character == '\x08' {
+        if buffer_pos > 0 && unsafe { STDIN_BUFFER[buffer_pos - 1] } != '\n' {
+            unsafe { STDIN_BUFFER[buffer_pos - 1] = '\0' };
+            STDIN_BUFFER_POS.store(buffer_pos - 1, core::sync::atomic::Ordering::Relaxed);
+            kprint!("{}", character);
+        }
+        return;
+    }
+
+    // keys are dropped while nobody reads them and the buffer is full
+    if buffer_pos < STDIN_BUFFER_SIZE - 1 {
+        unsafe {
+            STDIN_BUFFER[buffer_pos] = character;
+            STDIN_BUFFER[buffer_pos + 1] = '\0';
+        }
+        STDIN_BUFFER_POS.store(buffer_pos + 1, core::sync::atomic::Ordering::Relaxed);
+
+        kprint!("{}", character);
+
+        STDIN_WAIT_QUEUE.wake_all();
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn isr_handler(error_code: u64, int_no: u64) {
     let _event = core::hint::black_box(crate::instrument!());
 
     match int_no as u64 {
         0..=31 => {
-            ERROR!("ISR {} error_code {:x?}", int_no, error_code);
-            ERROR!("{}", CPU_EXCEPTIONS[int_no as usize]);
-
             if int_no == 14 {
                 // Page fault handling
                 let cr2: u64;
@@ -66,21 +151,39 @@ pub extern "C" fn isr_handler(error_code: u64, int_no: u64) {
                     asm!("mov {}, cr2", out(reg) cr2);
                 }
 
-                // Decode PF error code bits: Present=bit0, W/R=bit1, U/S=bit2
-                let present = (error_code & 0b001) != 0;
-                let write = (error_code & 0b010) != 0;
-                let user = (error_code & 0b100) != 0;
-                if !present && write && user {
-                    DEBUG!(
-                        "Extending user stack (cr2={:#x}, ec={:#x})",
+                // Decode PF error code bits: W/R=bit1, U/S=bit2, I/D=bit4
+                let write = (error_code & 0b00010) != 0;
+                let user = (error_code & 0b00100) != 0;
+                let instruction_fetch = (error_code & 0b10000) != 0;
+
+                let user_address = (cr2 as usize) < KERNEL_HIGHER_HALF_BASE;
+
+                if user_address
+                    && userland::handle_page_fault(cr2 as usize, write, instruction_fetch)
+                {
+                    DEBUG!("Resolved page fault (cr2={:#x}, ec={:#x})", cr2, error_code);
+                } else if user {
+                    ERROR!("Segmentation fault (cr2={:#x}, ec={:#x})", cr2, error_code);
+                    userland::segmentation_fault();
+                } else if user_address && user_memory::fix_up_fault() {
+                    ERROR!(
+                        "Invalid user memory access by a syscall (cr2={:#x}, ec={:#x})",
                         cr2,
                         error_code
                     );
-                    userland::extend_stack();
                 } else {
+                    ERROR!("ISR {} error_code {:x?}", int_no, error_code);
+                    ERROR!("{}", CPU_EXCEPTIONS[int_no as usize]);
+                    ERROR!(
+                        "cr2 maps to physical address {:x?}",
+                        mem::translate_address(mem::get_cr3(), cr2 as usize)
+                    );
                     panic!("Unhandled page fault: cr2={:#x}, ec={:#x}", cr2, error_code);
                 }
             } else {
+                ERROR!("ISR {} error_code {:x?}", int_no, error_code);
+                ERROR!("{}", CPU_EXCEPTIONS[int_no as usize]);
+
                 let cr2: u64;
                 unsafe {
                     asm!("mov {}, cr2", out(reg) cr2);
@@ -92,10 +195,10 @@ pub extern "C" fn isr_handler(error_code: u64, int_no: u64) {
                 );
             }
         }
+        // spurious interrupts of the local APIC must not be acknowledged
+        255 => {}
         _ => DEBUG!("ISR {}", int_no),
     };
-
-    out_port_b(0x20, 0x20);
 }
 
 #[unsafe(no_mangle)]
@@ -120,61 +223,34 @@ pub extern "C" fn irq_handler(int_no: u64) {
     }*/
 
     match (int_no - 32) as u64 {
-        // Clock
-        0 => unsafe {
-            if SCHEDULING_BLOCKED == 0 {
+        // Clock: the PIT on the bootstrap processor, the local APIC timer on the application processors
+        0 | 16 => {
+            timer::run_expired_timers();
+
+            if per_cpu::current().scheduling_blocked == 0 {
                 userland::schedule();
 
                 //time::update_clock();
-                kprint::kprint_integer_at_pos(
-                    USERLAND.lock().get_current_process_id() as i64,
-                    1,
-                    70,
-                    kprint::Colors::KPrintColorDarkGray,
-                );
+                if int_no == 32 {
+                    kprint::kprint_integer_at_pos(
+                        USERLAND.lock().get_current_process_id() as i64,
+                        1,
+                        70,
+                        kprint::Colors::KPrintColorDarkGray,
+                    );
+                }
             }
-        },
+        }
         // Keyboard action
         1 => {
-            let mut scancode: i8;
-
-            unsafe {
-                asm!("in al, dx", out("al") scancode, in("rdx") 0x60);
-            }
-
-            let key = keyboard::get_key_for_scancode(scancode as u8);
-
-            if key != 0xfe as char {
-                unsafe {
-                    STDIN_BUFFER[STDIN_BUFFER_POS.load(core::sync::atomic::Ordering::Relaxed)] =
-                        key;
-                }
-                STDIN_BUFFER_POS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
-                unsafe {
-                    STDIN_BUFFER[STDIN_BUFFER_POS.load(core::sync::atomic::Ordering::Relaxed)] =
-                        '\0';
-                }
-
-                kprint!("{}", key);
+            if let Some(event) = keyboard::handle_scancode(util::in_port_b(0x60)) {
+                handle_key_event(event);
             }
 
-            let lcontrol: char = 0x1d as char;
-
-            unsafe {
-                match key {
-                    'w' => keyboard::KEYSTATES[0] = true,
-                    'a' => keyboard::KEYSTATES[1] = true,
-                    's' => keyboard::KEYSTATES[2] = true,
-                    'd' => keyboard::KEYSTATES[3] = true,
-                    ' ' => keyboard::KEYSTATES[5] = true,
-                    'l' => profiling::log_tracepoints(),
-                    _ if key == lcontrol => keyboard::KEYSTATES[4] = true,
-                    _ if key == '\n' => keyboard::KEYSTATES[6] = true,
-                    _ => {}
-                }
+            // a syscall might be in progress on the kernel stack
+            if per_cpu::current().scheduling_blocked == 0 {
+                userland::schedule();
             }
-
-            userland::schedule();
         }
         _ => {}
     }
@@ -192,6 +268,15 @@ pub extern "C" fn irq_handler(int_no: u64) {
         kprint!("\n");
     }*/
 
+    end_of_interrupt(int_no);
+}
+
+fn end_of_interrupt(int_no: u64) {
+    if apic::is_local_apic_enabled() {
+        apic::end_of_interrupt();
+        return;
+    }
+
     if int_no >= 40 {
         out_port_b(0xA0, 0x20);
     }
@@ -235,6 +320,11 @@ pub fn init_idt() {
     out_port_b(0x21, 0x0);
     out_port_b(0xA1, 0x0);
 
+    // masks all irqs of the 8259 again if the IOAPIC takes over
+    if apic::init_apic() {
+        DEBUG!("Using local APIC and IOAPIC instead of the 8259 PIC");
+    }
+
     // Set PIC mask to only let keyboard irqs through
     // https://wiki.osdev.org/I_Can%27t_Get_Interrupts_Working#IRQ_problems
     //out_port_b(0x21, 0xfd);
@@ -303,9 +393,27 @@ pub fn init_idt() {
     set_isr!(45, irq13);
     set_isr!(46, irq14);
     set_isr!(47, irq15);
+    set_isr!(LOCAL_APIC_TIMER_VECTOR as usize, irq16);
 
     set_isr!(128, isr128);
     set_isr!(177, isr177);
+    set_isr!(apic::SPURIOUS_INTERRUPT_VECTOR as usize, isr255);
+
+    per_cpu::current().scheduling_blocked = 1;
+
+    // the keyboard irq must not be the first to access the queue, as this allocates memory
+    lazy_static::initialize(&STDIN_WAIT_QUEUE);
+
+    load_idt();
+
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+}
+
+// All CPUs share the same IDT
+pub fn load_idt() {
+    let _event = core::hint::black_box(crate::instrument!());
 
     unsafe {
         let idt_ptr: IdtPtrStruct = IdtPtrStruct {
@@ -315,10 +423,9 @@ pub fn init_idt() {
             // Complexity from last link probably not required
             base: core::ptr::addr_of!(IDT_ENTRIES) as u64, //(((IDT_ENTRIES.as_ptr() as u64) << 16) as i64 >> 16) as u64,
         };
-        SCHEDULING_BLOCKED = 1;
+
         asm!(
-            "lidt [{}]
-            sti",
+            "lidt [{}]",
             in(reg) &idt_ptr, options(readonly, nostack, preserves_flags)
         );
     }
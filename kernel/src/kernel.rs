@@ -6,6 +6,11 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 
 mod acpi;
+mod address_space;
+mod apic;
+mod block_cache;
+mod config;
+mod file_descriptor;
 mod filesystem;
 mod gdt;
 mod hdd;
@@ -16,14 +21,22 @@ mod kprint;
 mod logging;
 mod mem;
 mod mem_config;
+mod memory_area;
+mod multiboot2;
+mod per_cpu;
+mod pipe;
 mod process;
 mod profiling;
 mod serial;
+mod smp;
 mod syscall;
 mod time;
+mod timer;
+mod user_memory;
 mod userland;
 mod util;
 mod vga;
+mod wait_queue;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -39,12 +52,41 @@ lazy_static! {
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn kernel_main() {
-    mem::init_available_memory();
+pub extern "C" fn kernel_main(multiboot_information_address: usize) {
+    multiboot2::init(multiboot_information_address);
+    config::init();
 
     clear_console!();
     DEBUG!("Entering JOS Kernel");
 
+    if let Some(boot_loader_name) = multiboot2::boot_loader_name() {
+        DEBUG!("Booted by {}", boot_loader_name);
+    }
+    if let Some(command_line) = multiboot2::command_line() {
+        DEBUG!("Kernel command line: {}", command_line);
+    }
+    if let Some(framebuffer) = multiboot2::framebuffer() {
+        DEBUG!(
+            "Framebuffer at {:#x}: {}x{}, {} bits per pixel, pitch {}",
+            framebuffer.address,
+            framebuffer.width,
+            framebuffer.height,
+            framebuffer.bits_per_pixel,
+            framebuffer.pitch
+        );
+    }
+    for module in multiboot2::modules() {
+        DEBUG!(
+            "Boot module {:#x}-{:#x}: {}",
+            module.start,
+            module.end,
+            module.command_line
+        );
+    }
+
+    mem::init_available_memory();
+    DEBUG!("Initialized Physical Memory");
+
     time::set_initial_time();
     DEBUG!("Initialized High Precision Event Timer");
 
@@ -60,11 +102,22 @@ pub extern "C" fn kernel_main() {
     interrupt::init_idt();
     DEBUG!("Initialized Interrupt Descriptor Table");
 
-    //filesystem::init_filesystem();
-    //DEBUG!("Initialized Filesystem");
+    timer::init_timer();
+    DEBUG!("Initialized Timer");
+
+    smp::start_application_processors();
+
+    let config = config::get();
 
-    //vga::vga_enter();
-    //vga::vga_clear_screen();
+    if config.filesystem_info {
+        filesystem::init_filesystem();
+        DEBUG!("Initialized Filesystem");
+    }
+
+    if config.vga {
+        vga::vga_enter();
+        vga::vga_clear_screen();
+    }
 
     // Trigger test exception
     //unsafe {
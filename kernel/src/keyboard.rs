@@ -1,83 +1,365 @@
-// http://kbdlayout.info/KBDGR/scancodes+names
-// http://kbdlayout.info/KBDGR/virtualkeys
-static SCANCODES: [char; 69] = [
-    0xfe as char,
-    0xfe as char,
-    '1',
-    '2',
-    '3',
-    '$',
-    '5',
-    '6',
-    '7',
-    '8',
-    '9',
-    '0',
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    'q',
-    'w',
-    'e',
-    'r',
-    't',
-    'z',
-    'u',
-    'i',
-    'o',
-    'p',
-    0xfe as char,
-    '+' as char,
-    '\n',         //VK_RETURN -> map to ascii line feed
-    0x1d as char, //VK_LCONTROL
-    'a',
-    's',
-    'd',
-    'f',
-    'g',
-    'h',
-    'j',
-    'k',
-    'l',
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    'y',
-    'x',
-    'c',
-    'v',
-    'b',
-    'n',
-    'm',
-    ';',
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    ' ', //VK_SPACE -> map to whitespace
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-    0xfe as char,
-];
-
-pub static mut KEYSTATES: [bool; 10] = [false; 10];
-
-pub fn get_key_for_scancode(scancode: u8) -> char {
+use crate::config::{self, KeyboardLayout};
+use crate::interrupt::without_interrupts;
+use crate::time;
+use spin::Mutex;
+
+// PS/2 keyboard with scancode set 1
+// https://wiki.osdev.org/PS/2_Keyboard#Scan_Code_Set_1
+// http://kbdlayout.info/KBDUS/scancodes
+// http://kbdlayout.info/KBDGR/scancodes
+
+/// Identifies a physical key: the make code of scancode set 1, or'ed with KEY_EXTENDED for keys sent after 0xE0
+pub type KeyCode = u8;
+
+pub const KEY_EXTENDED: KeyCode = 0x80;
+
+pub const KEY_LEFT_CTRL: KeyCode = 0x1d;
+pub const KEY_LEFT_SHIFT: KeyCode = 0x2a;
+pub const KEY_RIGHT_SHIFT: KeyCode = 0x36;
+pub const KEY_LEFT_ALT: KeyCode = 0x38;
+pub const KEY_CAPS_LOCK: KeyCode = 0x3a;
+pub const KEY_NUM_LOCK: KeyCode = 0x45;
+pub const KEY_KEYPAD_7: KeyCode = 0x47;
+pub const KEY_KEYPAD_PERIOD: KeyCode = 0x53;
+pub const KEY_KEYPAD_ENTER: KeyCode = KEY_EXTENDED | 0x1c;
+pub const KEY_RIGHT_CTRL: KeyCode = KEY_EXTENDED | 0x1d;
+pub const KEY_KEYPAD_SLASH: KeyCode = KEY_EXTENDED | 0x35;
+pub const KEY_RIGHT_ALT: KeyCode = KEY_EXTENDED | 0x38;
+
+const SCANCODE_EXTENDED_PREFIX: u8 = 0xe0;
+const SCANCODE_RELEASED: u8 = 0x80;
+// print screen is sent with a fake shift, which must not change the modifiers
+const SCANCODE_FAKE_SHIFT: u8 = 0x2a;
+
+/** Modifier keys held down and lock keys toggled on */
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const SHIFT: u8 = 1 << 0;
+    pub const CTRL: u8 = 1 << 1;
+    pub const ALT: u8 = 1 << 2;
+    pub const ALT_GR: u8 = 1 << 3;
+    pub const CAPS_LOCK: u8 = 1 << 4;
+    pub const NUM_LOCK: u8 = 1 << 5;
+
+    pub fn contains(&self, modifier: u8) -> bool {
+        self.0 & modifier != 0
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub keycode: KeyCode,
+    // false when the key was released
+    pub pressed: bool,
+    // the state after this event
+    pub modifiers: Modifiers,
+    // the text the key produces with the current layout and modifiers, if any
+    pub character: Option<char>,
+}
+
+// number of keys covered by the tables of a layout, up to the additional key of ISO keyboards
+const LAYOUT_KEYS: usize = 0x57;
+
+/**
+ * Maps keycodes to characters, the tables are indexed by the keycodes which are not extended.
+ * '\0' marks keys which do not produce text.
+ */
+pub struct Layout {
+    normal: [char; LAYOUT_KEYS],
+    shifted: [char; LAYOUT_KEYS],
+    // third level, e.g. AltGr+q is @ on German keyboards
+    alt_gr: &'static [(KeyCode, char)],
+}
+
+// Decodes a string with exactly LAYOUT_KEYS characters of at most two UTF-8 bytes into a table
+const fn layout_table(keys: &str) -> [char; LAYOUT_KEYS] {
+    let bytes = keys.as_bytes();
+    let mut table = ['\0'; LAYOUT_KEYS];
+    let mut i = 0;
+    let mut key = 0;
+
+    while i < bytes.len() {
+        let code = if bytes[i] < 0x80 {
+            i += 1;
+            bytes[i - 1] as u32
+        } else {
+            i += 2;
+            ((bytes[i - 2] as u32 & 0x1f) << 6) | (bytes[i - 1] as u32 & 0x3f)
+        };
+        table[key] = match char::from_u32(code) {
+            Some(c) => c,
+            None => panic!("invalid layout"),
+        };
+        key += 1;
+    }
+
+    assert!(key == LAYOUT_KEYS, "layout needs a character for every key");
+    table
+}
+
+// caps lock, the function keys, num lock and scroll lock
+macro_rules! no_text_keys {
+    () => {
+        "\0\0\0\0\0\0\0\0\0\0\0\0\0"
+    };
+}
+// the keypad with num lock on, followed by two unused keycodes
+macro_rules! keypad_keys {
+    () => {
+        "789-456+1230.\0\0"
+    };
+}
+
+static LAYOUT_US: Layout = Layout {
+    normal: layout_table(concat!(
+        "\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ",
+        no_text_keys!(),
+        keypad_keys!(),
+        "\\"
+    )),
+    shifted: layout_table(concat!(
+        "\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ",
+        no_text_keys!(),
+        keypad_keys!(),
+        "|"
+    )),
+    alt_gr: &[],
+};
+
+static LAYOUT_DE: Layout = Layout {
+    normal: layout_table(concat!(
+        "\0\x1b1234567890ß´\x08\tqwertzuiopü+\n\0asdfghjklöä^\0#yxcvbnm,.-\0*\0 ",
+        no_text_keys!(),
+        keypad_keys!(),
+        "<"
+    )),
+    shifted: layout_table(concat!(
+        "\0\x1b!\"§$%&/()=?`\x08\tQWERTZUIOPÜ*\n\0ASDFGHJKLÖÄ°\0'YXCVBNM;:_\0*\0 ",
+        no_text_keys!(),
+        keypad_keys!(),
+        ">"
+    )),
+    alt_gr: &[
+        (0x03, '²'),
+        (0x04, '³'),
+        (0x08, '{'),
+        (0x09, '['),
+        (0x0a, ']'),
+        (0x0b, '}'),
+        (0x0c, '\\'),
+        (0x10, '@'),
+        (0x1b, '~'),
+        (0x32, 'µ'),
+        (0x56, '|'),
+    ],
+};
+
+struct Keyboard {
+    // the previous byte was the 0xE0 prefix
+    extended: bool,
+    modifiers: u8,
+    // keys which are held down, to tell repeated make codes apart from presses
+    pressed: [bool; 256],
+}
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+    extended: false,
+    modifiers: 0,
+    pressed: [false; 256],
+});
+
+fn layout() -> &'static Layout {
+    match config::get().keyboard_layout {
+        KeyboardLayout::Us => &LAYOUT_US,
+        KeyboardLayout::De => &LAYOUT_DE,
+    }
+}
+
+// Called with every byte read from the keyboard; returns an event once a complete scancode was received
+pub fn handle_scancode(scancode: u8) -> Option<KeyEvent> {
     let _event = core::hint::black_box(crate::instrument!());
-    match scancode as u8 {
-        0..=68 => SCANCODES[scancode as usize],
-        _ => 0xfe as char,
+
+    let mut keyboard = KEYBOARD.lock();
+
+    if scancode == SCANCODE_EXTENDED_PREFIX {
+        keyboard.extended = true;
+        return None;
     }
+
+    let extended = core::mem::take(&mut keyboard.extended);
+    let pressed = scancode & SCANCODE_RELEASED == 0;
+    let code = scancode & !SCANCODE_RELEASED;
+
+    if extended && code == SCANCODE_FAKE_SHIFT {
+        return None;
+    }
+
+    let keycode = if extended { KEY_EXTENDED | code } else { code };
+    let repeated = pressed && keyboard.pressed[keycode as usize];
+    keyboard.pressed[keycode as usize] = pressed;
+
+    let held = match keycode {
+        KEY_LEFT_SHIFT | KEY_RIGHT_SHIFT => Modifiers::SHIFT,
+        KEY_LEFT_CTRL | KEY_RIGHT_CTRL => Modifiers::CTRL,
+        KEY_LEFT_ALT => Modifiers::ALT,
+        KEY_RIGHT_ALT => Modifiers::ALT_GR,
+        _ => 0,
+    };
+    if held != 0 {
+        if pressed {
+            keyboard.modifiers |= held;
+        } else {
+            keyboard.modifiers &= !held;
+        }
+    }
+
+    // lock keys toggle on the press, not while they are held down
+    let toggled = match keycode {
+        KEY_CAPS_LOCK => Modifiers::CAPS_LOCK,
+        KEY_NUM_LOCK => Modifiers::NUM_LOCK,
+        _ => 0,
+    };
+    if pressed && !repeated {
+        keyboard.modifiers ^= toggled;
+    }
+
+    let modifiers = Modifiers(keyboard.modifiers);
+
+    Some(KeyEvent {
+        keycode,
+        pressed,
+        modifiers,
+        character: translate(layout(), keycode, modifiers),
+    })
+}
+
+fn translate(layout: &Layout, keycode: KeyCode, modifiers: Modifiers) -> Option<char> {
+    let character = match keycode {
+        KEY_KEYPAD_ENTER => '\n',
+        KEY_KEYPAD_SLASH => '/',
+        _ if keycode & KEY_EXTENDED != 0 || keycode as usize >= LAYOUT_KEYS => return None,
+        // without num lock the keypad only moves the cursor
+        KEY_KEYPAD_7..=KEY_KEYPAD_PERIOD if !modifiers.contains(Modifiers::NUM_LOCK) => {
+            return None;
+        }
+        _ if modifiers.contains(Modifiers::ALT_GR) => {
+            return layout
+                .alt_gr
+                .iter()
+                .find(|&&(key, _)| key == keycode)
+                .map(|&(_, character)| character);
+        }
+        _ => {
+            let normal = layout.normal[keycode as usize];
+
+            // caps lock only affects letters and is undone by shift
+            let shifted = modifiers.contains(Modifiers::SHIFT)
+                ^ (modifiers.contains(Modifiers::CAPS_LOCK) && normal.is_alphabetic());
+
+            if shifted {
+                layout.shifted[keycode as usize]
+            } else {
+                normal
+            }
+        }
+    };
+
+    match character {
+        '\0' => None,
+        // e.g. Ctrl+C is the control character 0x03
+        'a'..='z' | 'A'..='Z' if modifiers.contains(Modifiers::CTRL) => {
+            Some((character.to_ascii_lowercase() as u8 - b'a' + 1) as char)
+        }
+        _ => Some(character),
+    }
+}
+
+// number of events kept for processes which read them, older ones are lost
+const KEY_EVENT_QUEUE_SIZE: usize = 256;
+
+/** A key event as returned to user programs, see keyboard.h in libc */
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct KeyEventRecord {
+    pub timestamp_ns: u64,
+    pub keycode: KeyCode,
+    pub pressed: u8,
+    pub modifiers: u8,
+    // Latin-1, 0 if the key does not produce text
+    pub character: u8,
+    _reserved: [u8; 4],
+}
+
+/**
+ * The most recent key events. Each process has its own position in the queue, so every reader sees every event
+ * regardless of the other readers.
+ */
+struct KeyEventQueue {
+    events: [KeyEventRecord; KEY_EVENT_QUEUE_SIZE],
+    // number of events queued since boot
+    next_position: u64,
+}
+
+static KEY_EVENTS: Mutex<KeyEventQueue> = Mutex::new(KeyEventQueue {
+    events: [KeyEventRecord {
+        timestamp_ns: 0,
+        keycode: 0,
+        pressed: 0,
+        modifiers: 0,
+        character: 0,
+        _reserved: [0; 4],
+    }; KEY_EVENT_QUEUE_SIZE],
+    next_position: 0,
+});
+
+// Called from the keyboard irq
+pub fn queue_key_event(event: &KeyEvent) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let mut queue = KEY_EVENTS.lock();
+    let index = queue.next_position as usize % KEY_EVENT_QUEUE_SIZE;
+
+    queue.events[index] = KeyEventRecord {
+        timestamp_ns: time::get_ns_since_boot(),
+        keycode: event.keycode,
+        pressed: event.pressed as u8,
+        modifiers: event.modifiers.0,
+        character: match event.character {
+            Some(character) if (character as u32) < 0x100 => character as u8,
+            _ => 0,
+        },
+        _reserved: [0; 4],
+    };
+    queue.next_position += 1;
+}
+
+// A new reader starts with the events queued after this call
+pub fn next_key_event_position() -> u64 {
+    without_interrupts(|| KEY_EVENTS.lock().next_position)
+}
+
+// Copies the events after position into the buffer and advances the position; returns the number of events copied
+pub fn read_key_events(position: &mut u64, buffer: &mut [KeyEventRecord]) -> usize {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    // the keyboard irq takes the lock as well
+    without_interrupts(|| {
+        let queue = KEY_EVENTS.lock();
+
+        // skip the events which have been overwritten already
+        let oldest = queue
+            .next_position
+            .saturating_sub(KEY_EVENT_QUEUE_SIZE as u64);
+        *position = (*position).max(oldest);
+
+        let mut count = 0;
+        while *position < queue.next_position && count < buffer.len() {
+            buffer[count] = queue.events[*position as usize % KEY_EVENT_QUEUE_SIZE];
+            *position += 1;
+            count += 1;
+        }
+        count
+    })
 }
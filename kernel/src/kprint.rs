@@ -1,3 +1,4 @@
+use crate::config::{self, Console};
 use crate::mem_config::KERNEL_HIGHER_HALF_BASE;
 use crate::serial;
 // add better formatting options, see https://os.phil-opp.com/vga-text-mode/#a-kprintln-macro
@@ -197,14 +198,42 @@ pub fn kprint_char(character_in: char, color: Colors) {
     let mut character = character_in;
 
     match character as u8 {
-        0x20..=0x7e | b'\n' => (),
+        0x20..=0x7e | b'\n' | 0x08 => (),
+        b'\t' => character = ' ',
         _ => character = 0xfe as char,
     }
 
+    let console = config::get().console;
+
     unsafe {
+        // backspace erases the previous character of the line
+        if character == '\x08' {
+            if CURRENT_COL > 0 {
+                CURRENT_COL -= 1;
+
+                if console != Console::Vga {
+                    serial::write_serial('\x08');
+                    serial::write_serial(' ');
+                    serial::write_serial('\x08');
+                }
+                if console != Console::Serial {
+                    core::ptr::write_volatile(
+                        (KERNEL_HIGHER_HALF_BASE
+                            + 0xb8000
+                            + (CURRENT_COL + CURRENT_ROW * 80) as usize * 2)
+                            as *mut u16,
+                        get_video_byte_string(' ', color, Colors::KPrintColorWhite),
+                    );
+                }
+            }
+            return;
+        }
+
         if character == '\n' {
-            serial::write_serial('\r');
-            serial::write_serial('\n');
+            if console != Console::Vga {
+                serial::write_serial('\r');
+                serial::write_serial('\n');
+            }
 
             CURRENT_ROW += 1;
 
@@ -218,14 +247,18 @@ pub fn kprint_char(character_in: char, color: Colors) {
         }
 
         // Write to serial port first
-        serial::write_serial(character);
+        if console != Console::Vga {
+            serial::write_serial(character);
+        }
 
         // https://en.wikipedia.org/wiki/VGA_text_mode
-        core::ptr::write_volatile(
-            (KERNEL_HIGHER_HALF_BASE + 0xb8000 + (CURRENT_COL + CURRENT_ROW * 80) as usize * 2)
-                as *mut u16,
-            get_video_byte_string(character, color, Colors::KPrintColorWhite),
-        );
+        if console != Console::Serial {
+            core::ptr::write_volatile(
+                (KERNEL_HIGHER_HALF_BASE + 0xb8000 + (CURRENT_COL + CURRENT_ROW * 80) as usize * 2)
+                    as *mut u16,
+                get_video_byte_string(character, color, Colors::KPrintColorWhite),
+            );
+        }
 
         if CURRENT_COL == 80 {
             CURRENT_COL = 0;
@@ -1,29 +1,31 @@
 #[macro_export]
 macro_rules! log_with_level {
-    ($color:expr, $level:expr, $($arg:tt)*) => {{
-        crate::kprintcolor!($color, $level);
-        crate::kprint!("[{}] ", crate::time::get_ms_since_boot());
-        crate::kprintln!($($arg)*);
+    ($color:expr, $level:expr, $label:expr, $($arg:tt)*) => {{
+        if crate::config::get().log_level >= $level {
+            crate::kprintcolor!($color, $label);
+            crate::kprint!("[{}] ", crate::time::get_ms_since_boot());
+            crate::kprintln!($($arg)*);
+        }
     }};
 }
 
 #[macro_export]
 macro_rules! DEBUG {
     ($($arg:tt)*) => {
-        crate::log_with_level!(crate::kprint::Colors::KPrintColorGreen, "[DEBUG] ", $($arg)*)
+        crate::log_with_level!(crate::kprint::Colors::KPrintColorGreen, crate::config::LogLevel::Debug, "[DEBUG] ", $($arg)*)
     };
 }
 
 #[macro_export]
 macro_rules! INFO {
     ($($arg:tt)*) => {
-        crate::log_with_level!(crate::kprint::Colors::KPrintColorBlack, "[DEBUG] ", $($arg)*);
+        crate::log_with_level!(crate::kprint::Colors::KPrintColorBlack, crate::config::LogLevel::Info, "[INFO] ", $($arg)*);
     };
 }
 
 #[macro_export]
 macro_rules! ERROR {
     ($($arg:tt)*) => {
-        crate::log_with_level!(crate::kprint::Colors::KPrintColorRed, "[ERROR] ", $($arg)*)
+        crate::log_with_level!(crate::kprint::Colors::KPrintColorRed, crate::config::LogLevel::Error, "[ERROR] ", $($arg)*)
     };
 }
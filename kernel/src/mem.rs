@@ -1,78 +1,336 @@
-use crate::{mem, mem_config::*, process};
+use crate::multiboot2::{self, MEMORY_AVAILABLE};
+use crate::{DEBUG, ERROR, mem, mem_config::*, process};
 use core::{
     arch::asm,
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::atomic::{AtomicU16, AtomicUsize, Ordering},
 };
 
-static AVAILABLE_MEMORY: [AtomicBool; MAX_PAGE_FRAMES] =
-    [const { AtomicBool::new(false) }; MAX_PAGE_FRAMES];
+// Number of references to each page frame; 0 means the frame is free
+// Frames which are no usable RAM or belong to the kernel are marked as FRAME_RESERVED
+static FRAME_REFERENCE_COUNTS: [AtomicU16; MAX_PAGE_FRAMES] =
+    [const { AtomicU16::new(0) }; MAX_PAGE_FRAMES];
 static NEXT_FREE_PAGE: AtomicUsize = AtomicUsize::new(0);
 
+const FRAME_RESERVED: u16 = u16::MAX;
+const FRAME_SHARE_LIMIT: u16 = FRAME_RESERVED - 1;
+
 pub fn init_available_memory() {
     let _event = core::hint::black_box(crate::instrument!());
+
+    for frame in FRAME_REFERENCE_COUNTS.iter() {
+        frame.store(FRAME_RESERVED, Ordering::Relaxed);
+    }
+
+    match multiboot2::memory_map() {
+        Some(memory_map) => {
+            for entry in memory_map.filter(|entry| entry.entry_type == MEMORY_AVAILABLE) {
+                // only whole frames within the physical memory mapping can be used
+                let first_frame = (entry.base_address as usize).div_ceil(PAGE_SIZE);
+                let end_frame = core::cmp::min(
+                    (entry.base_address + entry.length) as usize / PAGE_SIZE,
+                    MAX_PAGE_FRAMES,
+                );
+                for frame in first_frame..end_frame {
+                    FRAME_REFERENCE_COUNTS[frame].store(0, Ordering::Relaxed);
+                }
+            }
+        }
+        None => {
+            ERROR!("No memory map available, assuming all memory is usable");
+            for frame in FRAME_REFERENCE_COUNTS.iter() {
+                frame.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
     // Kernel memory
-    for i in 0..(KERNEL_SIZE / PAGE_SIZE) {
-        AVAILABLE_MEMORY[i].store(true, Ordering::Relaxed);
+    reserve_physical_range(0, KERNEL_SIZE);
+
+    if let Some((address, size)) = multiboot2::boot_information_range() {
+        reserve_physical_range(address, size);
     }
 
+    for module in multiboot2::modules() {
+        reserve_physical_range(module.start, module.end - module.start);
+    }
+
+    let free_page_frames = FRAME_REFERENCE_COUNTS
+        .iter()
+        .filter(|frame| frame.load(Ordering::Relaxed) == 0)
+        .count();
     NEXT_FREE_PAGE.store(KERNEL_SIZE / PAGE_SIZE, Ordering::Relaxed);
+
+    DEBUG!(
+        "{} MiB of free physical memory",
+        free_page_frames * PAGE_SIZE / (1024 * 1024)
+    );
 }
 
+fn reserve_physical_range(address: usize, size: usize) {
+    let first_frame = address / PAGE_SIZE;
+    let end_frame = core::cmp::min((address + size).div_ceil(PAGE_SIZE), MAX_PAGE_FRAMES);
+
+    for frame in first_frame..end_frame {
+        FRAME_REFERENCE_COUNTS[frame].store(FRAME_RESERVED, Ordering::Relaxed);
+    }
+}
+
+// Returns the physical address of a free page frame with a reference count of 1
 pub fn allocate_page_frame() -> usize {
     //let _event = core::hint::black_box(crate::instrument!());
-    // TODO make safe
+
+    let start = NEXT_FREE_PAGE.load(Ordering::Relaxed);
+
+    for i in (start..MAX_PAGE_FRAMES).chain(0..start) {
+        if FRAME_REFERENCE_COUNTS[i]
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            NEXT_FREE_PAGE.store(i + 1, Ordering::Relaxed);
+            return i * PAGE_SIZE;
+        }
+    }
+
+    panic!("No more page frames available!");
+}
+
+// Like allocate_page_frame, but the page frame is filled with zeroes
+pub fn allocate_zeroed_page_frame() -> usize {
+    let address = allocate_page_frame();
+
     unsafe {
-        for i in NEXT_FREE_PAGE.load(Ordering::Relaxed)..MAX_PAGE_FRAMES {
-            if AVAILABLE_MEMORY[i].load(Ordering::Relaxed) == false {
-                AVAILABLE_MEMORY[i].store(true, Ordering::Relaxed);
-                NEXT_FREE_PAGE.store(i, Ordering::Relaxed);
-                return i * PAGE_SIZE;
+        core::ptr::write_bytes(physical_to_virtual(address) as *mut u8, 0, PAGE_SIZE);
+    }
+
+    address
+}
+
+// Drops one reference to the page frame; it is free again once nobody references it anymore
+pub fn free_page_frame(address: usize) {
+    let frame = address / PAGE_SIZE;
+
+    let result =
+        FRAME_REFERENCE_COUNTS[frame].fetch_update(Ordering::Release, Ordering::Relaxed, |count| {
+            match count {
+                0 | FRAME_RESERVED => None,
+                count => Some(count - 1),
+            }
+        });
+
+    match result {
+        Ok(1) => {
+            if frame < NEXT_FREE_PAGE.load(Ordering::Relaxed) {
+                NEXT_FREE_PAGE.store(frame, Ordering::Relaxed);
             }
         }
+        Ok(_) => {}
+        Err(count) => ERROR!(
+            "Freeing page frame {:#x} with reference count {}",
+            address,
+            count
+        ),
     }
+}
 
-    panic!("No more page frames available!");
+// Adds a reference to an allocated page frame, e.g. when a page is shared after fork
+// Fails if the reference count would reach FRAME_RESERVED, the page has to be copied instead then
+pub fn share_page_frame(address: usize) -> bool {
+    let frame = address / PAGE_SIZE;
+
+    let result =
+        FRAME_REFERENCE_COUNTS[frame].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            match count {
+                0 | FRAME_SHARE_LIMIT | FRAME_RESERVED => None,
+                count => Some(count + 1),
+            }
+        });
+
+    match result {
+        Ok(_) => true,
+        Err(FRAME_SHARE_LIMIT) => false,
+        Err(count) => {
+            ERROR!(
+                "Sharing page frame {:#x} with reference count {}",
+                address,
+                count
+            );
+            false
+        }
+    }
+}
+
+pub fn get_page_frame_reference_count(address: usize) -> u16 {
+    FRAME_REFERENCE_COUNTS[address / PAGE_SIZE].load(Ordering::Relaxed)
 }
 
+// Marks a frame outside of the usable RAM, e.g. ACPI tables or MMIO, as used by the kernel
 pub fn allocate_page_frame_for_given_physical_address(address: usize) -> usize {
     let _event = core::hint::black_box(crate::instrument!());
-    unsafe {
-        let page = address / PAGE_SIZE;
-        AVAILABLE_MEMORY[page].store(true, Ordering::Relaxed);
-        return page as usize * PAGE_SIZE as usize;
+
+    let page = address / PAGE_SIZE;
+    FRAME_REFERENCE_COUNTS[page].store(FRAME_RESERVED, Ordering::Relaxed);
+    return page * PAGE_SIZE;
+}
+
+// Returns the page tables of the kernel, which are the ones loaded at boot
+pub fn get_kernel_cr3() -> usize {
+    if process::KERNEL_CR3.load(Ordering::Relaxed) == 0 {
+        process::KERNEL_CR3.store(get_cr3() & ENTRY_MASK, Ordering::Relaxed);
     }
+
+    process::KERNEL_CR3.load(Ordering::Relaxed)
 }
 
-pub fn map_page_in_page_tables(
-    page: usize,
-    l4: usize,
-    l3: usize,
-    l2: usize,
-    l1: usize,
-    bitmask: u8,
-) {
+// Maps the page frame at the virtual address in the page tables starting at cr3
+// A new l3 table in the upper half would not be seen by processes, as they only copy the kernel's l4 entries
+pub fn map_page_in_page_tables(cr3: usize, vaddr: usize, page: usize, flags: usize) {
     let _event = core::hint::black_box(crate::instrument!());
 
+    let table_flags = if vaddr < KERNEL_HIGHER_HALF_BASE {
+        PAGE_ENTRY_FLAGS_USERSPACE
+    } else {
+        PAGE_ENTRY_FLAGS_KERNELSPACE
+    };
+
+    let entry = get_or_create_page_table_entry(cr3, vaddr, table_flags as usize);
+
+    unsafe {
+        *entry = page | flags;
+    }
+}
+
+pub fn physical_to_virtual(physical_address: usize) -> usize {
+    physical_address + PHYSICAL_MEMORY_OFFSET
+}
+
+pub fn get_cr3() -> usize {
+    let cr3: usize;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3);
+    }
+    cr3
+}
+
+pub fn set_cr3(cr3: usize) {
     unsafe {
-        if process::KERNEL_CR3.load(Ordering::Relaxed) == 0 {
-            let mut cr3: u64;
-            asm!("mov r15, cr3", out("r15") cr3);
-            process::KERNEL_CR3.store(cr3 as usize, Ordering::Relaxed);
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+    }
+}
+
+pub fn flush_tlb_entry(vaddr: usize) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) vaddr, options(nostack, preserves_flags));
+    }
+}
+
+// Walks the page tables starting at cr3 and returns a pointer to the l1 entry of vaddr
+// According to AMD Volume 2, page 146
+pub fn get_page_table_entry(cr3: usize, vaddr: usize) -> Option<*mut usize> {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let mut table = physical_to_virtual(cr3 & ENTRY_MASK) as *mut process::PageTable;
+
+    for shift in [L4_TABLE_SHIFT, L3_TABLE_SHIFT, L2_TABLE_SHIFT] {
+        let entry = unsafe { (*table).entry[(vaddr >> shift) & 0x1ff] };
+
+        if entry & PAGE_ENTRY_PRESENT == 0 || entry & PAGE_ENTRY_HUGE != 0 {
+            return None;
         }
 
-        let l4table =
-            (process::KERNEL_CR3.load(Ordering::Relaxed) & ENTRY_MASK) as *const process::PageTable;
+        table = physical_to_virtual(entry & ENTRY_MASK) as *mut process::PageTable;
+    }
 
-        let l3table = ((*l4table).entry[l4] & ENTRY_MASK) as *mut process::PageTable;
+    unsafe { Some(&mut (*table).entry[(vaddr >> L1_TABLE_SHIFT) & 0x1ff] as *mut usize) }
+}
 
-        let l2table = ((*l3table).entry[l3] & ENTRY_MASK) as *mut process::PageTable;
+// Like get_page_table_entry, but missing page tables are allocated with the given flags
+pub fn get_or_create_page_table_entry(cr3: usize, vaddr: usize, table_flags: usize) -> *mut usize {
+    let _event = core::hint::black_box(crate::instrument!());
 
-        let l1table = ((*l2table).entry[l2] & ENTRY_MASK) as *mut process::PageTable;
+    let mut table = physical_to_virtual(cr3 & ENTRY_MASK) as *mut process::PageTable;
 
-        if PAGE_SIZE == BASE_PAGE_SIZE {
-            (*l1table).entry[l1] = page | bitmask as usize;
-        } else {
-            (*l2table).entry[l2] = page | bitmask as usize;
+    for shift in [L4_TABLE_SHIFT, L3_TABLE_SHIFT, L2_TABLE_SHIFT] {
+        let entry = unsafe { &mut (*table).entry[(vaddr >> shift) & 0x1ff] };
+
+        if *entry & PAGE_ENTRY_PRESENT == 0 {
+            *entry = allocate_zeroed_page_frame() | table_flags;
+        } else if *entry & PAGE_ENTRY_HUGE != 0 {
+            panic!("Virtual address {:#x} is part of a huge page", vaddr);
         }
+
+        table = physical_to_virtual(*entry & ENTRY_MASK) as *mut process::PageTable;
     }
+
+    unsafe { &mut (*table).entry[(vaddr >> L1_TABLE_SHIFT) & 0x1ff] as *mut usize }
+}
+
+// Returns the physical address vaddr is mapped to in the page tables starting at cr3
+pub fn translate_address(cr3: usize, vaddr: usize) -> Option<usize> {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let mut table = physical_to_virtual(cr3 & ENTRY_MASK) as *const process::PageTable;
+
+    for shift in [
+        L4_TABLE_SHIFT,
+        L3_TABLE_SHIFT,
+        L2_TABLE_SHIFT,
+        L1_TABLE_SHIFT,
+    ] {
+        let entry = unsafe { (*table).entry[(vaddr >> shift) & 0x1ff] };
+
+        if entry & PAGE_ENTRY_PRESENT == 0 {
+            return None;
+        }
+
+        // a huge page maps everything below this level
+        if shift == L1_TABLE_SHIFT || entry & PAGE_ENTRY_HUGE != 0 {
+            let offset_mask = (1 << shift) - 1;
+            return Some((entry & ENTRY_MASK & !offset_mask) + (vaddr & offset_mask));
+        }
+
+        table = physical_to_virtual(entry & ENTRY_MASK) as *const process::PageTable;
+    }
+
+    None
+}
+
+// Gives the faulting process its own copy of a page it shared with its parent or child since fork
+// Returns false if the page is not a copy-on-write page
+pub fn resolve_copy_on_write(vaddr: usize) -> bool {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let entry = match get_page_table_entry(get_cr3(), vaddr) {
+        Some(entry) => entry,
+        None => return false,
+    };
+
+    unsafe {
+        if *entry & PAGE_ENTRY_COPY_ON_WRITE == 0 {
+            return false;
+        }
+
+        let old_frame = *entry & ENTRY_MASK;
+
+        // the last process sharing the page can just keep it
+        let frame = if get_page_frame_reference_count(old_frame) == 1 {
+            old_frame
+        } else {
+            let new_frame = allocate_page_frame();
+
+            core::ptr::copy_nonoverlapping(
+                physical_to_virtual(old_frame) as *const u8,
+                physical_to_virtual(new_frame) as *mut u8,
+                PAGE_SIZE,
+            );
+
+            free_page_frame(old_frame);
+            new_frame
+        };
+
+        *entry = frame | (*entry & !ENTRY_MASK & !PAGE_ENTRY_COPY_ON_WRITE) | PAGE_ENTRY_WRITABLE;
+    }
+
+    flush_tlb_entry(vaddr);
+
+    true
 }
@@ -18,6 +18,9 @@ pub const HUGE_PAGE_ENTRY_FLAGS: u8 = 0b10000011;
 
 pub const PAGE_ENTRY_FLAGS_KERNELSPACE: u8 = BASE_PAGE_ENTRY_FLAGS;
 
+/// Flags for memory mapped device registers (Present + Writable + No User + Write Through + Cache Disable)
+pub const PAGE_ENTRY_FLAGS_MMIO: u8 = 0b11011;
+
 /// Standard page table entry flags (Present + Writable + User)
 pub const BASE_PAGE_ENTRY_FLAGS_USERSPACE: u8 = 0b111;
 
@@ -28,6 +31,17 @@ pub const PAGE_ENTRY_FLAGS_USERSPACE: u8 = BASE_PAGE_ENTRY_FLAGS_USERSPACE;
 
 pub const PAGE_OFFSET_MASK: usize = PAGE_SIZE - 1;
 
+/// Individual bits of a page table entry
+pub const PAGE_ENTRY_PRESENT: usize = 1 << 0;
+pub const PAGE_ENTRY_WRITABLE: usize = 1 << 1;
+pub const PAGE_ENTRY_USER: usize = 1 << 2;
+pub const PAGE_ENTRY_HUGE: usize = 1 << 7;
+/// Requires EFER.NXE, see switch_to_ring3.S
+pub const PAGE_ENTRY_NO_EXECUTE: usize = 1 << 63;
+
+/// Available bit: page is shared copy-on-write after a fork
+pub const PAGE_ENTRY_COPY_ON_WRITE: usize = 1 << 9;
+
 /// Mask for extracting the physical address from a page table entry
 pub const ENTRY_MASK_2MB: usize = 0x000f_ffff_ffe0_0000; // bits [51:21]
 pub const ENTRY_MASK_4KB: usize = 0x000f_ffff_ffff_f000; // bits [51:12]
@@ -48,3 +62,22 @@ pub const MAX_PAGE_FRAMES: usize = 0x100000000 / PAGE_SIZE; // 4 GiB total memor
 pub const KERNEL_HIGHER_HALF_BASE: usize = 0xffff_8000_0000_0000;
 pub const KERNEL_STACK_TOP_ADDRESS: usize = 0xffff_ffff_ffff_ffff;
 pub const USERSPACE_STACK_TOP_ADDRESS: usize = 0x0000_7fff_ffff_fff0;
+
+/// The stack of a process grows on demand when an access hits this range just below its bottom
+pub const USER_STACK_GROWTH_WINDOW: usize = 0x10000; // 64 KiB
+
+/// Default limit of the stack size of a process, see RLIMIT_STACK
+pub const USER_STACK_DEFAULT_LIMIT: usize = 0x800000; // 8 MiB
+
+/// Memory mappings of a process are placed below this address, leaving room for the stack above
+pub const USER_MMAP_TOP: usize = 0x0000_7000_0000_0000;
+
+/// All physical memory (first 4 GiB) is mapped to l4 entry 257, see main.asm // SYNCID3
+pub const PHYSICAL_MEMORY_L4_INDEX: usize = 257;
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffff_8080_0000_0000;
+
+/// Size of the kernel stack used for interrupts and syscalls
+pub const KERNEL_STACK_SIZE: usize = 0x80000; // 512 KiB
+
+/// Size of the stack used while no process is ready for execution
+pub const IDLE_STACK_SIZE: usize = 0x10000; // 64 KiB
@@ -0,0 +1,215 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+// protection of a memory area, see sys/mman.h in libc
+pub const PROT_READ: u32 = 0x1;
+pub const PROT_WRITE: u32 = 0x2;
+pub const PROT_EXEC: u32 = 0x4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAreaKind {
+    Code,
+    Data,
+    Heap,
+    Stack,
+    Anonymous,
+}
+
+/** A page aligned range [start, end) of the virtual address space of a process */
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryArea {
+    pub start: usize,
+    pub end: usize,
+    pub kind: MemoryAreaKind,
+    pub protection: u32,
+}
+
+impl MemoryArea {
+    pub fn new(start: usize, end: usize, kind: MemoryAreaKind, protection: u32) -> Self {
+        Self {
+            start,
+            end,
+            kind,
+            protection,
+        }
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        self.start <= address && address < self.end
+    }
+}
+
+/** The memory areas of a process, sorted by their start address and never overlapping */
+#[derive(Debug, Clone)]
+pub struct MemoryAreas {
+    areas: Vec<MemoryArea>,
+}
+
+impl MemoryAreas {
+    pub fn new() -> Self {
+        Self { areas: Vec::new() }
+    }
+
+    pub fn clear(&mut self) {
+        self.areas.clear();
+    }
+
+    // the caller has to make sure the range of the area is free
+    pub fn insert(&mut self, area: MemoryArea) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if area.start == area.end {
+            return;
+        }
+
+        let index = self.areas.partition_point(|other| other.start < area.start);
+        self.areas.insert(index, area);
+        self.merge_adjacent();
+    }
+
+    // joins neighbouring areas of the same kind and protection, e.g. the heap after the program break moved up
+    fn merge_adjacent(&mut self) {
+        self.areas.dedup_by(|next, previous| {
+            if previous.end == next.start
+                && previous.kind == next.kind
+                && previous.protection == next.protection
+            {
+                previous.end = next.end;
+                return true;
+            }
+            false
+        });
+    }
+
+    // the area containing the given address
+    pub fn find(&self, address: usize) -> Option<&MemoryArea> {
+        self.areas.iter().find(|area| area.contains(address))
+    }
+
+    pub fn find_kind_mut(&mut self, kind: MemoryAreaKind) -> Option<&mut MemoryArea> {
+        self.areas.iter_mut().find(|area| area.kind == kind)
+    }
+
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        !self.areas.iter().any(|area| area.overlaps(start, end))
+    }
+
+    // Whether all areas overlapping [start, end) are of the given kind
+    pub fn all_of_kind(&self, start: usize, end: usize, kind: MemoryAreaKind) -> bool {
+        self.areas
+            .iter()
+            .filter(|area| area.overlaps(start, end))
+            .all(|area| area.kind == kind)
+    }
+
+    // Returns the highest free range of the given size within [lowest, highest)
+    pub fn find_free_range(&self, size: usize, lowest: usize, highest: usize) -> Option<usize> {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let mut end = highest;
+
+        for area in self.areas.iter().rev() {
+            if area.start >= end {
+                continue;
+            }
+
+            if end >= area.end.max(lowest) + size {
+                return Some(end - size);
+            }
+
+            end = area.start;
+        }
+
+        if end >= lowest + size {
+            return Some(end - size);
+        }
+
+        None
+    }
+
+    // Removes [start, end) from all areas of the given kind, splitting them if necessary
+    // Returns the removed ranges
+    pub fn remove_range(
+        &mut self,
+        start: usize,
+        end: usize,
+        kind: MemoryAreaKind,
+    ) -> Vec<(usize, usize)> {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let mut removed = Vec::new();
+        let mut remaining = Vec::with_capacity(self.areas.len() + 1);
+
+        for area in self.areas.drain(..) {
+            if area.kind != kind || !area.overlaps(start, end) {
+                remaining.push(area);
+                continue;
+            }
+
+            removed.push((area.start.max(start), area.end.min(end)));
+
+            if area.start < start {
+                remaining.push(MemoryArea::new(area.start, start, kind, area.protection));
+            }
+            if end < area.end {
+                remaining.push(MemoryArea::new(end, area.end, kind, area.protection));
+            }
+        }
+
+        self.areas = remaining;
+        removed
+    }
+
+    // Changes the protection of [start, end), splitting areas if necessary
+    // Returns false without changing anything if a part of the range does not belong to an area
+    pub fn protect_range(&mut self, start: usize, end: usize, protection: u32) -> bool {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        // the areas are sorted, so they cover the range if there is no gap between them
+        let mut covered = start;
+        for area in self.areas.iter().filter(|area| area.overlaps(start, end)) {
+            if area.start > covered {
+                return false;
+            }
+            covered = area.end;
+        }
+        if covered < end {
+            return false;
+        }
+
+        let mut areas = Vec::with_capacity(self.areas.len() + 2);
+
+        for area in self.areas.drain(..) {
+            if !area.overlaps(start, end) {
+                areas.push(area);
+                continue;
+            }
+
+            if area.start < start {
+                areas.push(MemoryArea::new(
+                    area.start,
+                    start,
+                    area.kind,
+                    area.protection,
+                ));
+            }
+            areas.push(MemoryArea::new(
+                area.start.max(start),
+                area.end.min(end),
+                area.kind,
+                protection,
+            ));
+            if end < area.end {
+                areas.push(MemoryArea::new(end, area.end, area.kind, area.protection));
+            }
+        }
+
+        self.areas = areas;
+        self.merge_adjacent();
+        true
+    }
+}
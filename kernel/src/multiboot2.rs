@@ -0,0 +1,256 @@
+use crate::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html#Boot-information-format
+
+// physical address of the boot information passed by the boot loader in ebx, see main.asm
+static BOOT_INFORMATION_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+const TAG_TYPE_END: u32 = 0;
+const TAG_TYPE_COMMAND_LINE: u32 = 1;
+const TAG_TYPE_BOOT_LOADER_NAME: u32 = 2;
+const TAG_TYPE_MODULE: u32 = 3;
+const TAG_TYPE_MEMORY_MAP: u32 = 6;
+const TAG_TYPE_FRAMEBUFFER: u32 = 8;
+const TAG_TYPE_ACPI_OLD_RSDP: u32 = 14;
+const TAG_TYPE_ACPI_NEW_RSDP: u32 = 15;
+
+/// Type of memory map entries which can be used freely
+pub const MEMORY_AVAILABLE: u32 = 1;
+
+/// Framebuffer type of the VGA text mode
+pub const FRAMEBUFFER_TYPE_EGA_TEXT: u8 = 2;
+
+#[repr(C)]
+struct BootInformationHeader {
+    total_size: u32,
+    _reserved: u32,
+}
+
+#[repr(C)]
+struct TagHeader {
+    tag_type: u32,
+    size: u32,
+}
+
+#[repr(C)]
+struct MemoryMapTag {
+    header: TagHeader,
+    entry_size: u32,
+    entry_version: u32,
+}
+
+#[repr(C)]
+struct ModuleTag {
+    header: TagHeader,
+    module_start: u32,
+    module_end: u32,
+    // followed by the zero terminated command line of the module
+}
+
+#[repr(C, packed)]
+struct FramebufferTag {
+    header: TagHeader,
+    address: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bits_per_pixel: u8,
+    framebuffer_type: u8,
+    _reserved: u8,
+    // followed by the color info depending on the type
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryMapEntry {
+    pub base_address: u64,
+    pub length: u64,
+    pub entry_type: u32,
+    _reserved: u32,
+}
+
+pub fn init(boot_information_address: usize) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    BOOT_INFORMATION_ADDRESS.store(boot_information_address, Ordering::Relaxed);
+}
+
+// Returns the physical address and size of the boot information, which must not be overwritten
+pub fn boot_information_range() -> Option<(usize, usize)> {
+    let address = BOOT_INFORMATION_ADDRESS.load(Ordering::Relaxed);
+    if address == 0 {
+        return None;
+    }
+
+    let header = mem::physical_to_virtual(address) as *const BootInformationHeader;
+    Some((address, unsafe { (*header).total_size } as usize))
+}
+
+// Iterates over the tags as (type, virtual address of the tag)
+fn tags() -> TagIterator {
+    match boot_information_range() {
+        Some((address, size)) => {
+            let start = mem::physical_to_virtual(address);
+            TagIterator {
+                current: start + core::mem::size_of::<BootInformationHeader>(),
+                end: start + size,
+            }
+        }
+        None => TagIterator { current: 0, end: 0 },
+    }
+}
+
+fn find_tag(tag_type: u32) -> Option<usize> {
+    tags()
+        .find(|&(t, _)| t == tag_type)
+        .map(|(_, address)| address)
+}
+
+struct TagIterator {
+    current: usize,
+    end: usize,
+}
+
+impl Iterator for TagIterator {
+    type Item = (u32, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current + core::mem::size_of::<TagHeader>() > self.end {
+            return None;
+        }
+
+        let tag = unsafe { &*(self.current as *const TagHeader) };
+        if tag.tag_type == TAG_TYPE_END {
+            return None;
+        }
+
+        let address = self.current;
+        // tags are 8 byte aligned
+        self.current += (tag.size as usize + 7) & !7;
+
+        Some((tag.tag_type, address))
+    }
+}
+
+// The memory map provided by the firmware; None if the boot loader did not pass one
+pub fn memory_map() -> Option<MemoryMapIterator> {
+    let address = find_tag(TAG_TYPE_MEMORY_MAP)?;
+    let tag = unsafe { &*(address as *const MemoryMapTag) };
+
+    Some(MemoryMapIterator {
+        current: address + core::mem::size_of::<MemoryMapTag>(),
+        end: address + tag.header.size as usize,
+        entry_size: tag.entry_size as usize,
+    })
+}
+
+pub struct MemoryMapIterator {
+    current: usize,
+    end: usize,
+    entry_size: usize,
+}
+
+impl Iterator for MemoryMapIterator {
+    type Item = MemoryMapEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entry_size == 0 || self.current + self.entry_size > self.end {
+            return None;
+        }
+
+        let entry = unsafe { core::ptr::read_unaligned(self.current as *const MemoryMapEntry) };
+        self.current += self.entry_size;
+
+        Some(entry)
+    }
+}
+
+// Reads the zero terminated string starting at the given virtual address
+fn read_string(address: usize, end: usize) -> &'static str {
+    let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, end - address) };
+    let length = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+
+    core::str::from_utf8(&bytes[..length]).unwrap_or("")
+}
+
+// Returns the string stored in a tag right after its header
+fn string_tag(tag_type: u32) -> Option<&'static str> {
+    let address = find_tag(tag_type)?;
+    let tag = unsafe { &*(address as *const TagHeader) };
+
+    Some(read_string(
+        address + core::mem::size_of::<TagHeader>(),
+        address + tag.size as usize,
+    ))
+}
+
+// The kernel command line as given in grub.cfg
+pub fn command_line() -> Option<&'static str> {
+    string_tag(TAG_TYPE_COMMAND_LINE)
+}
+
+pub fn boot_loader_name() -> Option<&'static str> {
+    string_tag(TAG_TYPE_BOOT_LOADER_NAME)
+}
+
+/** A file loaded by the boot loader together with the kernel, e.g. with `module2` in grub.cfg */
+#[derive(Debug, Copy, Clone)]
+pub struct Module {
+    // physical addresses
+    pub start: usize,
+    pub end: usize,
+    pub command_line: &'static str,
+}
+
+pub fn modules() -> impl Iterator<Item = Module> {
+    tags()
+        .filter(|&(tag_type, _)| tag_type == TAG_TYPE_MODULE)
+        .map(|(_, address)| {
+            let tag = unsafe { &*(address as *const ModuleTag) };
+
+            Module {
+                start: tag.module_start as usize,
+                end: tag.module_end as usize,
+                command_line: read_string(
+                    address + core::mem::size_of::<ModuleTag>(),
+                    address + tag.header.size as usize,
+                ),
+            }
+        })
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FramebufferInfo {
+    // physical address
+    pub address: u64,
+    // bytes per line
+    pub pitch: u32,
+    // in pixels or in characters for text mode
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u8,
+    pub framebuffer_type: u8,
+}
+
+pub fn framebuffer() -> Option<FramebufferInfo> {
+    let address = find_tag(TAG_TYPE_FRAMEBUFFER)?;
+    let tag = unsafe { core::ptr::read_unaligned(address as *const FramebufferTag) };
+
+    Some(FramebufferInfo {
+        address: tag.address,
+        pitch: tag.pitch,
+        width: tag.width,
+        height: tag.height,
+        bits_per_pixel: tag.bits_per_pixel,
+        framebuffer_type: tag.framebuffer_type,
+    })
+}
+
+// Virtual address of the copy of the RSDP which the boot loader put into the boot information;
+// the ACPI 2.0+ version is preferred over the original one
+pub fn rsdp() -> Option<usize> {
+    find_tag(TAG_TYPE_ACPI_NEW_RSDP)
+        .or_else(|| find_tag(TAG_TYPE_ACPI_OLD_RSDP))
+        .map(|address| address + core::mem::size_of::<TagHeader>())
+}
@@ -0,0 +1,79 @@
+use crate::address_space::AddressSpace;
+use crate::util::write_msr;
+use core::arch::asm;
+use core::ptr::addr_of_mut;
+
+/// Maximum number of CPUs which are brought up
+pub const MAX_CPUS: usize = 8;
+
+const IA32_GS_BASE_MSR: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE_MSR: u32 = 0xc000_0102;
+
+/**
+ * Data which exists once per CPU, the base of the gs segment points to the instance of the current CPU.
+ *
+ * The first fields are accessed from assembly code via gs, see the const operands of the global_asm! blocks.
+ */
+#[repr(C)]
+pub struct PerCpu {
+    self_pointer: u64,
+    // read by syscall_handler to switch from the user stack to the kernel stack
+    pub kernel_stack_top: u64,
+    pub syscall_user_rsp: u64,
+    // interrupt stack frame and registers of the innermost interrupt, see interrupt.S
+    pub stack_frame: u64,
+    pub pushed_registers: u64,
+    pub int_no: u64,
+    // set while a syscall is executed, the timer must not switch processes then
+    pub scheduling_blocked: u8,
+
+    pub cpu_index: usize,
+    pub apic_id: u8,
+    // pid of the process running on this CPU, 0 while idling
+    pub current_process: usize,
+    // address space of the current process, used by the page fault handler as USERLAND may be locked during a fault
+    pub address_space: *mut AddressSpace,
+}
+
+static mut PER_CPU: [PerCpu; MAX_CPUS] = [const {
+    PerCpu {
+        self_pointer: 0,
+        kernel_stack_top: 0,
+        syscall_user_rsp: 0,
+        stack_frame: 0,
+        pushed_registers: 0,
+        int_no: 0,
+        scheduling_blocked: 1,
+        cpu_index: 0,
+        apic_id: 0,
+        current_process: 0,
+        address_space: core::ptr::null_mut(),
+    }
+}; MAX_CPUS];
+
+// Points gs of the calling CPU to its per-CPU data
+// The user gs base starts at 0 and is swapped in by swapgs when returning to user mode, while the kernel gs base
+// holds the per-CPU data in the meantime
+pub fn init_per_cpu(cpu_index: usize, apic_id: u8, kernel_stack_top: u64) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    unsafe {
+        let per_cpu = &mut *addr_of_mut!(PER_CPU[cpu_index]);
+        per_cpu.self_pointer = per_cpu as *mut PerCpu as u64;
+        per_cpu.kernel_stack_top = kernel_stack_top;
+        per_cpu.cpu_index = cpu_index;
+        per_cpu.apic_id = apic_id;
+
+        write_msr(IA32_GS_BASE_MSR, per_cpu.self_pointer);
+        write_msr(IA32_KERNEL_GS_BASE_MSR, 0);
+    }
+}
+
+// The data of the CPU this code is running on
+pub fn current() -> &'static mut PerCpu {
+    let per_cpu: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) per_cpu, options(nostack, preserves_flags, readonly));
+        &mut *(per_cpu as *mut PerCpu)
+    }
+}
@@ -0,0 +1,141 @@
+use crate::file_descriptor::IoResult;
+use crate::wait_queue::WaitQueue;
+use spin::Mutex;
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+const PIPE_BUFFER_SIZE: usize = 0x1000;
+
+struct PipeBuffer {
+    data: [u8; PIPE_BUFFER_SIZE],
+    read_pos: usize,
+    len: usize,
+    readers: usize,
+    writers: usize,
+}
+
+/**
+ * Ring buffer shared by the two ends of a pipe.
+ *
+ * Readers wait while the buffer is empty and get EOF (0 bytes) once all write ends are closed,
+ * writers wait while it is full and fail with a broken pipe once all read ends are closed.
+ */
+pub struct Pipe {
+    buffer: Mutex<PipeBuffer>,
+    read_queue: Arc<WaitQueue>,
+    write_queue: Arc<WaitQueue>,
+}
+
+// The read or write end of a pipe, as referenced by an OpenFile
+// Dropping it (i.e. closing the last file descriptor of it) wakes up the processes waiting at the other end
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    is_writer: bool,
+}
+
+pub fn create_pipe() -> (PipeEnd, PipeEnd) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(PipeBuffer {
+            data: [0; PIPE_BUFFER_SIZE],
+            read_pos: 0,
+            len: 0,
+            readers: 1,
+            writers: 1,
+        }),
+        read_queue: Arc::new(WaitQueue::new()),
+        write_queue: Arc::new(WaitQueue::new()),
+    });
+
+    (
+        PipeEnd {
+            pipe: pipe.clone(),
+            is_writer: false,
+        },
+        PipeEnd {
+            pipe,
+            is_writer: true,
+        },
+    )
+}
+
+impl PipeEnd {
+    pub fn read(&self, buffer: *mut u8, len: usize) -> IoResult {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if self.is_writer {
+            return IoResult::Done(u64::MAX);
+        }
+
+        let waiter = self.pipe.read_queue.prepare_to_wait();
+        let mut pipe_buffer = self.pipe.buffer.lock();
+
+        if pipe_buffer.len == 0 {
+            if pipe_buffer.writers == 0 {
+                // end of file
+                return IoResult::Done(0);
+            }
+            return IoResult::Wait(waiter);
+        }
+
+        let bytes_to_read = core::cmp::min(len, pipe_buffer.len);
+        for i in 0..bytes_to_read {
+            unsafe {
+                *buffer.add(i) = pipe_buffer.data[(pipe_buffer.read_pos + i) % PIPE_BUFFER_SIZE];
+            }
+        }
+        pipe_buffer.read_pos = (pipe_buffer.read_pos + bytes_to_read) % PIPE_BUFFER_SIZE;
+        pipe_buffer.len -= bytes_to_read;
+
+        self.pipe.write_queue.wake_all();
+
+        IoResult::Done(bytes_to_read as u64)
+    }
+
+    pub fn write(&self, buffer: *const u8, len: usize) -> IoResult {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if !self.is_writer {
+            return IoResult::Done(u64::MAX);
+        }
+
+        let waiter = self.pipe.write_queue.prepare_to_wait();
+        let mut pipe_buffer = self.pipe.buffer.lock();
+
+        if pipe_buffer.readers == 0 {
+            return IoResult::BrokenPipe;
+        }
+
+        if pipe_buffer.len == PIPE_BUFFER_SIZE {
+            return IoResult::Wait(waiter);
+        }
+
+        // TODO writes are not atomic, a part of the data might be written now and the rest later
+        let bytes_to_write = core::cmp::min(len, PIPE_BUFFER_SIZE - pipe_buffer.len);
+        for i in 0..bytes_to_write {
+            let write_pos = (pipe_buffer.read_pos + pipe_buffer.len + i) % PIPE_BUFFER_SIZE;
+            pipe_buffer.data[write_pos] = unsafe { *buffer.add(i) };
+        }
+        pipe_buffer.len += bytes_to_write;
+
+        self.pipe.read_queue.wake_all();
+
+        IoResult::Done(bytes_to_write as u64)
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut pipe_buffer = self.pipe.buffer.lock();
+
+        if self.is_writer {
+            pipe_buffer.writers -= 1;
+            self.pipe.read_queue.wake_all();
+        } else {
+            pipe_buffer.readers -= 1;
+            self.pipe.write_queue.wake_all();
+        }
+    }
+}
@@ -1,19 +1,55 @@
 use crate::{
-    DEBUG, ERROR, INFO, filesystem::FileHandle, kprint, mem::allocate_page_frame, mem_config::*,
+    DEBUG, ERROR, INFO,
+    address_space::AddressSpace,
+    file_descriptor::{FileDescriptorTable, OpenFile},
+    filesystem::{self, FileHandle, O_APPEND, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY},
+    keyboard, kprint, mem,
+    mem_config::*,
+    memory_area::{MemoryArea, MemoryAreaKind, PROT_EXEC, PROT_READ, PROT_WRITE},
+    per_cpu, time, timer, util,
+    wait_queue::{WaitQueue, Waiter},
 };
 extern crate alloc;
-use alloc::collections::BTreeMap;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::arch::asm;
 use core::fmt::Debug;
 use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
-use elf::abi::PT_LOAD;
+use elf::ParseError;
+use elf::abi::{EI_CLASS, EI_NIDENT, PF_R, PF_W, PF_X, PT_LOAD};
 use elf::endian::AnyEndian;
+use elf::file::{Class, ELF64_EHDR_TAILSIZE, FileHeader};
+use elf::parse::ParseAt;
+use elf::segment::{ProgramHeader, SegmentTable};
 
 pub static KERNEL_CR3: AtomicUsize = AtomicUsize::new(0);
 pub static NEXT_PROCESS_ID: AtomicUsize = AtomicUsize::new(1);
 
+// Types of the auxiliary vector entries passed to a new program, see System V ABI
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+// resources of getrlimit and setrlimit, see sys/resource.h in libc
+pub const RLIMIT_STACK: u32 = 3;
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/** A soft and a hard limit of a resource, see struct rlimit in libc */
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceLimit {
+    pub rlim_cur: u64,
+    pub rlim_max: u64,
+}
+
 // stores a process' registers when it gets interrupted
 #[repr(C)]
 #[derive(Default, Clone)]
@@ -44,6 +80,83 @@ struct RegistersStruct {
     rax: u64,
 }
 
+// sets rax of the interrupted userspace code, i.e. the return value of a syscall
+pub fn set_syscall_result(result: u64) {
+    let pushed_registers = per_cpu::current().pushed_registers as *mut RegistersStruct;
+
+    unsafe {
+        (*pushed_registers).rax = result;
+    }
+}
+
+// rewinds the interrupted userspace code to the syscall instruction, so the syscall is executed again once the
+// process continues; used by syscalls that have to wait
+pub fn restart_syscall() {
+    let stack_frame = per_cpu::current().stack_frame as *mut u64;
+
+    unsafe {
+        *stack_frame -= 2; // length of the syscall instruction
+    }
+}
+
+// Reads the file header and the program headers of an executable, the segments are loaded on demand; fails if the
+// file is no 64 bit ELF file, e.g. a shell script
+fn read_elf_headers(
+    file_handle: &mut FileHandle,
+) -> Result<(FileHeader<AnyEndian>, Vec<ProgramHeader>), ParseError> {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let mut header = [0u8; EI_NIDENT + ELF64_EHDR_TAILSIZE];
+    file_handle.offset = 0;
+    let header_size = file_handle.read(header.as_mut_ptr(), header.len()) as usize;
+
+    // too short files are rejected by the magic check, unless the header is just truncated
+    let ident = elf::file::parse_ident::<AnyEndian>(&header)?;
+    if header_size != header.len() {
+        return Err(ParseError::SliceReadError((header_size, header.len())));
+    }
+    if ident.1 != Class::ELF64 {
+        return Err(ParseError::UnsupportedElfClass(header[EI_CLASS]));
+    }
+    let elf_header = FileHeader::parse_tail(ident, &header[EI_NIDENT..])?;
+
+    kprint!("Entry point is at: {:x}\n", elf_header.e_entry);
+
+    ProgramHeader::validate_entsize(elf_header.class, elf_header.e_phentsize as usize)?;
+
+    let table_size = elf_header.e_phentsize as usize * elf_header.e_phnum as usize;
+    let mut table = vec![0u8; table_size];
+    file_handle.offset = elf_header.e_phoff as usize;
+    if file_handle.read(table.as_mut_ptr(), table.len()) as usize != table_size {
+        return Err(ParseError::SliceReadError((
+            elf_header.e_phoff as usize,
+            elf_header.e_phoff as usize + table_size,
+        )));
+    }
+
+    let program_headers = SegmentTable::new(elf_header.endianness, elf_header.class, &table)
+        .iter()
+        .collect();
+
+    Ok((elf_header, program_headers))
+}
+
+// Returns the virtual address of the program header table, 0 if it is not loaded
+fn get_program_header_table_address(
+    elf_header: &FileHeader<AnyEndian>,
+    program_headers: &[ProgramHeader],
+) -> usize {
+    // the table is part of a load segment in the file, so it is found at the corresponding virtual address
+    program_headers
+        .iter()
+        .find(|phdr| {
+            phdr.p_type == PT_LOAD
+                && phdr.p_offset <= elf_header.e_phoff
+                && elf_header.e_phoff < phdr.p_offset + phdr.p_filesz
+        })
+        .map_or(0, |phdr| phdr.p_vaddr + elf_header.e_phoff - phdr.p_offset) as usize
+}
+
 #[repr(C)]
 #[repr(align(4096))]
 #[derive(Debug, Clone, Copy)]
@@ -51,14 +164,6 @@ pub struct PageTable {
     pub entry: [usize; PAGE_TABLE_ENTRIES],
 }
 
-impl PageTable {
-    fn default() -> Self {
-        Self {
-            entry: [0; PAGE_TABLE_ENTRIES],
-        }
-    }
-}
-
 fn _print_page_table_tree_for_cr3() {
     let mut cr3: u64;
 
@@ -77,6 +182,11 @@ fn print_page_table_tree(start_addr: u64) {
         kprint!("start_addr: {:#x}\n", start_addr);
 
         for l4_entry in 0..512 {
+            // skip the mapping of the whole physical memory
+            if l4_entry == PHYSICAL_MEMORY_L4_INDEX as u64 {
+                continue;
+            }
+
             let l4bits =
                 *(((start_addr + l4_entry * 8) | KERNEL_HIGHER_HALF_BASE as u64) as *const u64);
             if l4bits != 0 {
@@ -112,6 +222,7 @@ enum ProcessState {
     Active,
     Passive,
     Sleeping,
+    Blocked,
 }
 
 pub struct Process {
@@ -119,34 +230,37 @@ pub struct Process {
 
     registers: RegistersStruct,
 
-    l1_page_table: PageTable,
-    l2_page_directory_table: PageTable,
-    l3_page_directory_pointer_table: PageTable,
-    l4_page_map_l4_table: PageTable,
-
-    l1_page_table_beginning: [PageTable; 16],
-    l2_page_directory_table_beginning: PageTable,
-    l3_page_directory_pointer_table_beginning: PageTable,
+    address_space: AddressSpace,
 
     rip: usize,
     rsp: u64,
-    cr3: usize,
     ss: u64,
     cs: u64,
     rflags: u64,
 
     state: ProcessState,
 
-    heap_allocator: linked_list_allocator::LockedHeap,
-    heap_l1_table_number: usize,
-    heap_l2_table_number: usize,
+    // the wait queue of a blocked process
+    blocked_on: Option<Waiter>,
 
-    stack_page_counter: usize,
+    // the CPU whose registers currently hold the context of the process
+    running_on_cpu: Option<usize>,
+    // wait status of a process killed while it was running on another CPU
+    pending_termination: Option<u64>,
+    // position in the key event queue, None until the process reads key events for the first time
+    key_event_position: Option<u64>,
+    // deadline in ns since boot of a sleep in progress and the wait queue woken up by its timer
+    sleep_timer: Option<(u64, Arc<WaitQueue>)>,
 
-    working_directory: &'static str,
+    // the heap of the process ranges from the end of the program up to the program break
+    program_break_start: usize,
+    program_break: usize,
+    // the maximum size the stack may grow to (RLIMIT_STACK)
+    stack_limit: ResourceLimit,
 
-    file_handles: BTreeMap<u64, FileHandle>,
-    next_handle_id: u64,
+    working_directory: String,
+
+    file_descriptors: FileDescriptorTable,
 
     parent_id: u64,
 }
@@ -165,365 +279,374 @@ impl Process {
             process_id: NEXT_PROCESS_ID.fetch_add(1, Ordering::Relaxed) as u64,
 
             registers: RegistersStruct::default(),
-            l1_page_table: PageTable::default(),
-            l1_page_table_beginning: [PageTable::default(); 16],
-            l2_page_directory_table: PageTable::default(),
-            l2_page_directory_table_beginning: PageTable::default(),
-            l3_page_directory_pointer_table: PageTable::default(),
-            l3_page_directory_pointer_table_beginning: PageTable::default(),
-            l4_page_map_l4_table: PageTable::default(),
+            address_space: AddressSpace::new(),
 
             rip: 0,
-            cr3: 0,
             ss: 0x1b,
             cs: 0x23,
             rflags: 0x202,
             rsp: 0,
             state: ProcessState::New,
+            blocked_on: None,
 
-            heap_allocator: linked_list_allocator::LockedHeap::empty(),
-            heap_l1_table_number: 0,
-            heap_l2_table_number: 0,
+            running_on_cpu: None,
+            pending_termination: None,
+            key_event_position: None,
+            sleep_timer: None,
 
-            stack_page_counter: 0,
+            program_break_start: 0,
+            program_break: 0,
+            stack_limit: ResourceLimit {
+                rlim_cur: USER_STACK_DEFAULT_LIMIT as u64,
+                rlim_max: RLIM_INFINITY,
+            },
 
-            working_directory: "/",
-            file_handles: BTreeMap::new(),
-            next_handle_id: 1,
+            working_directory: String::from("/"),
+            file_descriptors: FileDescriptorTable::new(),
 
             parent_id: 0,
         }
     }
 
-    pub fn initialize(&mut self, file_path: &str) {
+    // Replaces the program of this process; nothing is changed if the file is no valid executable
+    pub fn initialize(
+        &mut self,
+        mut file_handle: FileHandle,
+        args: &[String],
+        env: &[String],
+    ) -> Result<(), ParseError> {
         let _event = core::hint::black_box(crate::instrument!());
 
-        // reset everything (relevant if process was forked from another process)
-        self.registers = RegistersStruct::default();
-        self.l1_page_table = PageTable::default();
-        self.l1_page_table_beginning = [PageTable::default(); 16];
-        self.l2_page_directory_table = PageTable::default();
-        self.l2_page_directory_table_beginning = PageTable::default();
-        self.l3_page_directory_pointer_table = PageTable::default();
-        self.l3_page_directory_pointer_table_beginning = PageTable::default();
-        self.l4_page_map_l4_table = PageTable::default();
-        self.heap_allocator = linked_list_allocator::LockedHeap::empty();
-        self.file_handles = BTreeMap::new();
-        self.heap_l1_table_number = 0;
-        self.heap_l2_table_number = 0;
-        self.stack_page_counter = 0;
-
-        // TODO Hack? map the kernel pages from main.asm to process
-        // TODO Later, the kernel pages should be restricted to superuser access; in order to do so, the process code and data must be fully in userspace pages
-        unsafe {
-            let mut kernel_cr3 = KERNEL_CR3.load(Ordering::Relaxed) as u64;
-
-            if kernel_cr3 == 0 {
-                let mut cr3: u64;
-                asm!("mov r15, cr3", out("r15") cr3);
-
-                KERNEL_CR3.store(cr3 as usize, Ordering::Relaxed);
-                kernel_cr3 = cr3;
-            } else {
-                // load user stack into kernel page table
-                /*let mut user_cr3: u64;
-                asm!("mov r15, cr3", out("r15") user_cr3);
-
-                *((kernel_cr3 | KERNEL_HIGHER_HALF_BASE as u64 + 255 * 8) as *mut u64) =
-                    *((user_cr3 | KERNEL_HIGHER_HALF_BASE as u64 + 255 * 8) as *const u64);
-
-                // in case we are not in the kernel page table, we need to switch to it temporarily to read the kernel page table entries
-                asm!(
-                    "mov cr3, r15",
-                    in("r15") kernel_cr3,
-                    options(nostack, preserves_flags)
-                );*/
-            }
-
-            kprint!("Kernel CR3: {:x}\n", kernel_cr3);
+        let (elf_header, program_headers) = read_elf_headers(&mut file_handle)?;
 
-            print_page_table_tree(KERNEL_CR3.load(Ordering::Relaxed) as u64);
+        // the page tables of this process might be active (execve), so switch to the kernel ones while rebuilding them
+        mem::set_cr3(mem::get_kernel_cr3());
 
-            self.l4_page_map_l4_table.entry[256] =
-                *((KERNEL_CR3.load(Ordering::Relaxed) + 256 * 8) as *const _);
-        }
-
-        if PAGE_SIZE == HUGE_PAGE_SIZE {
-            // allocate one user stack page
-            self.l2_page_directory_table.entry[511] =
-                allocate_page_frame() | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-
-            // TODO HackID1: Fixed kernel stack for interrupts - limited to one PAGE_SIZE!
-            self.l2_page_directory_table.entry[0] =
-                allocate_page_frame() | PAGE_ENTRY_FLAGS_KERNELSPACE as usize;
-        } else {
-            // allocate 502 user stack pages (512 - 10 for kernel stack, see next loop)
-            for i in 0..(512 - 10) {
-                self.l1_page_table.entry[511 - i] =
-                    allocate_page_frame() | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-            }
-
-            // TODO HackID1: Fixed kernel stack for interrupts - limited to ten PAGE_SIZE!
-            for i in 0..10 {
-                self.l1_page_table.entry[i] =
-                    allocate_page_frame() | PAGE_ENTRY_FLAGS_KERNELSPACE as usize;
-            }
+        kprint!("Kernel CR3: {:x}\n", mem::get_kernel_cr3());
 
-            self.l2_page_directory_table.entry[511] =
-                Process::get_physical_address_for_virtual_address(
-                    &self.l1_page_table as *const _ as usize,
-                ) | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-        }
+        print_page_table_tree(mem::get_kernel_cr3() as u64);
 
-        self.stack_page_counter = 1;
+        // reset everything (relevant if process was forked from another process)
+        // TODO Later, the kernel pages should be restricted to superuser access; in order to do so, the process code and data must be fully in userspace pages
+        self.address_space = AddressSpace::new();
+        self.registers = RegistersStruct::default();
+        self.file_descriptors.close_on_exec();
+        self.program_break_start = 0;
+        self.program_break = 0;
 
-        self.l3_page_directory_pointer_table.entry[511] =
-            Process::get_physical_address_for_virtual_address(
-                &self.l2_page_directory_table as *const _ as usize,
-            ) | PAGE_ENTRY_FLAGS_USERSPACE as usize;
+        // the limits are kept across execve
+        self.address_space
+            .set_stack_limit(self.stack_limit.rlim_cur as usize);
 
-        self.l4_page_map_l4_table.entry[255] = Process::get_physical_address_for_virtual_address(
-            &self.l3_page_directory_pointer_table as *const _ as usize,
-        ) | PAGE_ENTRY_FLAGS_USERSPACE as usize;
+        kprint!("Process CR3: {:x}\n", self.address_space.get_cr3());
 
-        let mut file_handle = FileHandle::new(file_path, 0).unwrap();
+        let program_end = self.load_elf_from_bin(file_handle, &program_headers);
+        let entry = elf_header.e_entry as usize;
+        self.rip = entry;
 
-        // put the whole file into a buffer
-        // TODO ensure the *kernel* has enough memory for this
-        let size = file_handle.size() as usize;
-        let mut buffer: Vec<u8> = Vec::with_capacity(size);
-        unsafe {
-            buffer.set_len(size); // unsafe, but we will overwrite all bytes
-        }
-        let program_slice = unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr(), size) };
-        let bytes_read = file_handle.read(program_slice.as_mut_ptr(), size);
-        if bytes_read as usize != size {
-            panic!("Error reading file");
-        }
+        // the heap is empty until the program moves its break
+        self.program_break_start = program_end;
+        self.program_break = program_end;
+
+        // the initial stack is written with the page tables of the process
+        mem::set_cr3(self.address_space.get_cr3());
+
+        let phdr = get_program_header_table_address(&elf_header, &program_headers);
+        self.rsp = self.set_up_initial_stack(
+            args,
+            env,
+            &[
+                (AT_PHDR, phdr as u64),
+                (AT_PHENT, elf_header.e_phentsize as u64),
+                (AT_PHNUM, elf_header.e_phnum as u64),
+                (AT_PAGESZ, PAGE_SIZE as u64),
+                (AT_ENTRY, entry as u64),
+            ],
+        );
 
-        // allocate enough pages at beginning of virtual memory for elf loading
-        // TODO do not map pages before the first used virtual address in the elf file (typically 0x400000)
-        let heap_page_number =
-            (self.get_size_of_program(program_slice) + PAGE_SIZE - 1) / PAGE_SIZE;
+        mem::set_cr3(mem::get_kernel_cr3());
 
-        self.heap_l2_table_number =
-            (heap_page_number + PAGE_TABLE_ENTRIES - 1) / PAGE_TABLE_ENTRIES;
-        self.heap_l1_table_number = (heap_page_number) % PAGE_TABLE_ENTRIES;
+        self.ss = 0x1b;
+        self.cs = 0x23;
+        self.rflags = 0x202;
+        self.state = ProcessState::Prepared;
 
-        if self.heap_l2_table_number > 15 {
-            panic!("Heap size exceeds maximum limit of 16 L2 page directory tables");
-        }
+        Ok(())
+    }
 
-        for i in 0..self.heap_l2_table_number {
-            self.l2_page_directory_table_beginning.entry[i] =
-                Process::get_physical_address_for_virtual_address(
-                    &self.l1_page_table_beginning[i] as *const _ as usize,
-                ) | PAGE_ENTRY_FLAGS_USERSPACE as usize;
+    // Lays out argc, argv, envp and the auxiliary vector below the stack top as described in the System V ABI
+    // and returns the resulting stack pointer; the page tables of this process have to be loaded
+    fn set_up_initial_stack(
+        &mut self,
+        args: &[String],
+        env: &[String],
+        auxiliary_vector: &[(u64, u64)],
+    ) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
 
-            if i < self.heap_l2_table_number - 1 {
-                for j in 0..PAGE_TABLE_ENTRIES {
-                    self.l1_page_table_beginning[i].entry[j] =
-                        allocate_page_frame() | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-                }
-            } else {
-                for j in 0..self.heap_l1_table_number {
-                    self.l1_page_table_beginning[i].entry[j] =
-                        allocate_page_frame() | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-                }
+        // the page fault handler does not know the process yet, so map the pages which are written below; the
+        // stack grows down from there on demand
+        let strings_size: usize = args.iter().chain(env).map(|string| string.len() + 1).sum();
+        let words_size = (args.len() + env.len() + 2 * auxiliary_vector.len() + 7) * 8;
+        // plus the random bytes and some room for the alignment
+        let lowest_address = USERSPACE_STACK_TOP_ADDRESS - strings_size - words_size - 64;
+
+        let stack_start = lowest_address & !PAGE_OFFSET_MASK;
+        let stack_end = USERSPACE_STACK_TOP_ADDRESS.next_multiple_of(PAGE_SIZE);
+
+        self.address_space
+            .map_range(stack_start, stack_end, PROT_READ | PROT_WRITE);
+        self.address_space
+            .memory_areas_mut()
+            .insert(MemoryArea::new(
+                stack_start,
+                stack_end,
+                MemoryAreaKind::Stack,
+                PROT_READ | PROT_WRITE,
+            ));
+
+        let mut stack_pointer = USERSPACE_STACK_TOP_ADDRESS;
+
+        // the strings themselves are placed at the very top
+        let mut push_string = |string: &str| -> u64 {
+            stack_pointer -= string.len() + 1;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    string.as_ptr(),
+                    stack_pointer as *mut u8,
+                    string.len(),
+                );
+                *((stack_pointer + string.len()) as *mut u8) = 0;
             }
-        }
-
-        self.l3_page_directory_pointer_table_beginning.entry[0] =
-            Process::get_physical_address_for_virtual_address(
-                &self.l2_page_directory_table_beginning as *const _ as usize,
-            ) | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-        self.l4_page_map_l4_table.entry[0] = Process::get_physical_address_for_virtual_address(
-            &self.l3_page_directory_pointer_table_beginning as *const _ as usize,
-        ) | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-
-        //print_page_table_tree(&self.l4_page_map_l4_table as *const _ as u64);
-
-        // TODO Here we load the new pagetable into cr3 for the first process. This needs to happen because otherwise we cant load the programm into the first pages. This is a hack I think
-        self.cr3 = Process::get_physical_address_for_virtual_address(
-            &self.l4_page_map_l4_table as *const _ as usize,
-        );
+            stack_pointer as u64
+        };
 
-        kprint!("Process CR3: {:x}\n", self.cr3);
+        let arg_pointers: Vec<u64> = args.iter().map(|arg| push_string(arg)).collect();
+        let env_pointers: Vec<u64> = env.iter().map(|var| push_string(var)).collect();
 
+        // 16 random bytes, e.g. used by a libc as stack protector canary
+        stack_pointer = (stack_pointer - 16) & !0xf;
+        let random_bytes = stack_pointer as u64;
         unsafe {
-            asm!(
-                "mov cr3, r15",
-                in("r15") self.cr3,
-                options(nostack, preserves_flags)
-            );
+            *(stack_pointer as *mut u64) = util::pseudo_random_u64();
+            *((stack_pointer + 8) as *mut u64) = util::pseudo_random_u64();
         }
 
-        //print_page_table_tree(&self.l4_page_map_l4_table as *const _ as u64);
-
-        self.rsp = USERSPACE_STACK_TOP_ADDRESS as u64;
-
-        let (entry, v_addr, p_memsz) = self.load_elf_from_bin(&program_slice);
-        self.rip = entry;
+        let mut words: Vec<u64> = Vec::new();
+        words.push(args.len() as u64);
+        words.extend_from_slice(&arg_pointers);
+        words.push(0);
+        words.extend_from_slice(&env_pointers);
+        words.push(0);
+        for (key, value) in auxiliary_vector {
+            words.push(*key);
+            words.push(*value);
+        }
+        words.extend_from_slice(&[AT_RANDOM, random_bytes, AT_NULL, 0]);
 
-        self.init_process_heap(v_addr, p_memsz);
+        // argc has to be 16 byte aligned
+        if words.len() % 2 != 0 {
+            stack_pointer -= 8;
+        }
+        stack_pointer -= words.len() * 8;
 
         unsafe {
-            asm!(
-                "mov cr3, r15",
-                in("r15") KERNEL_CR3.load(Ordering::Relaxed) as u64,
-                options(nostack, preserves_flags)
-            );
+            core::ptr::copy_nonoverlapping(words.as_ptr(), stack_pointer as *mut u64, words.len());
         }
 
-        self.ss = 0x1b;
-        self.cs = 0x23;
-        self.rflags = 0x202;
-        self.state = ProcessState::Prepared;
+        stack_pointer as u64
     }
 
-    fn init_process_heap(&mut self, v_addr: usize, p_memsz: usize) {
+    // Moves the program break to the given address; returns the (possibly unchanged) program break
+    pub fn brk(&mut self, address: usize) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
 
-        let heap_bottom = v_addr + p_memsz + 1;
-
-        // calculate heap size as difference between the beginning of the next page frame and heap bottom
-        let heap_size = PAGE_SIZE - (heap_bottom % PAGE_SIZE);
-
-        unsafe {
-            self.heap_allocator.lock().init(
-                heap_bottom as *mut u8,
-                heap_size as usize, // TODO Heap only uses the rest of the current page frame
-            );
+        if address < self.program_break_start || address > USER_MMAP_TOP {
+            return self.program_break as u64;
         }
-    }
 
-    // TODO unallocate stack memory when stack gets smaller again?
-    pub fn extend_stack(&mut self) {
-        let _event = core::hint::black_box(crate::instrument!());
+        let old_end = self.program_break.next_multiple_of(PAGE_SIZE);
+        let new_end = address.next_multiple_of(PAGE_SIZE);
 
-        self.stack_page_counter += 1;
-
-        if self.stack_page_counter >= PAGE_TABLE_ENTRIES {
-            panic!("Stack size exceeds maximum limit of 512 pages");
-        }
+        if new_end > old_end {
+            // the heap must not grow into a memory mapping
+            if !self.address_space.memory_areas().is_free(old_end, new_end) {
+                return self.program_break as u64;
+            }
 
-        // TODO limited to 512 stack pages
-        if PAGE_SIZE == HUGE_PAGE_SIZE {
-            self.l2_page_directory_table.entry[PAGE_TABLE_ENTRIES - self.stack_page_counter] =
-                allocate_page_frame() | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-            return;
+            self.address_space
+                .memory_areas_mut()
+                .insert(MemoryArea::new(
+                    old_end,
+                    new_end,
+                    MemoryAreaKind::Heap,
+                    PROT_READ | PROT_WRITE,
+                ));
         } else {
-            self.l1_page_table.entry[PAGE_TABLE_ENTRIES - self.stack_page_counter] =
-                allocate_page_frame() | PAGE_ENTRY_FLAGS_USERSPACE as usize;
+            self.address_space.memory_areas_mut().remove_range(
+                new_end,
+                old_end,
+                MemoryAreaKind::Heap,
+            );
+            self.address_space.unmap_range(new_end, old_end);
         }
+
+        self.program_break = address;
+        self.program_break as u64
     }
 
-    pub fn malloc(&mut self, size: usize) -> u64 {
+    // Reserves zeroed memory at the given address or, if it is 0 or not available, anywhere above the heap
+    // The pages are mapped on their first access
+    pub fn mmap(&mut self, address: usize, length: usize, protection: u32, fixed: bool) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
 
-        unsafe {
-            let layout = core::alloc::Layout::from_size_align_unchecked(size, 0x8);
+        if length == 0
+            || length > USER_MMAP_TOP
+            || address % PAGE_SIZE != 0
+            || protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        {
+            return u64::MAX;
+        }
 
-            let success = false;
+        let Some(length) = length.checked_next_multiple_of(PAGE_SIZE) else {
+            return u64::MAX;
+        };
+        let requested = address != 0
+            && address
+                .checked_add(length)
+                .is_some_and(|end| end <= USER_MMAP_TOP);
+
+        let start = if fixed {
+            if !requested || address < self.program_break_start {
+                return u64::MAX;
+            }
 
-            while !success {
-                match self.heap_allocator.lock().allocate_first_fit(layout) {
-                    Ok(address) => {
-                        return address.as_ptr() as u64;
-                    }
-                    Err(()) => {
-                        //DEBUG!("Allocating userspace memory failed - attempting to increase heap size\n");
-                    }
-                }
+            // a fixed mapping replaces the mappings it overlaps
+            self.munmap(address, length);
 
-                // allocate more memory if not sufficient amount is available
-                // TODO this only works until 7 l1 page directory tables are full; // later, we need to allocate more l1 page directory tables
+            if !self
+                .address_space
+                .memory_areas()
+                .is_free(address, address + length)
+            {
+                return u64::MAX;
+            }
 
-                // Map a new frame into the L1 table
-                self.l1_page_table_beginning[self.heap_l2_table_number - 1].entry
-                    [self.heap_l1_table_number] =
-                    allocate_page_frame() | PAGE_ENTRY_FLAGS_USERSPACE as usize;
+            address
+        } else if requested
+            && self
+                .address_space
+                .memory_areas()
+                .is_free(address, address + length)
+        {
+            address
+        } else {
+            let lowest = self.program_break.next_multiple_of(PAGE_SIZE);
+
+            match self
+                .address_space
+                .memory_areas()
+                .find_free_range(length, lowest, USER_MMAP_TOP)
+            {
+                Some(start) => start,
+                None => return u64::MAX,
+            }
+        };
 
-                // Ensure the page table write is visible before proceeding
-                // TODO really required?
-                core::sync::atomic::fence(Ordering::Release);
+        self.address_space
+            .memory_areas_mut()
+            .insert(MemoryArea::new(
+                start,
+                start + length,
+                MemoryAreaKind::Anonymous,
+                protection,
+            ));
 
-                self.heap_l1_table_number += 1;
+        start as u64
+    }
 
-                if self.heap_l1_table_number >= PAGE_TABLE_ENTRIES {
-                    self.heap_l1_table_number = 0;
-                    self.heap_l2_table_number += 1;
+    // Unmaps the parts of anonymous mappings within the given range
+    // Code, data, heap and stack are managed by the kernel, so a range overlapping them is rejected
+    pub fn munmap(&mut self, address: usize, length: usize) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
 
-                    if self.heap_l2_table_number >= 15 {
-                        ERROR!("Heap size exceeds maximum limit of 16 L1 page directory tables\n");
-                        panic!("Out of memory");
-                    }
+        if length == 0 || address % PAGE_SIZE != 0 || address >= USER_MMAP_TOP {
+            return u64::MAX;
+        }
 
-                    self.l2_page_directory_table_beginning.entry[self.heap_l2_table_number - 1] =
-                        &self.l1_page_table_beginning[self.heap_l2_table_number - 1] as *const _
-                            as usize
-                            - KERNEL_HIGHER_HALF_BASE
-                            | PAGE_ENTRY_FLAGS_USERSPACE as usize;
-                }
+        let end = match length
+            .checked_next_multiple_of(PAGE_SIZE)
+            .and_then(|length| address.checked_add(length))
+        {
+            Some(end) => end.min(USER_MMAP_TOP),
+            None => return u64::MAX,
+        };
 
-                self.heap_allocator.lock().extend(PAGE_SIZE);
-            }
+        if !self
+            .address_space
+            .memory_areas()
+            .all_of_kind(address, end, MemoryAreaKind::Anonymous)
+        {
+            return u64::MAX;
+        }
 
-            ERROR!("Failed to allocate memory after extending heap\n");
-            panic!("Out of memory");
+        for (start, end) in self.address_space.memory_areas_mut().remove_range(
+            address,
+            end,
+            MemoryAreaKind::Anonymous,
+        ) {
+            self.address_space.unmap_range(start, end);
         }
+
+        0
     }
 
-    pub fn realloc(&mut self, ptr: u64, new_size: usize) -> u64 {
+    // Changes the protection of the pages within the given range, which have to belong to memory areas
+    pub fn mprotect(&mut self, address: usize, length: usize, protection: u32) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
 
-        unsafe {
-            if ptr == 0 {
-                return self.malloc(new_size);
-            }
-
-            let layout = core::alloc::Layout::from_size_align_unchecked(new_size, 0x8);
+        if address % PAGE_SIZE != 0
+            || address >= KERNEL_HIGHER_HALF_BASE
+            || length > KERNEL_HIGHER_HALF_BASE - address
+            || protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        {
+            return u64::MAX;
+        }
 
-            if new_size == 0 {
-                self.heap_allocator
-                    .lock()
-                    .deallocate(core::ptr::NonNull::new_unchecked(ptr as *mut u8), layout);
-                return 0;
-            }
+        let end = (address + length).next_multiple_of(PAGE_SIZE);
 
-            /*
-                // SAFETY: the caller must ensure that the `new_size` does not overflow.
-                // `layout.align()` comes from a `Layout` and is thus guaranteed to be valid.
-                let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
-                // SAFETY: the caller must ensure that `new_layout` is greater than zero.
-                let new_ptr = unsafe { self.alloc(new_layout) };
-                if !new_ptr.is_null() {
-                    // SAFETY: the previously allocated block cannot overlap the newly allocated block.
-                    // The safety contract for `dealloc` must be upheld by the caller.
-                    unsafe {
-                        ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
-                        self.dealloc(ptr, layout);
-                    }
-                }
-            new_ptr */
+        match self.address_space.protect_range(address, end, protection) {
+            true => 0,
+            false => u64::MAX,
+        }
+    }
 
-            let new_ptr = self.heap_allocator.lock().allocate_first_fit(layout);
+    pub fn getrlimit(&self, resource: u32) -> Option<ResourceLimit> {
+        let _event = core::hint::black_box(crate::instrument!());
 
-            if new_ptr.is_ok() {
-                let new_address = new_ptr.unwrap().as_ptr() as u64;
+        match resource {
+            RLIMIT_STACK => Some(self.stack_limit),
+            _ => None,
+        }
+    }
 
-                core::ptr::copy_nonoverlapping(ptr as *const u8, new_address as *mut u8, new_size);
+    // Returns 0 or u64::MAX if the resource is not supported or the limit is invalid
+    pub fn setrlimit(&mut self, resource: u32, limit: ResourceLimit) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
 
-                self.heap_allocator
-                    .lock()
-                    .deallocate(core::ptr::NonNull::new_unchecked(ptr as *mut u8), layout);
+        // without privileges the hard limit can only be lowered
+        if resource != RLIMIT_STACK
+            || limit.rlim_cur > limit.rlim_max
+            || limit.rlim_max > self.stack_limit.rlim_max
+        {
+            return u64::MAX;
+        }
 
-                return new_address;
-            }
+        // a stack which is already larger is kept, but does not grow anymore
+        self.stack_limit = limit;
+        self.address_space.set_stack_limit(limit.rlim_cur as usize);
 
-            ERROR!("Failed to reallocate memory\n");
-            panic!("Out of memory");
-        }
+        0
     }
 
     pub fn launch(&mut self) {
@@ -537,10 +660,9 @@ impl Process {
         let _event = core::hint::black_box(crate::instrument!());
 
         DEBUG!("Activating process");
-        unsafe extern "C" {
-            static mut pushed_registers: *mut RegistersStruct;
-            static mut stack_frame: *mut u64;
-        }
+        let per_cpu = per_cpu::current();
+        let pushed_registers = per_cpu.pushed_registers as *mut RegistersStruct;
+        let stack_frame = per_cpu.stack_frame as *mut u64;
 
         unsafe {
             //kprint!("Stack frame: {:x}\n", stack_frame as u64);
@@ -564,6 +686,7 @@ impl Process {
                 (*pushed_registers).r9 = self.registers.r9;
                 (*pushed_registers).r8 = self.registers.r8;
                 (*pushed_registers).rbp = self.registers.rbp;
+                (*pushed_registers).rdi = self.registers.rdi;
                 (*pushed_registers).rsi = self.registers.rsi;
                 (*pushed_registers).rdx = self.registers.rdx;
                 (*pushed_registers).rcx = self.registers.rcx;
@@ -579,48 +702,41 @@ impl Process {
 
             asm!(
                 "mov cr3, r15",
-                in("r15") self.cr3,
+                in("r15") self.address_space.get_cr3(),
                 options(nostack, preserves_flags),
                 clobber_abi("C")
             );
         }
 
         self.state = ProcessState::Active;
+        self.running_on_cpu = Some(per_cpu::current().cpu_index);
+        per_cpu::current().address_space = &mut self.address_space;
     }
 
     pub fn passivate(&mut self) {
         let _event = core::hint::black_box(crate::instrument!());
 
         DEBUG!("Passivating process");
-        unsafe extern "C" {
-            static pushed_registers: *const RegistersStruct;
-            static stack_frame: *const u64;
+        self.save_context();
+        self.running_on_cpu = None;
+        per_cpu::current().address_space = core::ptr::null_mut();
+
+        // a process that went to sleep during this syscall stays asleep
+        if let ProcessState::Active = self.state {
+            self.state = ProcessState::Passive;
         }
+    }
+
+    // copies the registers of the interrupted userspace code into the process
+    fn save_context(&mut self) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let pushed_registers = per_cpu::current().pushed_registers as *const RegistersStruct;
+        let stack_frame = per_cpu::current().stack_frame as *const u64;
 
         unsafe {
             //kprint!("Stack frame: {:x}\n", stack_frame as u64);
-            self.registers.xmm7 = (*pushed_registers).xmm7;
-            self.registers.xmm6 = (*pushed_registers).xmm6;
-            self.registers.xmm5 = (*pushed_registers).xmm5;
-            self.registers.xmm4 = (*pushed_registers).xmm4;
-            self.registers.xmm3 = (*pushed_registers).xmm3;
-            self.registers.xmm2 = (*pushed_registers).xmm2;
-            self.registers.xmm1 = (*pushed_registers).xmm1;
-            self.registers.xmm0 = (*pushed_registers).xmm0;
-            self.registers.r15 = (*pushed_registers).r15;
-            self.registers.r14 = (*pushed_registers).r14;
-            self.registers.r13 = (*pushed_registers).r13;
-            self.registers.r12 = (*pushed_registers).r12;
-            self.registers.r11 = (*pushed_registers).r11;
-            self.registers.r10 = (*pushed_registers).r10;
-            self.registers.r9 = (*pushed_registers).r9;
-            self.registers.r8 = (*pushed_registers).r8;
-            self.registers.rbp = (*pushed_registers).rbp;
-            self.registers.rsi = (*pushed_registers).rsi;
-            self.registers.rdx = (*pushed_registers).rdx;
-            self.registers.rcx = (*pushed_registers).rcx;
-            self.registers.rbx = (*pushed_registers).rbx;
-            self.registers.rax = (*pushed_registers).rax;
+            self.registers = (*pushed_registers).clone();
 
             self.rip = *(stack_frame.add(0)) as usize;
             self.cs = *(stack_frame.add(1));
@@ -628,249 +744,199 @@ impl Process {
             self.rsp = *(stack_frame.add(3));
             self.ss = *(stack_frame.add(4));
         }
-
-        self.state = ProcessState::Passive;
     }
 
     pub fn activatable(&self) -> bool {
         let _event = core::hint::black_box(crate::instrument!());
 
+        // the context of a process which was woken up while still running on another CPU has not been saved yet
         match self.state {
-            ProcessState::Passive => true,
+            ProcessState::Passive => self
+                .running_on_cpu
+                .is_none_or(|cpu| cpu == per_cpu::current().cpu_index),
             _ => false,
         }
     }
 
-    // According to AMD Volume 2, page 146
-
-    fn get_physical_address_for_virtual_address(vaddr: usize) -> usize {
-        let _event = core::hint::black_box(crate::instrument!());
+    pub fn is_running_on_other_cpu(&self) -> bool {
+        self.running_on_cpu
+            .is_some_and(|cpu| cpu != per_cpu::current().cpu_index)
+    }
 
-        let l4_page_map_table_offset = (vaddr >> L4_TABLE_SHIFT) & 0x1ff;
-        let l3_page_directory_pointer_offset = (vaddr >> L3_TABLE_SHIFT) & 0x1ff;
-        let l2_page_directory_offset = (vaddr >> L2_TABLE_SHIFT) & 0x1ff;
-        let l1_page_table_offset = (vaddr >> L1_TABLE_SHIFT) & 0x1ff;
-        let page_offset = vaddr & PAGE_OFFSET_MASK;
+    pub fn terminate_later(&mut self, wait_status: u64) {
+        self.pending_termination = Some(wait_status);
+    }
 
-        unsafe {
-            let mut cr3: u64;
-
-            asm!("mov {}, cr3", out(reg) cr3);
-
-            let l4_page_map_base_address = cr3 as usize | KERNEL_HIGHER_HALF_BASE;
-
-            let l3_page_directory_pointer_table_address =
-                *((l4_page_map_base_address + l4_page_map_table_offset * 8) as *const usize)
-                    & ENTRY_MASK
-                    | KERNEL_HIGHER_HALF_BASE;
-
-            let l2_page_directory_table_address = *((l3_page_directory_pointer_table_address
-                + l3_page_directory_pointer_offset * 8)
-                as *const usize)
-                & ENTRY_MASK
-                | KERNEL_HIGHER_HALF_BASE;
-
-            if PAGE_SIZE == HUGE_PAGE_SIZE {
-                let physical_page_address = *((l2_page_directory_table_address
-                    + l2_page_directory_offset * 8)
-                    as *const usize)
-                    & ENTRY_MASK;
-
-                return physical_page_address + page_offset;
-            } else {
-                let l1_page_table_address = *((l2_page_directory_table_address
-                    + l2_page_directory_offset * 8)
-                    as *const usize)
-                    & ENTRY_MASK
-                    | KERNEL_HIGHER_HALF_BASE;
-
-                let physical_page_address = *((l1_page_table_address + l1_page_table_offset * 8)
-                    as *const usize)
-                    & ENTRY_MASK;
-
-                return physical_page_address + page_offset;
-            }
-        }
+    pub fn get_pending_termination(&self) -> Option<u64> {
+        self.pending_termination
     }
 
     pub fn get_c3_page_map_l4_base_address(&self) -> usize {
         let _event = core::hint::black_box(crate::instrument!());
 
-        Process::get_physical_address_for_virtual_address(
-            &(self.l4_page_map_l4_table) as *const _ as usize,
-        )
+        self.address_space.get_cr3()
     }
 
     pub fn get_entry_ip(&self) -> usize {
         self.rip
     }
 
-    // TODO reduce code duplication with load_elf_from_bin
-    pub fn get_size_of_program(&mut self, program_slice: &[u8]) -> usize {
-        let _event = core::hint::black_box(crate::instrument!());
-
-        unsafe {
-            let file =
-                elf::ElfBytes::<AnyEndian>::minimal_parse(program_slice).expect("Open test1");
-
-            let elf_header = file.ehdr;
-
-            kprint!("Entry point is at: {:x}\n", elf_header.e_entry);
-
-            let program_headers = file
-                .segments()
-                .unwrap()
-                .iter()
-                .filter(|phdr| phdr.p_type == PT_LOAD);
-
-            let mut last_v_addr: usize = 0;
-            let mut last_p_memsz: usize = 0;
-
-            for phdr in program_headers {
-                kprint!(
-                    "Load segment is at: {:x}\nMem Size is: {:x}\n",
-                    phdr.p_vaddr,
-                    phdr.p_memsz
-                );
-
-                if last_v_addr < phdr.p_vaddr as usize {
-                    last_v_addr = phdr.p_vaddr as usize;
-                    last_p_memsz = phdr.p_memsz as usize;
-                }
-            }
-
-            return last_v_addr + last_p_memsz;
-        }
+    pub fn get_stack_pointer(&self) -> u64 {
+        self.rsp
     }
 
-    pub fn load_elf_from_bin(&mut self, program_slice: &[u8]) -> (usize, usize, usize) {
+    // Sets up the memory areas of the load segments, whose pages are read from the file on their first access
+    // Returns the page aligned end of the program
+    pub fn load_elf_from_bin(
+        &mut self,
+        file_handle: FileHandle,
+        program_headers: &[ProgramHeader],
+    ) -> usize {
         let _event = core::hint::black_box(crate::instrument!());
 
-        unsafe {
-            let file =
-                elf::ElfBytes::<AnyEndian>::minimal_parse(program_slice).expect("Open test1");
-
-            let elf_header = file.ehdr;
+        let mut program_end: usize = 0;
 
-            kprint!("Entry point is at: {:x}\n", elf_header.e_entry);
+        for phdr in program_headers.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
+            kprint!(
+                "Load segment is at: {:x}\nMem Size is: {:x}\n",
+                phdr.p_vaddr,
+                phdr.p_memsz
+            );
 
-            let program_headers = file
-                .segments()
-                .unwrap()
-                .iter()
-                .filter(|phdr| phdr.p_type == PT_LOAD);
+            // e.g. text is read-only and executable, rodata only readable and data writable
+            let mut protection = 0;
+            if phdr.p_flags & PF_R != 0 {
+                protection |= PROT_READ;
+            }
+            if phdr.p_flags & PF_W != 0 {
+                protection |= PROT_WRITE;
+            }
+            if phdr.p_flags & PF_X != 0 {
+                protection |= PROT_EXEC;
+            }
 
-            let mut last_v_addr: usize = 0;
-            let mut last_p_memsz: usize = 0;
+            let kind = match protection & PROT_WRITE {
+                0 => MemoryAreaKind::Code,
+                _ => MemoryAreaKind::Data,
+            };
+
+            let first_page = phdr.p_vaddr as usize & !PAGE_OFFSET_MASK;
+            let start = first_page.max(program_end);
+            let end = (phdr.p_vaddr + phdr.p_memsz).next_multiple_of(PAGE_SIZE as u64) as usize;
+
+            // a page shared with the previous segment allows the accesses of both
+            if let Some(shared) = self.address_space.memory_areas().find(first_page).copied() {
+                let shared_kind = match kind {
+                    MemoryAreaKind::Data => kind,
+                    _ => shared.kind,
+                };
+
+                let memory_areas = self.address_space.memory_areas_mut();
+                memory_areas.remove_range(first_page, first_page + PAGE_SIZE, shared.kind);
+                memory_areas.insert(MemoryArea::new(
+                    first_page,
+                    first_page + PAGE_SIZE,
+                    shared_kind,
+                    shared.protection | protection,
+                ));
+            }
 
-            for phdr in program_headers {
-                kprint!(
-                    "Load segment is at: {:x}\nMem Size is: {:x}\n",
-                    phdr.p_vaddr,
-                    phdr.p_memsz
-                );
+            if start < end {
+                self.address_space
+                    .memory_areas_mut()
+                    .insert(MemoryArea::new(start, end, kind, protection));
+            }
 
-                asm!(
-                    "mov rcx, {}
-                    mov rsi, {}
-                    mov rdi, {}
-                    rep movsb",
-                    in(reg) phdr.p_filesz,
-                    in(reg) program_slice.as_ptr() as usize + phdr.p_offset as usize,
-                    in(reg) phdr.p_vaddr,
-                    out("rcx") _,
-                    out("rsi") _,
-                    out("rdi") _
-                );
+            // the rest of the segment up to its memory size is bss and stays zero
+            self.address_space.add_file_segment(
+                phdr.p_vaddr as usize,
+                phdr.p_offset as usize,
+                phdr.p_filesz as usize,
+            );
 
-                if phdr.p_flags & 0x2 != 0 {
-                    // Writable segment --> BSS
-                    let bss_start = phdr.p_vaddr + phdr.p_filesz;
-                    let bss_size = phdr.p_memsz - phdr.p_filesz;
-
-                    // Zeroes the bss region
-                    asm!(
-                        "mov rcx, {}
-                        xor rsi, rsi
-                        mov rdi, {}
-                        rep movsb",
-                        in(reg) bss_size,
-                        in(reg) bss_start,
-                        out("rcx") _,
-                        out("rsi") _,
-                        out("rdi") _
-                    );
-                }
+            program_end = program_end.max(end);
+        }
 
-                if last_v_addr < phdr.p_vaddr as usize {
-                    last_v_addr = phdr.p_vaddr as usize;
-                    last_p_memsz = phdr.p_memsz as usize;
-                }
-            }
+        self.address_space.set_program_file(file_handle);
 
-            return (elf_header.e_entry as usize, last_v_addr, last_p_memsz);
-        }
+        program_end
     }
 
-    pub fn set_working_directory(&mut self, path: &'static str) -> u64 {
+    pub fn set_working_directory(&mut self, path: &str) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
+
+        let path = self.resolve_path(path);
+        if !filesystem::is_directory(&path) {
+            return u64::MAX;
+        }
+
         self.working_directory = path;
         return 0;
     }
 
-    pub fn get_working_directory(&self) -> &'static str {
+    pub fn get_working_directory(&self) -> &str {
         let _event = core::hint::black_box(crate::instrument!());
-        self.working_directory
+        &self.working_directory
+    }
+
+    // Turns a path relative to the working directory into an absolute one
+    pub fn resolve_path(&self, path: &str) -> String {
+        filesystem::resolve_path(&self.working_directory, path)
     }
 
     pub fn fopen(&mut self, path: &str, mode: &str) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
 
-        let mode_num = match mode {
-            "r" => 0,
-            "w" => 1,
-            "rw" => 2,
-            _ => 0, // default to read-only
+        // the binary flag makes no difference
+        let flags = match mode.replace('b', "").as_str() {
+            "r" => O_RDONLY,
+            "r+" | "rw" => O_RDWR,
+            "w" => O_WRONLY | O_CREAT | O_TRUNC,
+            "w+" => O_RDWR | O_CREAT | O_TRUNC,
+            "a" => O_WRONLY | O_CREAT | O_APPEND,
+            "a+" => O_RDWR | O_CREAT | O_APPEND,
+            _ => O_RDONLY, // default to read-only
         };
-        match FileHandle::new(path, mode_num) {
+
+        self.open(path, flags)
+    }
+
+    // Returns the new file descriptor or u64::MAX on error
+    pub fn open(&mut self, path: &str, flags: u32) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let path = self.resolve_path(path);
+
+        match FileHandle::new(&path, flags) {
             Some(file_handle) => {
                 kprint!("File opened: {}\n", path);
-                let handle_id = self.next_handle_id;
-                self.next_handle_id += 1;
-                self.file_handles.insert(handle_id, file_handle);
-                kprint!("File handle id: {}\n", handle_id);
-                return handle_id;
+                let fd = self.file_descriptors.install(OpenFile::File(file_handle));
+                kprint!("File descriptor: {}\n", fd);
+                return fd;
             }
             None => {
                 kprint!("Error opening file: {}\n", path);
-                return 0;
+                return u64::MAX;
             }
         }
     }
 
-    pub fn fread(&mut self, handle_id: u64, buffer: *mut u8, size: usize) -> u64 {
+    pub fn fseek(&mut self, fd: u64, offset: usize, whence: u32) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
 
-        if let Some(file_handle) = self.file_handles.get_mut(&handle_id) {
-            let bytes_read = file_handle.read(buffer, size);
-            file_handle.offset += bytes_read as usize;
-            return bytes_read;
+        if let Some(open_file) = self.file_descriptors.get(fd) {
+            if let OpenFile::File(file_handle) = &mut *open_file.lock() {
+                file_handle.fseek(offset, whence);
+            }
+            return 0;
         } else {
-            ERROR!("Invalid file handle id: {}\n", handle_id);
+            ERROR!("Invalid file descriptor: {}\n", fd);
             return 0;
         }
     }
 
-    pub fn fseek(&mut self, handle_id: u64, offset: usize, whence: u32) -> u64 {
-        let _event = core::hint::black_box(crate::instrument!());
-
-        if let Some(file_handle) = self.file_handles.get_mut(&handle_id) {
-            file_handle.fseek(offset, whence);
-            return 0;
-        } else {
-            ERROR!("Invalid file handle id: {}\n", handle_id);
-            return 0;
-        }
+    pub fn get_file_descriptors(&mut self) -> &mut FileDescriptorTable {
+        &mut self.file_descriptors
     }
 
     pub fn get_parent_id(&self) -> u64 {
@@ -878,16 +944,109 @@ impl Process {
         self.parent_id
     }
 
+    pub fn set_parent_id(&mut self, parent_id: u64) {
+        let _event = core::hint::black_box(crate::instrument!());
+        self.parent_id = parent_id;
+    }
+
     pub fn get_pid(&self) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
         self.process_id
     }
 
-    pub fn clone_from_parent(&mut self, parent: &Process) {
+    // Duplicates this process; all writable user pages are shared copy-on-write afterwards
+    // Must be called from within a syscall of this process, as the child continues from its saved registers
+    pub fn fork(&mut self) -> Box<Process> {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let mut child = Box::new(Process::new());
+
+        child.address_space = self.address_space.fork();
+
+        // the child returns from the same syscall, but with 0 as result
+        child.save_context();
+        child.registers.rax = 0;
+
+        child.program_break_start = self.program_break_start;
+        child.program_break = self.program_break;
+        child.stack_limit = self.stack_limit;
+
+        child.working_directory = self.working_directory.clone();
+        // parent and child share the open files
+        child.file_descriptors = self.file_descriptors.clone();
+
+        child.parent_id = self.process_id;
+        child.state = ProcessState::Passive;
+
+        child
+    }
+
+    pub fn wake_up(&mut self) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        if let ProcessState::Sleeping = self.state {
+            DEBUG!("Waking up process");
+            self.state = ProcessState::Passive;
+        }
+    }
+
+    pub fn block_on(&mut self, waiter: Waiter) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        DEBUG!("Blocking process");
+        self.blocked_on = Some(waiter);
+        self.state = ProcessState::Blocked;
+    }
+
+    // lets a blocked process run again if its wait queue has been woken up in the meantime
+    pub fn unblock_if_woken_up(&mut self) {
+        if let Some(waiter) = &self.blocked_on {
+            if waiter.is_woken_up() {
+                DEBUG!("Unblocking process");
+                self.blocked_on = None;
+                self.state = ProcessState::Passive;
+            }
+        }
+    }
+
+    pub fn get_key_event_position(&mut self) -> &mut u64 {
+        self.key_event_position
+            .get_or_insert_with(keyboard::next_key_event_position)
+    }
+
+    // The deadline of an interrupted sleep, which continues when its syscall is executed again
+    pub fn get_sleep_deadline(&self) -> Option<u64> {
+        self.sleep_timer
+            .as_ref()
+            .map(|(deadline_ns, _)| *deadline_ns)
+    }
+
+    // Blocks the process until the time since boot reached the deadline, returns true once it has
+    pub fn sleep_until(&mut self, deadline_ns: u64) -> bool {
         let _event = core::hint::black_box(crate::instrument!());
 
-        self.working_directory = parent.working_directory;
-        self.parent_id = parent.process_id;
+        let (deadline_ns, wait_queue) = self.sleep_timer.get_or_insert_with(|| {
+            let wait_queue = Arc::new(WaitQueue::new());
+            timer::add_timer(deadline_ns, wait_queue.clone());
+            (deadline_ns, wait_queue)
+        });
+
+        let waiter = wait_queue.prepare_to_wait();
+        if time::get_ns_since_boot() >= *deadline_ns {
+            self.sleep_timer = None;
+            return true;
+        }
+
+        waiter.wait(self);
+        false
+    }
+
+    pub fn is_sleeping(&self) -> bool {
+        matches!(self.state, ProcessState::Sleeping)
+    }
+
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, ProcessState::Active)
     }
 
     pub fn put_to_sleep(&mut self) {
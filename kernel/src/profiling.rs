@@ -1,4 +1,5 @@
 use crate::DEBUG;
+use crate::block_cache::{BLOCK_CACHE_HITS, BLOCK_CACHE_MISSES};
 use crate::get_ns_since_boot;
 extern crate alloc;
 use core::arch::x86_64::_rdtsc;
@@ -78,6 +79,16 @@ pub fn log_tracepoints() {
         }
     }
     DEBUG!("Tracepoints logged");
+
+    log_counters();
+}
+
+pub fn log_counters() {
+    DEBUG!(
+        "Block cache: {} hits, {} misses",
+        BLOCK_CACHE_HITS.load(Ordering::Relaxed),
+        BLOCK_CACHE_MISSES.load(Ordering::Relaxed)
+    );
 }
 
 // Approach adopted from https://github.com/Compaile/ctrack
@@ -89,6 +100,14 @@ pub struct EventHandler {
 impl EventHandler {
     #[inline(always)]
     pub fn new(function: &'static str) -> Self {
+        // tracing is only enabled with trace=on on the kernel command line
+        if !crate::config::get().trace {
+            return EventHandler {
+                function,
+                call_id: 0,
+            };
+        }
+
         let call_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
         let start_clock = unsafe { _rdtsc() };
         let start_time = get_ns_since_boot!();
@@ -111,6 +130,10 @@ impl EventHandler {
 impl Drop for EventHandler {
     #[inline(always)]
     fn drop(&mut self) {
+        if self.call_id == 0 {
+            return;
+        }
+
         let end_time = get_ns_since_boot!();
         let end_clock = unsafe { _rdtsc() };
 
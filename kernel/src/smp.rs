@@ -0,0 +1,168 @@
+use crate::per_cpu::{self, MAX_CPUS};
+use crate::process::KERNEL_CR3;
+use crate::userland::get_idle_stack_top;
+use crate::{DEBUG, ERROR, apic, config, gdt, interrupt, mem, time};
+use core::arch::{asm, global_asm};
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// https://wiki.osdev.org/SMP
+// https://wiki.osdev.org/Symmetric_Multiprocessing
+
+// physical address of the startup code, must be page aligned and below 1 MiB
+const AP_TRAMPOLINE_ADDRESS: usize = 0x8000;
+
+const INIT_DELAY_US: u64 = 10_000;
+const STARTUP_DELAY_US: u64 = 200;
+const STARTUP_TIMEOUT_US: u64 = 100_000;
+
+global_asm!(
+    include_str!("ap_trampoline.S"),
+    AP_TRAMPOLINE_ADDRESS = const AP_TRAMPOLINE_ADDRESS,
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u64;
+    static ap_trampoline_stack_top: u64;
+    static ap_trampoline_cpu_index: u64;
+    static ap_trampoline_entry: u64;
+}
+
+// number of CPUs which finished their initialization, including the bootstrap processor
+static RUNNING_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn get_running_cpus() -> usize {
+    RUNNING_CPUS.load(Ordering::Acquire)
+}
+
+// Starts all processors listed in the MADT one after the other; they enter the idle loop and pick up processes with
+// the timer interrupt of their local APIC
+pub fn start_application_processors() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    if !config::get().smp || !apic::is_local_apic_enabled() {
+        return;
+    }
+
+    let Some(madt) = apic::parse_madt() else {
+        return;
+    };
+
+    apic::calibrate_timer();
+    copy_trampoline();
+
+    let bootstrap_apic_id = apic::local_apic_id();
+    let cr3 = match KERNEL_CR3.load(Ordering::Relaxed) {
+        0 => mem::get_cr3(),
+        cr3 => cr3,
+    };
+
+    for apic_id in madt
+        .processor_apic_ids
+        .into_iter()
+        .filter(|&apic_id| apic_id != bootstrap_apic_id)
+    {
+        let cpu_index = get_running_cpus();
+        if cpu_index >= MAX_CPUS {
+            ERROR!(
+                "Ignoring processor with APIC id {}, at most {} CPUs are supported",
+                apic_id,
+                MAX_CPUS
+            );
+            break;
+        }
+
+        write_trampoline_parameter(addr_of!(ap_trampoline_cr3), cr3 as u64);
+        write_trampoline_parameter(
+            addr_of!(ap_trampoline_stack_top),
+            get_idle_stack_top(cpu_index),
+        );
+        write_trampoline_parameter(addr_of!(ap_trampoline_cpu_index), cpu_index as u64);
+        write_trampoline_parameter(
+            addr_of!(ap_trampoline_entry),
+            ap_main as extern "C" fn(usize) -> ! as usize as u64,
+        );
+
+        if !start_processor(apic_id, cpu_index) {
+            ERROR!("Processor with APIC id {} did not start", apic_id);
+        }
+    }
+
+    DEBUG!("{} CPUs running", get_running_cpus());
+}
+
+// Sends INIT and up to two STARTUP interrupts, returns true once the processor signalled that it is running
+fn start_processor(apic_id: u8, cpu_index: usize) -> bool {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    apic::send_init(apic_id);
+    time::busy_wait_us(INIT_DELAY_US);
+
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (AP_TRAMPOLINE_ADDRESS >> 12) as u8);
+        time::busy_wait_us(STARTUP_DELAY_US);
+
+        if get_running_cpus() > cpu_index {
+            return true;
+        }
+    }
+
+    let start = time::get_us_since_boot();
+    while time::get_us_since_boot() - start < STARTUP_TIMEOUT_US {
+        if get_running_cpus() > cpu_index {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+
+    false
+}
+
+fn copy_trampoline() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let length = addr_of!(ap_trampoline_end) as usize - start as usize;
+
+        core::ptr::copy_nonoverlapping(
+            start,
+            mem::physical_to_virtual(AP_TRAMPOLINE_ADDRESS) as *mut u8,
+            length,
+        );
+    }
+}
+
+// Writes to the copy of the given parameter of the trampoline
+fn write_trampoline_parameter(parameter: *const u64, value: u64) {
+    let offset = parameter as usize - addr_of!(ap_trampoline_start) as usize;
+
+    unsafe {
+        core::ptr::write_volatile(
+            mem::physical_to_virtual(AP_TRAMPOLINE_ADDRESS + offset) as *mut u64,
+            value,
+        );
+    }
+}
+
+// First Rust code of the application processors, running on their idle stack
+extern "C" fn ap_main(cpu_index: usize) -> ! {
+    gdt::init_gdt_for_cpu(cpu_index);
+    interrupt::load_idt();
+    apic::init_local_apic();
+    apic::start_timer();
+
+    DEBUG!(
+        "CPU {} running with APIC id {}",
+        cpu_index,
+        per_cpu::current().apic_id
+    );
+
+    per_cpu::current().scheduling_blocked = 0;
+    RUNNING_CPUS.fetch_add(1, Ordering::Release);
+
+    // see switch_to_ring3.S
+    unsafe { asm!("jmp idle_loop", options(noreturn)) }
+}
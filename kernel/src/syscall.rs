@@ -1,17 +1,53 @@
 use crate::ERROR;
+use crate::file_descriptor::{IoResult, OpenFile};
+use crate::filesystem;
 use crate::filesystem::FileHandle;
 use crate::filesystem::Stat;
-use crate::interrupt;
+use crate::keyboard::KeyEventRecord;
 use crate::kprint;
+use crate::pipe;
+use crate::process;
+use crate::process::ResourceLimit;
+use crate::time::{CLOCK_MONOTONIC, TIMER_ABSTIME, Timespec, Timeval};
+use crate::user_memory::{self, read_user, read_user_string, write_user};
 use crate::{USERLAND, time};
 use crate::{keyboard, vga};
 use core::arch::asm;
 
 extern crate alloc;
+use alloc::string::String;
 use alloc::vec::Vec;
 
+// commands of fcntl, see fcntl.h in libc
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+
+const SIGPIPE: u32 = 13;
+
+// flags of mmap, see sys/mman.h in libc
+const MAP_SHARED: u32 = 0x01;
+const MAP_PRIVATE: u32 = 0x02;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+// mmap takes more arguments than fit into the syscall registers, so they are passed in memory
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MmapArguments {
+    address: u64,
+    length: u64,
+    protection: u32,
+    flags: u32,
+    fd: i32,
+    offset: i64,
+}
+
+// called from syscall_common_stub with the registers of the calling process saved on the kernel stack
 #[unsafe(no_mangle)]
-pub extern "C" fn system_call() -> u64 {
+pub extern "C" fn system_call() {
     let mut syscall_nr: i64;
     let mut arg0: u64;
     let mut arg1: u64;
@@ -32,19 +68,27 @@ pub extern "C" fn system_call() -> u64 {
         );
     }
 
+    let result = dispatch_system_call(syscall_nr, arg0, arg1, arg2);
+
+    process::set_syscall_result(result);
+
+    // e.g. a vfork parent sleeps now, so continue with another process
+    USERLAND.lock().switch_process_if_inactive();
+}
+
+fn dispatch_system_call(syscall_nr: i64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
     match syscall_nr {
         1 => return syscall_write(arg0, arg1, arg2),
         2 => return syscall_getpid(),
         3 => return syscall_plot_pixel(arg0 as u32, arg1 as u32, arg2 as u32),
-        4 => return syscall_malloc(arg0 as usize),
-        5 => return syscall_fopen(arg0 as *const u64, arg1 as *mut u32),
+        5 => return syscall_fopen(arg0 as *const u64, arg1 as *const u64),
         6 => return syscall_fread(arg0, arg1, arg2 as usize),
         7 => return syscall_fseek(arg0, arg1 as usize, arg2 as usize),
         8 => return syscall_ftell(arg0),
         9 => return syscall_feof(arg0),
         10 => return syscall_plot_framebuffer(arg0),
         11 => return syscall_switch_vga_mode(arg0),
-        12 => return syscall_get_keystate(arg0 as usize),
+        12 => return syscall_read_key_events(arg0 as *mut KeyEventRecord, arg1 as usize),
         13 => return syscall_get_time(arg0 as *mut u32, arg1 as *mut u32),
         14 => return syscall_stat(arg0 as *const u64, arg1 as *mut u64),
         15 => return syscall_chdir(arg0 as *const u64),
@@ -52,7 +96,6 @@ pub extern "C" fn system_call() -> u64 {
         17 => return syscall_getppid(),
         18 => return syscall_kill(arg0 as u64, arg1 as u32),
         19 => return syscall_read(arg0, arg1, arg2),
-        20 => return syscall_realloc(arg0, arg1 as usize),
         21 => return syscall_vfork(),
         22 => {
             return syscall_execve(
@@ -61,6 +104,28 @@ pub extern "C" fn system_call() -> u64 {
                 arg2 as *const *const u64,
             );
         }
+        23 => return syscall_fork(),
+        24 => return syscall_wait4(arg0 as i64, arg1 as *mut u32, arg2),
+        25 => return syscall_dup(arg0),
+        26 => return syscall_dup2(arg0, arg1),
+        27 => return syscall_close(arg0),
+        28 => return syscall_fcntl(arg0, arg1, arg2),
+        29 => return syscall_pipe(arg0 as *mut i32),
+        30 => return syscall_open(arg0 as *const u64, arg1),
+        31 => return syscall_unlink(arg0 as *const u64),
+        32 => return syscall_ftruncate(arg0, arg1),
+        33 => return syscall_getdents64(arg0, arg1 as *mut u8, arg2 as usize),
+        34 => return syscall_nanosleep(arg0 as *const Timespec),
+        35 => return syscall_clock_nanosleep(arg0, arg1, arg2 as *const Timespec),
+        36 => return syscall_clock_gettime(arg0, arg1 as *mut Timespec),
+        37 => return syscall_gettimeofday(arg0 as *mut Timeval),
+        38 => return syscall_brk(arg0 as usize),
+        39 => return syscall_mmap(arg0 as *const MmapArguments),
+        40 => return syscall_munmap(arg0 as usize, arg1 as usize),
+        41 => return syscall_mprotect(arg0 as usize, arg1 as usize, arg2 as u32),
+        42 => return syscall_getrlimit(arg0 as u32, arg1 as *mut ResourceLimit),
+        43 => return syscall_setrlimit(arg0 as u32, arg1 as *const ResourceLimit),
+        60 => return syscall_exit(arg0),
         _ => {
             ERROR!("Undefined system call triggered: {}", syscall_nr);
             return 0xdeadbeef;
@@ -86,46 +151,158 @@ fn syscall_fseek(handle: u64, offset: usize, origin: usize) -> u64 {
         .fseek(handle, offset, origin as u32);
 }
 
+// Reads like read, but reports errors as 0 bytes read
 fn syscall_fread(handle: u64, ptr: u64, num_bytes: usize) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
-    USERLAND
+
+    match syscall_read(handle, ptr, num_bytes as u64) {
+        u64::MAX => 0,
+        bytes_read => bytes_read,
+    }
+}
+
+fn syscall_fopen(filename: *const u64, mode: *const u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    match (
+        read_user_string(filename as usize),
+        read_user_string(mode as usize),
+    ) {
+        (Some(path), Some(mode)) => USERLAND.lock().get_current_process().fopen(&path, &mode),
+        _ => u64::MAX,
+    }
+}
+
+fn syscall_open(pathname: *const u64, flags: u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    match read_user_string(pathname as usize) {
+        Some(path) => USERLAND
+            .lock()
+            .get_current_process()
+            .open(&path, flags as u32),
+        None => u64::MAX,
+    }
+}
+
+fn syscall_unlink(pathname: *const u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    match read_user_string(pathname as usize) {
+        Some(path) => {
+            let path = USERLAND.lock().get_current_process().resolve_path(&path);
+            filesystem::unlink(&path)
+        }
+        None => u64::MAX,
+    }
+}
+
+fn syscall_ftruncate(filedescriptor: u64, length: u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let open_file = match USERLAND
         .lock()
         .get_current_process()
-        .fread(handle, ptr as *mut u8, num_bytes)
+        .get_file_descriptors()
+        .get(filedescriptor)
+    {
+        Some(open_file) => open_file,
+        None => return u64::MAX,
+    };
+
+    match &mut *open_file.lock() {
+        OpenFile::File(file_handle) => file_handle.truncate(length as usize),
+        _ => u64::MAX,
+    }
 }
 
-fn syscall_fopen(filename: *const u64, mode: *mut u32) -> u64 {
+fn syscall_getdents64(filedescriptor: u64, dirp: *mut u8, count: usize) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
 
-    if filename.is_null() {
-        return 0;
+    if !user_memory::prepare_user_buffer(dirp as usize, count, true) {
+        return u64::MAX;
     }
 
-    match unsafe { core::str::from_utf8(core::slice::from_raw_parts(filename as *const u8, 256)) } {
-        Ok(path_str) => match path_str.split('\0').next() {
-            Some(path_str) => match unsafe {
-                core::str::from_utf8(core::slice::from_raw_parts(mode as *const u8, 8))
-            } {
-                Ok(mode_str) => USERLAND
-                    .lock()
-                    .get_current_process()
-                    .fopen(path_str, mode_str),
-                Err(_) => 0,
-            },
-            None => return 0,
-        },
-        Err(_) => 0,
+    let open_file = match USERLAND
+        .lock()
+        .get_current_process()
+        .get_file_descriptors()
+        .get(filedescriptor)
+    {
+        Some(open_file) => open_file,
+        None => return u64::MAX,
+    };
+
+    match &mut *open_file.lock() {
+        OpenFile::File(file_handle) => file_handle.read_directory_entries(dirp, count),
+        _ => u64::MAX,
     }
 }
 
-fn syscall_malloc(size: usize) -> u64 {
+fn syscall_brk(address: usize) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
-    return USERLAND.lock().process_malloc(size);
+    USERLAND.lock().get_current_process().brk(address)
 }
 
-fn syscall_realloc(ptr: u64, size: usize) -> u64 {
+fn syscall_mmap(arguments: *const MmapArguments) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
-    return USERLAND.lock().process_realloc(ptr, size);
+
+    let Some(arguments) = read_user::<MmapArguments>(arguments as usize) else {
+        return u64::MAX;
+    };
+
+    // only private anonymous mappings are supported
+    if arguments.flags & (MAP_ANONYMOUS | MAP_PRIVATE) != (MAP_ANONYMOUS | MAP_PRIVATE)
+        || arguments.flags & MAP_SHARED != 0
+    {
+        ERROR!("mmap: unsupported flags {:#x}", arguments.flags);
+        return u64::MAX;
+    }
+
+    USERLAND.lock().get_current_process().mmap(
+        arguments.address as usize,
+        arguments.length as usize,
+        arguments.protection,
+        arguments.flags & MAP_FIXED != 0,
+    )
+}
+
+fn syscall_munmap(address: usize, length: usize) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+    USERLAND
+        .lock()
+        .get_current_process()
+        .munmap(address, length)
+}
+
+fn syscall_mprotect(address: usize, length: usize, protection: u32) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+    USERLAND
+        .lock()
+        .get_current_process()
+        .mprotect(address, length, protection)
+}
+
+fn syscall_getrlimit(resource: u32, limit: *mut ResourceLimit) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    match USERLAND.lock().get_current_process().getrlimit(resource) {
+        Some(resource_limit) if write_user(limit as usize, &resource_limit) => 0,
+        _ => u64::MAX,
+    }
+}
+
+fn syscall_setrlimit(resource: u32, limit: *const ResourceLimit) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let Some(resource_limit) = read_user::<ResourceLimit>(limit as usize) else {
+        return u64::MAX;
+    };
+
+    USERLAND
+        .lock()
+        .get_current_process()
+        .setrlimit(resource, resource_limit)
 }
 
 fn syscall_plot_pixel(x: u32, y: u32, color: u32) -> u64 {
@@ -147,29 +324,35 @@ fn syscall_write(filedescriptor: u64, payload: u64, len: u64) -> u64 {
         return 0;
     }
 
-    unsafe {
-        match core::str::from_utf8(core::slice::from_raw_parts(
-            payload as *const u8,
-            len as usize,
-        )) {
-            Ok(msg) => match filedescriptor {
-                // stdout
-                1 | 2 => {
-                    return kprint!("{}", msg);
-                }
-                _ => {
-                    core::hint::black_box(()); // dummy instruction to place breakpoint on
-                    ERROR!("Undefined filedescriptor!");
-                }
-            },
-            Err(_) => ERROR!("\nCouldnt reconstruct string!\n"),
-        }
+    if !user_memory::prepare_user_buffer(payload as usize, len as usize, false) {
+        return u64::MAX;
     }
-    return 0;
+
+    let open_file = match USERLAND
+        .lock()
+        .get_current_process()
+        .get_file_descriptors()
+        .get(filedescriptor)
+    {
+        Some(open_file) => open_file,
+        None => {
+            ERROR!("Undefined filedescriptor!");
+            return u64::MAX;
+        }
+    };
+
+    let result = open_file.lock().write(payload as *const u8, len as usize);
+    finish_io(result)
 }
 
 fn syscall_plot_framebuffer(framebuffer: u64) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
+
+    let size = (vga::VGA_SCREEN_WIDTH * vga::VGA_SCREEN_HEIGHT) as usize;
+    if !user_memory::prepare_user_buffer(framebuffer as usize, size, false) {
+        return u64::MAX;
+    }
+
     vga::vga_plot_framebuffer(framebuffer as *const u8);
     vga::vga_flip();
     return 0;
@@ -186,84 +369,164 @@ fn syscall_switch_vga_mode(vga_on: u64) -> u64 {
     return 0;
 }
 
-fn syscall_get_keystate(key: usize) -> u64 {
+// Returns the key events since the previous call without blocking, the first call only starts recording them
+fn syscall_read_key_events(events: *mut KeyEventRecord, count: usize) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
-    let keystate;
-    unsafe {
-        keystate = keyboard::KEYSTATES[key];
-        keyboard::KEYSTATES[key] = false;
+
+    // an overflowing size is saturated, which fails the range check
+    let size = size_of::<KeyEventRecord>().saturating_mul(count);
+    if events.is_null() || !user_memory::prepare_user_buffer(events as usize, size, true) {
+        return u64::MAX;
     }
-    return keystate as u64;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(events, count) };
+
+    let mut userland = USERLAND.lock();
+    let position = userland.get_current_process().get_key_event_position();
+
+    keyboard::read_key_events(position, buffer) as u64
 }
 
 fn syscall_get_time(sec: *mut u32, usec: *mut u32) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
-    unsafe {
-        let (s, us) = time::get_time();
-        core::ptr::write_unaligned(sec, s);
-        core::ptr::write_unaligned(usec, us);
+    let (s, us) = time::get_time();
+    if !write_user(sec as usize, &s) || !write_user(usec as usize, &us) {
+        return u64::MAX;
     }
     return 1;
 }
 
-fn syscall_stat(path: *const u64, statbuf: *mut u64) -> u64 {
+fn syscall_clock_gettime(clock_id: u64, timespec: *mut Timespec) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
 
-    if path.is_null() {
-        return 0;
+    let Some(clock_ns) = time::get_clock_ns(clock_id) else {
+        ERROR!("Unsupported clock {} for clock_gettime", clock_id);
+        return u64::MAX;
+    };
+
+    if !write_user(timespec as usize, &Timespec::from_ns(clock_ns)) {
+        return u64::MAX;
     }
+    0
+}
 
-    match unsafe { core::str::from_utf8(core::slice::from_raw_parts(path as *const u8, 256)) } {
-        Ok(path_str) => match path_str.split('\0').next() {
-            Some(path_str) => match FileHandle::new(path_str, 0) {
-                Some(file_handle) => {
-                    kprint!("File opened: {}\n", path_str);
-
-                    let stat = file_handle.stat();
-                    unsafe {
-                        core::ptr::write_unaligned(statbuf as *mut Stat, stat);
-                    }
-                    return 0;
-                }
-                None => {
-                    kprint!("Error opening file: {}\n", path_str);
-                    return u64::MAX;
-                }
-            },
-            None => return u64::MAX,
-        },
-        Err(_) => return u64::MAX,
+// The timezone argument is obsolete, the RTC runs in UTC
+fn syscall_gettimeofday(timeval: *mut Timeval) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let (tv_sec, tv_usec) = time::get_time();
+    let value = Timeval {
+        tv_sec: tv_sec as i64,
+        tv_usec: tv_usec as i64,
+    };
+
+    if !write_user(timeval as usize, &value) {
+        return u64::MAX;
     }
+    0
 }
 
-fn syscall_chdir(pathname: *const u64) -> u64 {
+// The remaining time is not reported, since a sleep cannot be interrupted by a signal
+fn syscall_nanosleep(request: *const Timespec) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    syscall_clock_nanosleep(CLOCK_MONOTONIC, 0, request)
+}
+
+fn syscall_clock_nanosleep(clock_id: u64, flags: u64, request: *const Timespec) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    // the time would never advance
+    if !time::is_hpet_available() {
+        ERROR!("Sleeping requires the HPET");
+        return u64::MAX;
+    }
+
+    let mut userland = USERLAND.lock();
+    let process = userland.get_current_process();
+
+    // the syscall is executed again after every wake up, but the deadline stays the same
+    let deadline_ns = match process.get_sleep_deadline() {
+        Some(deadline_ns) => deadline_ns,
+        None => {
+            let Some(duration_ns) =
+                read_user::<Timespec>(request as usize).and_then(|request| request.as_ns())
+            else {
+                return u64::MAX;
+            };
+
+            let Some(clock_ns) = time::get_clock_ns(clock_id) else {
+                ERROR!("Unsupported clock {} for clock_nanosleep", clock_id);
+                return u64::MAX;
+            };
+
+            // the timers use the time since boot
+            if flags & TIMER_ABSTIME != 0 {
+                time::get_ns_since_boot().saturating_add(duration_ns.saturating_sub(clock_ns))
+            } else {
+                time::get_ns_since_boot().saturating_add(duration_ns)
+            }
+        }
+    };
+
+    process.sleep_until(deadline_ns);
+    0
+}
+
+fn syscall_stat(path: *const u64, statbuf: *mut u64) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
-    // get string from pathname pointer
-    match unsafe { core::str::from_utf8(core::slice::from_raw_parts(pathname as *const u8, 256)) } {
-        Ok(pathname) => {
-            return USERLAND
-                .lock()
-                .get_current_process()
-                .set_working_directory(pathname);
+
+    let path = match read_user_string(path as usize) {
+        Some(path) => USERLAND.lock().get_current_process().resolve_path(&path),
+        None => return u64::MAX,
+    };
+
+    match FileHandle::new(&path, 0) {
+        Some(file_handle) => {
+            kprint!("File opened: {}\n", path);
+
+            let stat: Stat = file_handle.stat();
+            if !write_user(statbuf as usize, &stat) {
+                return u64::MAX;
+            }
+            return 0;
+        }
+        None => {
+            kprint!("Error opening file: {}\n", path);
+            return u64::MAX;
         }
-        Err(_) => return u64::MAX,
     }
 }
 
+fn syscall_chdir(pathname: *const u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    match read_user_string(pathname as usize) {
+        Some(path) => USERLAND
+            .lock()
+            .get_current_process()
+            .set_working_directory(&path),
+        None => u64::MAX,
+    }
+}
+
+// Returns the length of the working directory or 0 if it does not fit into buf
 fn syscall_getcwd(buf: *mut u64, size: u64) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
-    let cwd = USERLAND
-        .lock()
-        .get_current_process()
-        .get_working_directory();
-    // copy cwd to buf
-    let cwd_bytes = cwd.as_bytes();
-    let cwd_len = cwd_bytes.len();
-    let buf_slice = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, size as usize) };
-    for i in 0..core::cmp::min(cwd_len, size as usize) {
-        buf_slice[i] = cwd_bytes[i];
+
+    let mut userland = USERLAND.lock();
+    let cwd = userland.get_current_process().get_working_directory();
+
+    // copy cwd including the terminating null byte to buf, an invalid buf is reported as 0 like a too small one
+    let mut cwd_bytes = Vec::from(cwd.as_bytes());
+    cwd_bytes.push(0);
+    if buf.is_null()
+        || cwd_bytes.len() > size as usize
+        || !user_memory::copy_to_user(buf as usize, &cwd_bytes)
+    {
+        return 0;
     }
-    return cwd_len as u64;
+
+    return (cwd_bytes.len() - 1) as u64;
 }
 
 fn syscall_getppid() -> u64 {
@@ -278,32 +541,115 @@ fn syscall_kill(_pid: u64, _sig: u32) -> u64 {
 fn syscall_read(filedescriptor: u64, buffer: u64, len: u64) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
 
-    if filedescriptor == 0 {
-        // stdin
-        interrupt::STDIN_BUFFER_POS.store(0, core::sync::atomic::Ordering::Relaxed);
+    if !user_memory::prepare_user_buffer(buffer as usize, len as usize, true) {
+        return u64::MAX;
+    }
 
-        // wait for input or until len bytes have been read
-        while interrupt::STDIN_BUFFER_POS.load(core::sync::atomic::Ordering::Relaxed) == 0
-            || unsafe {
-                interrupt::STDIN_BUFFER
-                    [interrupt::STDIN_BUFFER_POS.load(core::sync::atomic::Ordering::Relaxed) - 1]
-                    != '\n'
-            }
-            || len < interrupt::STDIN_BUFFER_POS.load(core::sync::atomic::Ordering::Relaxed) as u64
-        {
-            // wait for input
-            unsafe { asm!("hlt") };
-        }
+    // USERLAND is not locked while reading, the process might e.g. need to extend its stack meanwhile
+    let open_file = match USERLAND
+        .lock()
+        .get_current_process()
+        .get_file_descriptors()
+        .get(filedescriptor)
+    {
+        Some(open_file) => open_file,
+        None => return u64::MAX,
+    };
+
+    let result = open_file.lock().read(buffer as *mut u8, len as usize);
+    finish_io(result)
+}
 
-        for i in 0..interrupt::STDIN_BUFFER_POS.load(core::sync::atomic::Ordering::Relaxed) {
-            unsafe {
-                *(buffer as *mut u8).add(i) = interrupt::STDIN_BUFFER[i] as u8;
-            }
+// blocks the current process if the read or write has to wait
+fn finish_io(result: IoResult) -> u64 {
+    match result {
+        IoResult::Done(bytes) => bytes,
+        IoResult::Wait(waiter) => {
+            waiter.wait(USERLAND.lock().get_current_process());
+            0
+        }
+        IoResult::BrokenPipe => {
+            // there are no signal handlers, so SIGPIPE always terminates the process
+            USERLAND.lock().kill_current_process(SIGPIPE);
+            u64::MAX
         }
+    }
+}
 
-        return interrupt::STDIN_BUFFER_POS.load(core::sync::atomic::Ordering::Relaxed) as u64;
-    } else {
-        todo!();
+fn syscall_pipe(filedescriptors: *mut i32) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let (read_end, write_end) = pipe::create_pipe();
+
+    let mut userland = USERLAND.lock();
+    let file_descriptors = userland.get_current_process().get_file_descriptors();
+
+    let read_fd = file_descriptors.install(OpenFile::Pipe(read_end));
+    if read_fd == u64::MAX {
+        return u64::MAX;
+    }
+
+    let write_fd = file_descriptors.install(OpenFile::Pipe(write_end));
+    if write_fd == u64::MAX {
+        file_descriptors.close(read_fd);
+        return u64::MAX;
+    }
+
+    if !write_user(filedescriptors as usize, &[read_fd as i32, write_fd as i32]) {
+        file_descriptors.close(read_fd);
+        file_descriptors.close(write_fd);
+        return u64::MAX;
+    }
+
+    return 0;
+}
+
+fn syscall_dup(filedescriptor: u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+    USERLAND
+        .lock()
+        .get_current_process()
+        .get_file_descriptors()
+        .dup(filedescriptor, 0)
+}
+
+fn syscall_dup2(old_filedescriptor: u64, new_filedescriptor: u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+    USERLAND
+        .lock()
+        .get_current_process()
+        .get_file_descriptors()
+        .dup2(old_filedescriptor, new_filedescriptor)
+}
+
+fn syscall_close(filedescriptor: u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+    USERLAND
+        .lock()
+        .get_current_process()
+        .get_file_descriptors()
+        .close(filedescriptor)
+}
+
+fn syscall_fcntl(filedescriptor: u64, cmd: u64, arg: u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let mut userland = USERLAND.lock();
+    let file_descriptors = userland.get_current_process().get_file_descriptors();
+
+    // file status flags (F_GETFL/F_SETFL) are not supported and ignored
+    match cmd {
+        F_DUPFD => file_descriptors.dup(filedescriptor, arg),
+        F_GETFD => file_descriptors.get_flags(filedescriptor),
+        F_SETFD => file_descriptors.set_flags(filedescriptor, arg),
+        F_GETFL | F_SETFL => match file_descriptors.get(filedescriptor) {
+            Some(_) => 0,
+            None => u64::MAX,
+        },
+        _ => {
+            ERROR!("Unsupported fcntl command {}", cmd);
+            u64::MAX
+        }
     }
 }
 
@@ -312,40 +658,55 @@ fn syscall_vfork() -> u64 {
     return USERLAND.lock().vfork_current_process();
 }
 
+fn syscall_fork() -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+    return USERLAND.lock().fork_current_process();
+}
+
+fn syscall_exit(status: u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+    USERLAND.lock().exit_current_process(status);
+    return 0;
+}
+
+fn syscall_wait4(pid: i64, status: *mut u32, options: u64) -> u64 {
+    let _event = core::hint::black_box(crate::instrument!());
+    return USERLAND.lock().wait_for_child(pid, status, options);
+}
+
 fn syscall_execve(filename: *const u64, argv: *const *const u64, envp: *const *const u64) -> u64 {
     let _event = core::hint::black_box(crate::instrument!());
 
-    if filename.is_null() {
+    // the old address space (including path, argv and envp) is gone once the new program is loaded
+    let (Some(path), Some(args), Some(env)) = (
+        read_user_string(filename as usize),
+        copy_string_array(argv),
+        copy_string_array(envp),
+    ) else {
         return u64::MAX;
+    };
+
+    USERLAND.lock().execve(&path, &args, &env)
+}
+
+// copies a null terminated array of strings like argv from userspace
+fn copy_string_array(array: *const *const u64) -> Option<Vec<String>> {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let mut strings = Vec::new();
+
+    if array.is_null() {
+        return Some(strings);
     }
 
-    match unsafe { core::str::from_utf8(core::slice::from_raw_parts(filename as *const u8, 256)) } {
-        Ok(path_str) => match path_str.split('\0').next() {
-            Some(path_str) => {
-                // reconstruct argv
-                let mut args: Vec<&str> = Vec::new();
-                let mut i = 0;
-                loop {
-                    let arg_ptr = unsafe { *argv.add(i) };
-                    if arg_ptr.is_null() {
-                        break;
-                    }
-                    match unsafe {
-                        core::str::from_utf8(core::slice::from_raw_parts(arg_ptr as *const u8, 256))
-                    } {
-                        Ok(arg_str) => match arg_str.split('\0').next() {
-                            Some(arg_str) => args.push(arg_str),
-                            None => break,
-                        },
-                        Err(_) => break,
-                    }
-                    i += 1;
-                }
-
-                return USERLAND.lock().execve(path_str);
-            }
-            None => return u64::MAX,
-        },
-        Err(_) => return u64::MAX,
+    for i in 0.. {
+        let entry = (array as usize).checked_add(i * size_of::<u64>())?;
+        let string_ptr = read_user::<u64>(entry)?;
+        if string_ptr == 0 {
+            break;
+        }
+        strings.push(read_user_string(string_ptr as usize)?);
     }
+
+    Some(strings)
 }
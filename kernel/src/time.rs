@@ -1,9 +1,16 @@
 use crate::kprint::{_kprint_char_at_pos, _kprint_integer, kprint_char, kprint_integer_at_pos};
-use crate::{acpi, kprint};
-use core::arch::asm;
+use crate::util::{in_port_b, out_port_b};
+use crate::{DEBUG, acpi, kprint};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// https://wiki.osdev.org/CMOS#Accessing_CMOS_Registers
+const CMOS_ADDRESS: u32 = 0x70;
+const CMOS_DATA: u32 = 0x71;
+// keeps NMIs disabled while a register is selected
+const CMOS_NMI_DISABLE: u8 = 0x80;
 
 #[allow(dead_code)]
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Copy)]
 enum CmosRegister {
     Seconds = 0x00,
     Minutes = 0x02,
@@ -16,79 +23,200 @@ enum CmosRegister {
     StatusB = 0x0b,
 }
 
-// https://wiki.osdev.org/CMOS#Accessing_CMOS_Registers
-fn read_cmos_i16(register: CmosRegister, bcd_enabled: bool) -> i16 {
-    unsafe {
-        asm!(
-            r#"out %al, %dx"#,
-            in("dx") 0x70 as i16,
-            in("al") register.clone() as u8,
-            options(att_syntax)
-        );
-
-        let mut ret: i8;
-
-        asm!(
-            r#"in %dx, %al"#,
-            in("dx") 0x71 as i16,
-            out("al") ret,
-            options(att_syntax)
-        );
-
-        if bcd_enabled && register != CmosRegister::StatusA {
-            return ((ret as i16 & 0xF0) >> 1) + ((ret as i16 & 0xF0) >> 3) + (ret as i16 & 0xf);
-        } else {
-            return ret as i16;
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR_MODE: u8 = 1 << 1;
+const STATUS_B_BINARY_MODE: u8 = 1 << 2;
+// set in the hours register for PM in 12 hour mode
+const HOURS_PM: u8 = 1 << 7;
+
+fn read_cmos_register(register: u8) -> u8 {
+    out_port_b(CMOS_ADDRESS, CMOS_NMI_DISABLE | register);
+    in_port_b(CMOS_DATA)
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/** Date and time as stored by the real time clock, which runs in UTC */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl RtcTime {
+    // Reads the raw registers without waiting for an update to finish
+    fn read_registers(century_register: u8) -> [u8; 7] {
+        [
+            read_cmos_register(CmosRegister::Seconds as u8),
+            read_cmos_register(CmosRegister::Minutes as u8),
+            read_cmos_register(CmosRegister::Hours as u8),
+            read_cmos_register(CmosRegister::DayOfMonth as u8),
+            read_cmos_register(CmosRegister::Month as u8),
+            read_cmos_register(CmosRegister::Year as u8),
+            match century_register {
+                0 => 0,
+                register => read_cmos_register(register),
+            },
+        ]
+    }
+
+    // The registers are read until two reads in a row match, so an update in between cannot result in a mix of
+    // the old and the new time
+    pub fn read() -> Self {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let century_register = acpi::rtc_century_register();
+
+        let mut registers;
+        loop {
+            while read_cmos_register(CmosRegister::StatusA as u8) & STATUS_A_UPDATE_IN_PROGRESS != 0
+            {
+                core::hint::spin_loop();
+            }
+            registers = Self::read_registers(century_register);
+
+            while read_cmos_register(CmosRegister::StatusA as u8) & STATUS_A_UPDATE_IN_PROGRESS != 0
+            {
+                core::hint::spin_loop();
+            }
+            if Self::read_registers(century_register) == registers {
+                break;
+            }
+        }
+
+        let status_b = read_cmos_register(CmosRegister::StatusB as u8);
+        let [
+            mut seconds,
+            mut minutes,
+            hours_register,
+            mut day,
+            mut month,
+            mut year,
+            mut century,
+        ] = registers;
+
+        let pm = hours_register & HOURS_PM != 0;
+        let mut hours = hours_register & !HOURS_PM;
+
+        if status_b & STATUS_B_BINARY_MODE == 0 {
+            seconds = bcd_to_binary(seconds);
+            minutes = bcd_to_binary(minutes);
+            hours = bcd_to_binary(hours);
+            day = bcd_to_binary(day);
+            month = bcd_to_binary(month);
+            year = bcd_to_binary(year);
+            century = bcd_to_binary(century);
+        }
+
+        // 12 AM is midnight and 12 PM is noon
+        if status_b & STATUS_B_24_HOUR_MODE == 0 {
+            hours = match (hours, pm) {
+                (12, false) => 0,
+                (12, true) => 12,
+                (hours, false) => hours,
+                (hours, true) => hours + 12,
+            };
+        }
+
+        // without a century register the year is assumed to be in this century
+        let century = if century == 0 { 20 } else { century };
+
+        Self {
+            year: century as u16 * 100 + year as u16,
+            month,
+            day,
+            hours,
+            minutes,
+            seconds,
         }
     }
+
+    // Seconds since 1970-01-01 00:00:00 UTC
+    // https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let month = self.month as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        (days * 86400 + self.hours as i64 * 3600 + self.minutes as i64 * 60 + self.seconds as i64)
+            .max(0) as u64
+    }
 }
 
 // https://github.com/sphaerophoria/stream-os/blob/master/src/io/io_allocator.rs#L67
 // https://stackoverflow.com/a/64818139
 pub fn _kprint_time() {
-    let bcd_enabled: bool = read_cmos_i16(CmosRegister::StatusA, false) != 0;
-
-    let hours: i16 = read_cmos_i16(CmosRegister::Hours, bcd_enabled);
-    let minutes: i16 = read_cmos_i16(CmosRegister::Minutes, bcd_enabled);
-    let seconds: i16 = read_cmos_i16(CmosRegister::Seconds, bcd_enabled);
+    let time = RtcTime::read();
 
-    _kprint_integer(hours.into(), kprint::Colors::KPrintColorDarkGray);
+    _kprint_integer(time.hours.into(), kprint::Colors::KPrintColorDarkGray);
     kprint_char(':', kprint::Colors::KPrintColorDarkGray);
-    _kprint_integer(minutes.into(), kprint::Colors::KPrintColorDarkGray);
+    _kprint_integer(time.minutes.into(), kprint::Colors::KPrintColorDarkGray);
     kprint_char(':', kprint::Colors::KPrintColorDarkGray);
-    _kprint_integer(seconds.into(), kprint::Colors::KPrintColorDarkGray);
+    _kprint_integer(time.seconds.into(), kprint::Colors::KPrintColorDarkGray);
 }
 
-static mut INITIAL_HOURS: i16 = 0;
-static mut INITIAL_MINUTES: i16 = 0;
-static mut INITIAL_SECONDS: i16 = 0;
+// Unix time in ns at which the time since boot was 0
+static BOOT_TIME_NS: AtomicU64 = AtomicU64::new(0);
 
 pub fn set_initial_time() {
     let _event = core::hint::black_box(crate::instrument!());
-    acpi::init_acpi();
+    // without ACPI there is no HPET and the time since boot stays 0
+    if crate::config::get().acpi {
+        acpi::init_acpi();
+    }
 
-    let bcd_enabled: bool = read_cmos_i16(CmosRegister::StatusA, false) != 0;
+    // the RTC only has a resolution of seconds, so the fraction of the current second is lost
+    let time = RtcTime::read();
+    let boot_time_ns =
+        (time.to_unix_timestamp() * 1_000_000_000).saturating_sub(get_ns_since_boot());
+    BOOT_TIME_NS.store(boot_time_ns, Ordering::Relaxed);
 
-    unsafe {
-        INITIAL_HOURS = read_cmos_i16(CmosRegister::Hours, bcd_enabled);
-        INITIAL_MINUTES = read_cmos_i16(CmosRegister::Minutes, bcd_enabled);
-        INITIAL_SECONDS = read_cmos_i16(CmosRegister::Seconds, bcd_enabled);
-    }
+    DEBUG!(
+        "RTC time: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        time.year,
+        time.month,
+        time.day,
+        time.hours,
+        time.minutes,
+        time.seconds
+    );
 }
 
 pub fn _update_clock() {
     let _event = core::hint::black_box(crate::instrument!());
-    let bcd_enabled: bool = read_cmos_i16(CmosRegister::StatusA, false) != 0;
+    let time = RtcTime::read();
 
-    let hours: i16 = read_cmos_i16(CmosRegister::Hours, bcd_enabled);
-    let minutes: i16 = read_cmos_i16(CmosRegister::Minutes, bcd_enabled);
-    let seconds: i16 = read_cmos_i16(CmosRegister::Seconds, bcd_enabled);
-
-    kprint_integer_at_pos(hours.into(), 0, 70, kprint::Colors::KPrintColorDarkGray);
+    kprint_integer_at_pos(
+        time.hours.into(),
+        0,
+        70,
+        kprint::Colors::KPrintColorDarkGray,
+    );
     _kprint_char_at_pos(':', 0, 72, kprint::Colors::KPrintColorDarkGray);
-    kprint_integer_at_pos(minutes.into(), 0, 73, kprint::Colors::KPrintColorDarkGray);
+    kprint_integer_at_pos(
+        time.minutes.into(),
+        0,
+        73,
+        kprint::Colors::KPrintColorDarkGray,
+    );
     _kprint_char_at_pos(':', 0, 75, kprint::Colors::KPrintColorDarkGray);
-    kprint_integer_at_pos(seconds.into(), 0, 76, kprint::Colors::KPrintColorDarkGray);
+    kprint_integer_at_pos(
+        time.seconds.into(),
+        0,
+        76,
+        kprint::Colors::KPrintColorDarkGray,
+    );
 }
 
 #[macro_export]
@@ -110,17 +238,95 @@ macro_rules! get_ns_since_boot {
     }};
 }
 
+// clocks of clock_gettime and clock_nanosleep, see time.h in libc
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+pub const TIMER_ABSTIME: u64 = 1;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+impl Timespec {
+    pub fn from_ns(ns: u64) -> Self {
+        Self {
+            tv_sec: (ns / 1_000_000_000) as i64,
+            tv_nsec: (ns % 1_000_000_000) as i64,
+        }
+    }
+
+    // None for negative or denormalized values
+    pub fn as_ns(&self) -> Option<u64> {
+        if self.tv_sec < 0 || !(0..1_000_000_000).contains(&self.tv_nsec) {
+            return None;
+        }
+        (self.tv_sec as u64)
+            .checked_mul(1_000_000_000)?
+            .checked_add(self.tv_nsec as u64)
+    }
+}
+
+pub fn get_ns_since_boot() -> u64 {
+    get_ns_since_boot!()
+}
+
 pub fn get_us_since_boot() -> u64 {
     get_ns_since_boot!() / 1000
 }
 
+// Without the HPET the time since boot stays 0
+pub fn is_hpet_available() -> bool {
+    !acpi::HPET_COUNTER_VALUE_ADDRESS
+        .load(core::sync::atomic::Ordering::Relaxed)
+        .is_null()
+}
+
+// Without the HPET the time does not advance, so this returns immediately
+pub fn busy_wait_us(us: u64) {
+    let start = get_us_since_boot();
+    while get_us_since_boot() - start < us && is_hpet_available() {
+        core::hint::spin_loop();
+    }
+}
+
 pub fn get_ms_since_boot() -> u64 {
     get_ns_since_boot!() / 1_000_000
 }
 
+// Unix time in ns, advanced by the HPET since the RTC was read at boot
+pub fn get_realtime_ns() -> u64 {
+    BOOT_TIME_NS.load(Ordering::Relaxed) + get_ns_since_boot()
+}
+
+// Seconds since the epoch, e.g. for ext2 timestamps
+pub fn get_unix_time() -> u32 {
+    (get_realtime_ns() / 1_000_000_000) as u32
+}
+
+// Seconds and microseconds since the epoch
 pub fn get_time() -> (u32, u32) {
+    let realtime_us = get_realtime_ns() / 1000;
     (
-        ((get_ms_since_boot() / 1000 + unsafe { INITIAL_SECONDS } as u64) % 60) as u32,
-        (get_us_since_boot() % 1000000) as u32,
+        (realtime_us / 1_000_000) as u32,
+        (realtime_us % 1_000_000) as u32,
     )
 }
+
+// The current time of the clock given to clock_gettime or clock_nanosleep, None for unknown clocks
+pub fn get_clock_ns(clock_id: u64) -> Option<u64> {
+    match clock_id {
+        CLOCK_REALTIME => Some(get_realtime_ns()),
+        CLOCK_MONOTONIC => Some(get_ns_since_boot()),
+        _ => None,
+    }
+}
@@ -0,0 +1,76 @@
+use crate::interrupt::without_interrupts;
+use crate::wait_queue::WaitQueue;
+use crate::{DEBUG, config, get_ns_since_boot, util};
+use spin::Mutex;
+
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+// https://wiki.osdev.org/Programmable_Interval_Timer
+const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+const PIT_CHANNEL_0: u32 = 0x40;
+const PIT_COMMAND: u32 = 0x43;
+// channel 0, lobyte/hibyte access, rate generator
+const PIT_COMMAND_RATE_GENERATOR: u8 = 0b0011_0100;
+
+/** Wakes up a wait queue once the time since boot reached the deadline */
+struct Timer {
+    deadline_ns: u64,
+    wait_queue: Arc<WaitQueue>,
+}
+
+// sorted by deadline, the next timer to expire comes first
+static TIMERS: Mutex<Vec<Timer>> = Mutex::new(Vec::new());
+
+// Programs the PIT of the bootstrap processor to the tick rate from the kernel command line, the local APIC timers of
+// the application processors use the same rate, see apic::start_timer
+pub fn init_timer() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let tick_rate = config::get().tick_rate;
+    let divisor = (PIT_FREQUENCY_HZ / tick_rate).clamp(1, u16::MAX as u32) as u16;
+
+    without_interrupts(|| {
+        util::out_port_b(PIT_COMMAND, PIT_COMMAND_RATE_GENERATOR);
+        util::out_port_b(PIT_CHANNEL_0, divisor as u8);
+        util::out_port_b(PIT_CHANNEL_0, (divisor >> 8) as u8);
+    });
+
+    DEBUG!("Timer tick rate: {} Hz", tick_rate);
+}
+
+// Inserts a timer which wakes up the wait queue at the next tick after the deadline (in ns since boot)
+pub fn add_timer(deadline_ns: u64, wait_queue: Arc<WaitQueue>) {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    // the timer interrupt takes the lock as well
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let position = timers.partition_point(|timer| timer.deadline_ns <= deadline_ns);
+        timers.insert(
+            position,
+            Timer {
+                deadline_ns,
+                wait_queue,
+            },
+        );
+    });
+}
+
+// Called on every timer interrupt of every CPU before the scheduler runs
+pub fn run_expired_timers() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let now = get_ns_since_boot!();
+
+    // another CPU is already handling the timers
+    let Some(mut timers) = TIMERS.try_lock() else {
+        return;
+    };
+
+    let expired = timers.partition_point(|timer| timer.deadline_ns <= now);
+    for timer in timers.drain(..expired) {
+        timer.wait_queue.wake_all();
+    }
+}
@@ -0,0 +1,168 @@
+use crate::mem_config::{KERNEL_HIGHER_HALF_BASE, PAGE_OFFSET_MASK, PAGE_SIZE};
+use crate::per_cpu;
+use core::arch::global_asm;
+use core::mem::MaybeUninit;
+use core::ptr::addr_of;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// longer strings passed to syscalls are rejected, e.g. paths or the arguments of execve
+const MAX_USER_STRING_LENGTH: usize = 32 * PAGE_SIZE;
+
+// Copies rdx bytes from rsi to rdi and returns true; if the copy faults, fix_up_fault continues at user_copy_fault,
+// which returns false instead
+global_asm!(
+    ".globl user_copy",
+    ".globl user_copy_access",
+    ".globl user_copy_fault",
+    "user_copy:",
+    "    mov rcx, rdx",
+    "user_copy_access:",
+    "    rep movsb",
+    "    mov eax, 1",
+    "    ret",
+    "user_copy_fault:",
+    "    xor eax, eax",
+    "    ret",
+);
+
+unsafe extern "C" {
+    fn user_copy(destination: *mut u8, source: *const u8, length: usize) -> bool;
+    static user_copy_access: u8;
+    static user_copy_fault: u8;
+}
+
+// Returns true if the range lies completely in the lower half, i.e. it cannot reach kernel memory
+pub fn is_user_range(address: usize, length: usize) -> bool {
+    address
+        .checked_add(length)
+        .is_some_and(|end| end <= KERNEL_HIGHER_HALF_BASE)
+}
+
+// Checks that a buffer passed to a syscall is accessible by the current process and maps all its pages, so the
+// kernel can access it directly afterwards
+pub fn prepare_user_buffer(address: usize, length: usize, write: bool) -> bool {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    if !is_user_range(address, length) {
+        return false;
+    }
+    if length == 0 {
+        return true;
+    }
+
+    let address_space = per_cpu::current().address_space;
+    if address_space.is_null() {
+        return false;
+    }
+
+    (address & !PAGE_OFFSET_MASK..address + length)
+        .step_by(PAGE_SIZE)
+        .all(|page| unsafe { (*address_space).fault_in(page, write) })
+}
+
+fn copy_user(
+    destination: *mut u8,
+    source: *const u8,
+    length: usize,
+    user_address: usize,
+    write: bool,
+) -> bool {
+    prepare_user_buffer(user_address, length, write)
+        && unsafe { user_copy(destination, source, length) }
+}
+
+pub fn copy_from_user(destination: &mut [u8], source: usize) -> bool {
+    copy_user(
+        destination.as_mut_ptr(),
+        source as *const u8,
+        destination.len(),
+        source,
+        false,
+    )
+}
+
+pub fn copy_to_user(destination: usize, source: &[u8]) -> bool {
+    copy_user(
+        destination as *mut u8,
+        source.as_ptr(),
+        source.len(),
+        destination,
+        true,
+    )
+}
+
+// Reads a plain struct like Timespec from user memory, which does not need to be aligned
+pub fn read_user<T: Copy>(address: usize) -> Option<T> {
+    let mut value = MaybeUninit::<T>::uninit();
+
+    if copy_user(
+        value.as_mut_ptr() as *mut u8,
+        address as *const u8,
+        size_of::<T>(),
+        address,
+        false,
+    ) {
+        Some(unsafe { value.assume_init() })
+    } else {
+        None
+    }
+}
+
+pub fn write_user<T>(address: usize, value: &T) -> bool {
+    copy_user(
+        address as *mut u8,
+        value as *const T as *const u8,
+        size_of::<T>(),
+        address,
+        true,
+    )
+}
+
+// Copies a null terminated string from user memory
+pub fn read_user_string(address: usize) -> Option<String> {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let mut bytes = Vec::new();
+    let mut current = address;
+
+    while bytes.len() < MAX_USER_STRING_LENGTH {
+        // the string may end right before an unmapped page, so a chunk never crosses a page boundary
+        let start = bytes.len();
+        let chunk_length = PAGE_SIZE - (current & PAGE_OFFSET_MASK);
+        bytes.resize(start + chunk_length, 0);
+
+        if !copy_from_user(&mut bytes[start..], current) {
+            return None;
+        }
+
+        if let Some(end) = bytes[start..].iter().position(|&byte| byte == 0) {
+            bytes.truncate(start + end);
+            return String::from_utf8(bytes).ok();
+        }
+
+        current += chunk_length;
+    }
+
+    None
+}
+
+// Called for a page fault of the kernel in user memory which could not be resolved; a fault while copying from or
+// to user memory makes the copy fail, so the syscall returns an error instead of the kernel crashing
+pub fn fix_up_fault() -> bool {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    // the innermost interrupt stack frame is the one of the page fault, it starts with the rip
+    let rip = per_cpu::current().stack_frame as *mut u64;
+
+    unsafe {
+        if *rip != addr_of!(user_copy_access) as u64 {
+            return false;
+        }
+        *rip = addr_of!(user_copy_fault) as u64;
+    }
+
+    true
+}
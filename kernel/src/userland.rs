@@ -1,27 +1,65 @@
 use spin::Mutex;
 
-use crate::mem_config::USERSPACE_STACK_TOP_ADDRESS;
-use crate::process::Process;
-use crate::{ERROR, USERLAND};
+use crate::filesystem::FileHandle;
+use crate::mem;
+use crate::mem_config::IDLE_STACK_SIZE;
+use crate::per_cpu::{self, MAX_CPUS, PerCpu};
+use crate::process::{self, KERNEL_CR3, Process};
+use crate::user_memory;
+use crate::{DEBUG, ERROR, USERLAND};
 
 extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
 
 use core::arch::global_asm;
 use core::fmt;
+use core::ptr::addr_of;
+use core::sync::atomic::Ordering;
+
+global_asm!(
+    include_str!("switch_to_ring3.S"),
+    PER_CPU_KERNEL_STACK_TOP = const core::mem::offset_of!(PerCpu, kernel_stack_top),
+    PER_CPU_SYSCALL_USER_RSP = const core::mem::offset_of!(PerCpu, syscall_user_rsp),
+    PER_CPU_SCHEDULING_BLOCKED = const core::mem::offset_of!(PerCpu, scheduling_blocked),
+);
+
+/// Option for wait4: return immediately if no child has exited
+const WNOHANG: u64 = 1;
+
+/// Signal terminating a process after an invalid memory access
+pub const SIGSEGV: u32 = 11;
+
+// what remains of a process after it exited, until its parent collects the wait status
+struct ZombieProcess {
+    process_id: u64,
+    parent_id: u64,
+    wait_status: u64,
+}
+
+#[repr(C, align(4096))]
+struct IdleStack([u8; IDLE_STACK_SIZE]);
 
-global_asm!(include_str!("switch_to_ring3.S"));
+static mut IDLE_STACKS: [IdleStack; MAX_CPUS] =
+    [const { IdleStack([0; IDLE_STACK_SIZE]) }; MAX_CPUS];
+
+// the application processors start on their idle stack, see smp.rs
+pub fn get_idle_stack_top(cpu_index: usize) -> u64 {
+    unsafe { addr_of!(IDLE_STACKS[cpu_index]) as u64 + IDLE_STACK_SIZE as u64 }
+}
 
 //#[derive(Default)]
 pub struct Userland {
-    processes: Vec<Process>,
-    current_process: usize,
+    // boxed, as the per-CPU data points to the address space of a running process
+    processes: Vec<Box<Process>>,
+    zombies: Vec<ZombieProcess>,
 }
 
 impl fmt::Debug for Userland {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Userland")
-            .field("current_process", &self.current_process)
+            .field("current_process", &self.current_pid())
             .finish()
     }
 }
@@ -32,22 +70,10 @@ impl Userland {
 
         Self {
             processes: Vec::new(),
-            current_process: 0,
+            zombies: Vec::new(),
         }
     }
 
-    pub fn process_malloc(&mut self, size: usize) -> u64 {
-        let _event = core::hint::black_box(crate::instrument!());
-
-        return self.get_current_process().malloc(size);
-    }
-
-    pub fn process_realloc(&mut self, ptr: u64, size: usize) -> u64 {
-        let _event = core::hint::black_box(crate::instrument!());
-
-        return self.get_current_process().realloc(ptr, size);
-    }
-
     pub fn switch_to_userland(&mut self, mutex: &Mutex<Userland>) {
         let _event = core::hint::black_box(crate::instrument!());
 
@@ -56,16 +82,20 @@ impl Userland {
         }
 
         unsafe {
-            self.processes.push(Process::new());
+            self.processes.push(Box::new(Process::new()));
             //self.processes.push(Process::new());
             //self.processes.push(Process::new());
             //self.processes.push(Process::new());
 
+            let init = crate::config::get().init;
             for process in &mut self.processes {
-                process.initialize("/dash");
+                let file_handle = FileHandle::new(init, 0).expect("init program not found");
+                process
+                    .initialize(file_handle, &[String::from(init)], &[])
+                    .expect("init program is no executable");
             }
 
-            self.current_process = self.processes[0].get_pid() as usize;
+            per_cpu::current().current_process = self.processes[0].get_pid() as usize;
 
             self.processes[0].launch();
             //self.processes[1].launch();
@@ -81,7 +111,7 @@ impl Userland {
 
             jump_usermode(
                 c3_page_map_l4_base_address as u64,
-                USERSPACE_STACK_TOP_ADDRESS as u64,
+                self.processes[0].get_stack_pointer(),
                 self.processes[0].get_entry_ip() as u64,
             );
         }
@@ -92,47 +122,84 @@ impl Userland {
 
         // TODO for now scheduler is simply going round robin
 
+        for process in self.processes.iter_mut() {
+            process.unblock_if_woken_up();
+        }
+
+        // a process killed while it was running on this CPU
+        if let Some(wait_status) = self
+            .processes
+            .iter()
+            .find(|p| p.get_pid() == self.current_pid() as u64)
+            .and_then(|p| p.get_pending_termination())
+        {
+            self.terminate_process(self.current_pid() as u64, wait_status);
+        }
+
         // find vector index of current process by iterating through all processes
-        let mut current_process_index = self
+        // there is none if the cpu is idling or the current process has just exited
+        let current_process_index = self
             .processes
             .iter()
-            .position(|p| p.get_pid() == self.current_process as u64)
-            .unwrap();
+            .position(|p| p.get_pid() == self.current_pid() as u64);
 
-        let last_process = current_process_index;
+        let first_candidate = current_process_index.map_or(0, |index| index + 1);
+        let next_process_index = (0..self.processes.len())
+            .map(|i| (first_candidate + i) % self.processes.len())
+            .find(|&index| self.processes[index].activatable());
 
-        loop {
-            current_process_index += 1;
-            if current_process_index == self.processes.len() {
-                current_process_index = 0;
-            }
-            if self.processes[current_process_index].activatable() {
-                break;
+        if let Some(index) = current_process_index {
+            // not a single other userspace process ready for execution
+            if next_process_index.is_none() && self.processes[index].is_active() {
+                return;
             }
 
-            // not a single userspace process ready for execution
-            if current_process_index == last_process {
-                return;
+            self.processes[index].passivate();
+        }
+
+        match next_process_index {
+            Some(index) => {
+                per_cpu::current().current_process = self.processes[index].get_pid() as usize;
+                self.processes[index].activate(false);
+            }
+            None => {
+                per_cpu::current().current_process = 0;
+                enter_idle_loop();
             }
         }
+    }
+
+    // switches to another process if the current one stopped running during a syscall
+    pub fn switch_process_if_inactive(&mut self) {
+        let _event = core::hint::black_box(crate::instrument!());
 
-        self.processes[last_process].passivate();
-        self.current_process = self.processes[current_process_index].get_pid() as usize;
-        self.processes[self.current_process].activate(false);
+        if !self
+            .processes
+            .iter()
+            .any(|p| p.get_pid() == self.current_pid() as u64 && p.is_active())
+        {
+            self.switch_process();
+        }
     }
 
     pub fn get_current_process_id(&self) -> usize {
         let _event = core::hint::black_box(crate::instrument!());
 
-        self.current_process
+        self.current_pid()
+    }
+
+    // every CPU runs its own process
+    fn current_pid(&self) -> usize {
+        per_cpu::current().current_process
     }
 
     pub fn get_current_process(&mut self) -> &mut Process {
         let _event = core::hint::black_box(crate::instrument!());
 
+        let current_pid = self.current_pid() as u64;
         self.processes
             .iter_mut()
-            .find(|p| p.get_pid() == self.current_process as u64)
+            .find(|p| p.get_pid() == current_pid)
             .unwrap()
     }
 
@@ -150,44 +217,205 @@ impl Userland {
             return -1;
         }
 
-        if let Some(pos) = self.processes.iter().position(|x| x.get_pid() == pid) {
-            self.processes.remove(pos);
+        // the parent sees the signal as termination reason
+        if self.terminate_process(pid, sig as u64) {
             return 0;
         }
 
         return -1;
     }
 
-    pub fn vfork_current_process(&mut self) -> u64 {
+    pub fn kill_current_process(&mut self, sig: u32) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        DEBUG!("Process {} killed by signal {}", self.current_pid(), sig);
+        self.terminate_process(self.current_pid() as u64, sig as u64);
+    }
+
+    pub fn exit_current_process(&mut self, status: u64) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        DEBUG!(
+            "Process {} exited with status {}",
+            self.current_pid(),
+            status
+        );
+        self.terminate_process(self.current_pid() as u64, (status & 0xff) << 8);
+    }
+
+    // Removes a process and keeps its wait status as zombie until the parent collects it with wait4
+    // Returns false if there is no such process
+    fn terminate_process(&mut self, pid: u64, wait_status: u64) -> bool {
         let _event = core::hint::black_box(crate::instrument!());
 
-        let parent_process = self.get_current_process();
-        parent_process.put_to_sleep();
+        let position = match self.processes.iter().position(|p| p.get_pid() == pid) {
+            Some(position) => position,
+            None => return false,
+        };
+
+        // the page tables are still in use, the other CPU terminates the process when it schedules next
+        if self.processes[position].is_running_on_other_cpu() {
+            self.processes[position].terminate_later(wait_status);
+            return true;
+        }
 
-        let mut child_process = Process::new();
-        child_process.clone_from_parent(parent_process);
+        if pid == self.current_pid() as u64 {
+            // the page tables of the process are freed below
+            mem::set_cr3(KERNEL_CR3.load(Ordering::Relaxed));
+            per_cpu::current().current_process = 0;
+            per_cpu::current().address_space = core::ptr::null_mut();
+        }
 
+        // dropping the process frees its page frames
+        let parent_id = self.processes.remove(position).get_parent_id();
+
+        // nobody is going to wait for the children of this process anymore
+        for child in self
+            .processes
+            .iter_mut()
+            .filter(|p| p.get_parent_id() == pid)
+        {
+            child.set_parent_id(0);
+        }
+        self.zombies.retain(|zombie| zombie.parent_id != pid);
+
+        // wakes up a vfork parent as well as a parent waiting in wait4
+        if let Some(parent) = self.processes.iter_mut().find(|p| p.get_pid() == parent_id) {
+            parent.wake_up();
+
+            self.zombies.push(ZombieProcess {
+                process_id: pid,
+                parent_id,
+                wait_status,
+            });
+        }
+
+        true
+    }
+
+    // Returns the pid of an exited child and stores its wait status, 0 if none has exited yet with WNOHANG
+    // and u64::MAX if there is no matching child; otherwise the process sleeps until a child exits
+    pub fn wait_for_child(&mut self, pid: i64, status: *mut u32, options: u64) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        // TODO process groups are not supported, so any pid <= 0 matches all children
+        let parent_id = self.current_pid() as u64;
+        let matches = |child_pid: u64| pid <= 0 || child_pid == pid as u64;
+
+        if let Some(position) = self
+            .zombies
+            .iter()
+            .position(|zombie| zombie.parent_id == parent_id && matches(zombie.process_id))
+        {
+            // the zombie is kept if the status cannot be stored
+            let wait_status = self.zombies[position].wait_status as u32;
+            if !status.is_null() && !user_memory::write_user(status as usize, &wait_status) {
+                return u64::MAX;
+            }
+
+            return self.zombies.remove(position).process_id;
+        }
+
+        if !self
+            .processes
+            .iter()
+            .any(|p| p.get_parent_id() == parent_id && matches(p.get_pid()))
+        {
+            return u64::MAX;
+        }
+
+        if options & WNOHANG != 0 {
+            return 0;
+        }
+
+        // the syscall is executed again when an exiting child wakes the process up
+        self.get_current_process().put_to_sleep();
+        process::restart_syscall();
+
+        return 0;
+    }
+
+    pub fn fork_current_process(&mut self) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let child_process = self.get_current_process().fork();
         let child_pid = child_process.get_pid();
         self.processes.push(child_process);
 
-        self.current_process = child_pid as usize;
+        // the parent gets the pid of the child, the child returns 0 from its saved registers
+        return child_pid;
+    }
 
-        // return 0 as we are executing in the child process
-        return 0;
+    pub fn vfork_current_process(&mut self) -> u64 {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        let child_pid = self.fork_current_process();
+
+        // the parent sleeps until the child calls execve
+        self.get_current_process().put_to_sleep();
+
+        return child_pid;
     }
 
-    pub fn execve(&mut self, filename: &str) -> u64 {
+    pub fn execve(&mut self, filename: &str, args: &[String], env: &[String]) -> u64 {
         let _event = core::hint::black_box(crate::instrument!());
 
-        // cycle through all processes to find the current one
+        let path = self.get_current_process().resolve_path(filename);
+        let filename = path.as_str();
+
+        let Some(file_handle) = FileHandle::new(filename, 0) else {
+            ERROR!("execve: file not found: {}\n", filename);
+            return u64::MAX;
+        };
+
+        // the headers are checked before anything is replaced, so the caller keeps running on ENOEXEC
         let current_process = self.get_current_process();
+        if let Err(error) = current_process.initialize(file_handle, args, env) {
+            ERROR!("execve: {} is no executable: {:?}\n", filename, error);
+            return u64::MAX;
+        }
 
-        let filename_str = filename;
-        current_process.initialize(filename_str);
+        let parent_id = current_process.get_parent_id();
+        if let Some(parent_process) = self
+            .processes
+            .iter_mut()
+            .find(|p| p.get_pid() == parent_id && p.is_sleeping())
+        {
+            parent_process.wake_up();
+        }
+
+        // continue with the new program instead of returning to the old one
+        self.get_current_process().activate(false);
         0
     }
 }
 
+// lets the cpu sleep in kernel mode until an interrupt makes a process ready for execution again
+fn enter_idle_loop() {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    unsafe extern "C" {
+        fn idle_loop();
+    }
+
+    let stack_frame = per_cpu::current().stack_frame as *mut u64;
+
+    mem::set_cr3(KERNEL_CR3.load(Ordering::Relaxed));
+
+    unsafe {
+        let idle_loop: unsafe extern "C" fn() = idle_loop;
+
+        core::ptr::write_volatile(stack_frame.add(0), idle_loop as usize as u64);
+        core::ptr::write_volatile(stack_frame.add(1), 0x08); // kernel code segment
+        core::ptr::write_volatile(stack_frame.add(2), 0x202);
+        core::ptr::write_volatile(
+            stack_frame.add(3),
+            get_idle_stack_top(per_cpu::current().cpu_index),
+        );
+        core::ptr::write_volatile(stack_frame.add(4), 0x10); // kernel data segment
+    }
+}
+
 // very simple scheduler
 pub fn schedule() {
     let _event = core::hint::black_box(crate::instrument!());
@@ -195,8 +423,24 @@ pub fn schedule() {
     USERLAND.lock().switch_process();
 }
 
-pub fn extend_stack() {
+// Maps the page on demand if the memory areas of the current process allow the access
+// USERLAND must not be locked here, as the kernel accesses user memory while holding the lock
+pub fn handle_page_fault(address: usize, write: bool, execute: bool) -> bool {
+    let _event = core::hint::black_box(crate::instrument!());
+
+    let address_space = per_cpu::current().address_space;
+    if address_space.is_null() {
+        return false;
+    }
+
+    unsafe { (*address_space).handle_page_fault(address, write, execute) }
+}
+
+// Terminates the current process after an invalid memory access in user mode
+pub fn segmentation_fault() {
     let _event = core::hint::black_box(crate::instrument!());
 
-    USERLAND.lock().get_current_process().extend_stack();
+    let mut userland = USERLAND.lock();
+    userland.kill_current_process(SIGSEGV);
+    userland.switch_process();
 }
@@ -1,4 +1,5 @@
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 pub fn out_port_b(port: u32, value: u8) {
     unsafe {
@@ -27,18 +28,42 @@ pub fn in_port_w(port: u32) -> u16 {
     return key;
 }
 
-pub fn compare_str_to_memory(s: &str, addr: usize) -> bool {
-    let _event = core::hint::black_box(crate::instrument!());
-    let bytes = s.as_bytes();
-    let ptr = addr as *const u8;
+pub fn out_port_w(port: u32, value: u16) {
+    unsafe {
+        asm!("out dx, ax", in("rdx") port, in("ax") value);
+    }
+}
 
+pub fn read_msr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
     unsafe {
-        for i in 0..bytes.len() {
-            if *ptr.add(i) != bytes[i] {
-                return false; // Mismatch found
-            }
-        }
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
     }
+    ((high as u64) << 32) | low as u64
+}
+
+pub fn write_msr(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack)
+        );
+    }
+}
+
+// Not suitable for cryptography: mixes the timestamp counter with splitmix64
+pub fn pseudo_random_u64() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
 
-    true // All bytes match
+    let seed = unsafe { core::arch::x86_64::_rdtsc() };
+    let mut z = STATE
+        .fetch_add(seed | 1, Ordering::Relaxed)
+        .wrapping_add(seed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
@@ -1,5 +1,6 @@
 use crate::kprint;
 use crate::mem_config::KERNEL_HIGHER_HALF_BASE;
+use crate::multiboot2::{self, FRAMEBUFFER_TYPE_EGA_TEXT};
 use crate::util::in_port_b;
 use crate::util::out_port_b;
 
@@ -30,8 +31,8 @@ const VGA_NUM_CRTC_REGS: u32 = 25;
 const VGA_NUM_GC_REGS: u32 = 9;
 const VGA_NUM_SEQ_REGS: u32 = 5;
 
-const VGA_SCREEN_WIDTH: u32 = 320;
-const VGA_SCREEN_HEIGHT: u32 = 200;
+pub const VGA_SCREEN_WIDTH: u32 = 320;
+pub const VGA_SCREEN_HEIGHT: u32 = 200;
 const VGA_SCREEN_SIZE: usize = 320 * 200;
 
 // migrated from https://github.com/pagekey/pkos/blob/vid/os015/src/vga/vga.c#L93-L146
@@ -236,9 +237,20 @@ fn vga_restore_text_mode_palette() {
 const VGA_TEXT_MODE_SIZE: usize = 80 * 25;
 static mut VGA_TEXT_MODE_BACKUP: [u16; VGA_TEXT_MODE_SIZE] = [0; VGA_TEXT_MODE_SIZE];
 
+// The text mode buffer as reported by the boot loader, falling back to the standard location
+fn text_buffer_address() -> u64 {
+    match multiboot2::framebuffer() {
+        Some(framebuffer) if framebuffer.framebuffer_type == FRAMEBUFFER_TYPE_EGA_TEXT => {
+            framebuffer.address
+        }
+        _ => REGION3,
+    }
+}
+
 fn vga_backup_vidmem() {
     let _event = core::hint::black_box(crate::instrument!());
-    let text_ptr: *const u16 = (KERNEL_HIGHER_HALF_BASE as u64 + REGION3) as *const u16;
+    let text_ptr: *const u16 =
+        (KERNEL_HIGHER_HALF_BASE as u64 + text_buffer_address()) as *const u16;
     unsafe {
         for i in 0..VGA_TEXT_MODE_SIZE {
             VGA_TEXT_MODE_BACKUP[i] = *text_ptr.add(i);
@@ -248,7 +260,7 @@ fn vga_backup_vidmem() {
 
 fn vga_restore_vidmem() {
     let _event = core::hint::black_box(crate::instrument!());
-    let text_ptr: *mut u16 = (KERNEL_HIGHER_HALF_BASE as u64 + REGION3) as *mut u16;
+    let text_ptr: *mut u16 = (KERNEL_HIGHER_HALF_BASE as u64 + text_buffer_address()) as *mut u16;
     unsafe {
         for i in 0..VGA_TEXT_MODE_SIZE {
             core::ptr::write_volatile(text_ptr.add(i), VGA_TEXT_MODE_BACKUP[i]);
@@ -0,0 +1,56 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::process::{self, Process};
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+/**
+ * Processes waiting for an event, e.g. keyboard input.
+ *
+ * Waking up only increments a counter, so it can be done from interrupt handlers without locking USERLAND.
+ * A blocked process remembers the counter from before it checked the awaited condition and the scheduler lets it
+ * run again once the counter has changed.
+ */
+pub struct WaitQueue {
+    wake_ups: AtomicU64,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self {
+            wake_ups: AtomicU64::new(0),
+        }
+    }
+
+    // Has to be called before checking the awaited condition, so a wake up in between is not lost
+    pub fn prepare_to_wait(self: &Arc<Self>) -> Waiter {
+        Waiter {
+            wait_queue: self.clone(),
+            wake_ups: self.wake_ups.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn wake_all(&self) {
+        self.wake_ups.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Waiter {
+    wait_queue: Arc<WaitQueue>,
+    wake_ups: u64,
+}
+
+impl Waiter {
+    // Blocks the current process, the syscall in progress is executed again when the process continues
+    pub fn wait(self, process: &mut Process) {
+        let _event = core::hint::black_box(crate::instrument!());
+
+        process.block_on(self);
+        process::restart_syscall();
+    }
+
+    pub fn is_woken_up(&self) -> bool {
+        self.wait_queue.wake_ups.load(Ordering::Relaxed) != self.wake_ups
+    }
+}
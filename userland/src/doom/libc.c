@@ -44,10 +44,24 @@ void draw_pixel(uint32_t x, uint32_t y, uint8_t color) {
   DO_SYSCALL(3, result, x, y, color);
 }
 
-// Allocate memory
+// Allocate memory by moving the program break
 void *malloc(int size) {
-  uint64_t address;
-  DO_SYSCALL(4, address, size, 0, 0); // Only size is passed
+  static uint64_t program_break = 0;
+
+  if (program_break == 0) {
+    DO_SYSCALL(38, program_break, 0, 0, 0);
+  }
+
+  uint64_t address = (program_break + 15) & ~15ull;
+  uint64_t new_break;
+  DO_SYSCALL(38, new_break, address + size, 0, 0);
+
+  // the kernel returns the unchanged break on failure
+  if (new_break != address + size) {
+    return 0;
+  }
+
+  program_break = new_break;
   return (void *)address;
 }
 
@@ -63,6 +77,10 @@ void *fopen(const char *filename, const char *options) {
 
   uint64_t handle;
   DO_SYSCALL(5, handle, (uintptr_t)filename, (uintptr_t)options, 0);
+
+  if (handle == (uint64_t)-1) {
+    return 0;
+  }
   return (void *)handle;
 }
 
@@ -71,10 +89,11 @@ void fclose(void *handle) {
   // TODO: Implement the fclose function
 }
 
-// Write to a file (currently does nothing)
-int fwrite(void *handle, const void *foo, int bar) {
-  write(1, "fwrite: ", strlen("fwrite: "));
-  return 0; // TODO: Implement fwrite
+// Write to a file
+int fwrite(void *handle, const void *ptr, int size) {
+  uint64_t written_bytes;
+  DO_SYSCALL(1, written_bytes, handle, (uintptr_t)ptr, size);
+  return written_bytes;
 }
 
 // Seek within a file
@@ -119,14 +138,30 @@ uint64_t switch_vga_mode(bool vga_on) {
   return result;
 }
 
-// Get the state of a key
-bool get_keystate(int key) {
-  uint64_t state;
-  DO_SYSCALL(12, state, key, 0, 0);
-  return (bool)state;
+int read_key_events(struct key_event *events, int count) {
+  uint64_t result;
+  DO_SYSCALL(12, result, (uintptr_t)events, count, 0);
+
+  if (result == (uint64_t)-1) {
+    return -1;
+  }
+  return (int)result;
 }
 
 void get_time(int *sec, int *usec) {
   uint64_t result;
   DO_SYSCALL(13, result, sec, usec, 0);
+}
+
+struct timespec {
+  int64_t tv_sec;
+  int64_t tv_nsec;
+};
+
+void sleep_ms(int ms) {
+  struct timespec request = {.tv_sec = ms / 1000,
+                             .tv_nsec = (ms % 1000) * 1000000};
+
+  uint64_t result;
+  DO_SYSCALL(34, result, (uintptr_t)&request, 0, 0);
 }
\ No newline at end of file
@@ -5,6 +5,8 @@
 #include "inttypes.h"
 #include "stdbool.h"
 #include "stdint.h"
+// shared with the libc of the other user programs
+#include "../../usr/include/keyboard.h"
 
 #define DOOM_IMPLEMENTATION
 
@@ -49,7 +51,7 @@ void free(void *address);
 void write(uint64_t filedescriptor, const char *payload, uint64_t len);
 uint64_t draw_framebuffer(const uint8_t *framebuffer);
 uint64_t switch_vga_mode(bool vga_on);
-bool get_keystate(int key);
 void get_time(int *sec, int *usec);
+void sleep_ms(int ms);
 
 #endif // __LIBC_H__
@@ -1,6 +1,7 @@
 extern crate alloc;
 use core::alloc::{GlobalAlloc, Layout};
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 pub struct ProcessAllocator {}
 
@@ -96,13 +97,14 @@ pub fn draw_pixel(x: u32, y: u32, color: u8) {
     }
 }
 
-pub fn malloc(size: usize) -> u64 {
-    let mut address: u64;
+// sets the program break and returns the new one, or the unchanged one on failure
+pub fn brk(address: u64) -> u64 {
+    let mut program_break: u64;
 
     unsafe {
         asm!("
             push rdi
-            mov rdi, 4
+            mov rdi, 38
 
             push r11
             push rcx
@@ -114,13 +116,33 @@ pub fn malloc(size: usize) -> u64 {
 
             pop rdi
         ",
-            in("r8") size,
-            out("rax") address,
+            in("r8") address,
+            out("rax") program_break,
             options(nostack),
             clobber_abi("C")
         );
     }
 
+    return program_break;
+}
+
+static PROGRAM_BREAK: AtomicU64 = AtomicU64::new(0);
+
+// allocates memory by moving the program break
+pub fn malloc(size: usize) -> u64 {
+    let mut program_break = PROGRAM_BREAK.load(Ordering::Relaxed);
+    if program_break == 0 {
+        program_break = brk(0);
+    }
+
+    let address = (program_break + 15) & !15;
+    let new_break = brk(address + size as u64);
+
+    if new_break != address + size as u64 {
+        return 0;
+    }
+
+    PROGRAM_BREAK.store(new_break, Ordering::Relaxed);
     return address;
 }
 
@@ -252,6 +274,40 @@ pub fn fread(ptr: *mut u8, size: usize, nmemb: usize) {
     }
 }
 
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+pub fn nanosleep(ns: u64) {
+    let request = Timespec {
+        tv_sec: (ns / 1_000_000_000) as i64,
+        tv_nsec: (ns % 1_000_000_000) as i64,
+    };
+
+    unsafe {
+        asm!(
+            "
+            push rdi
+            mov rdi, 34
+
+            push r11
+            push rcx
+        
+            syscall
+        
+            pop rcx
+            pop r11
+            pop rdi
+            ",
+            options(nostack),
+            in("r8") &request as *const Timespec,
+            lateout("rax") _,
+        );
+    }
+}
+
 pub struct Printer {}
 
 impl core::fmt::Write for Printer {
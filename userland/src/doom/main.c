@@ -30,6 +30,80 @@ void allocate_stack_memory() {
   mini_print("Allocated large stack memory\n");
 }
 
+// Returns the letter or digit on a key at the position of the keycode on a US
+// keyboard or 0 for other keys
+char key_position_character(uint8_t keycode) {
+  if (keycode >= 0x02 && keycode <= 0x0b) {
+    return "1234567890"[keycode - 0x02];
+  }
+  if (keycode >= 0x10 && keycode <= 0x19) {
+    return "qwertyuiop"[keycode - 0x10];
+  }
+  if (keycode >= 0x1e && keycode <= 0x26) {
+    return "asdfghjkl"[keycode - 0x1e];
+  }
+  if (keycode >= 0x2c && keycode <= 0x32) {
+    return "zxcvbnm"[keycode - 0x2c];
+  }
+  return 0;
+}
+
+// Maps the keys to the ones of Doom, WASD moves like the arrow keys
+doom_key_t translate_key(const struct key_event *event) {
+  switch (event->keycode) {
+  case KEY_UP:
+    return DOOM_KEY_UP_ARROW;
+  case KEY_LEFT:
+    return DOOM_KEY_LEFT_ARROW;
+  case KEY_DOWN:
+    return DOOM_KEY_DOWN_ARROW;
+  case KEY_RIGHT:
+    return DOOM_KEY_RIGHT_ARROW;
+  case KEY_LEFT_CTRL:
+  case KEY_RIGHT_CTRL:
+    return DOOM_KEY_CTRL;
+  case KEY_LEFT_SHIFT:
+  case KEY_RIGHT_SHIFT:
+    return DOOM_KEY_SHIFT;
+  case KEY_LEFT_ALT:
+  case KEY_RIGHT_ALT:
+    return DOOM_KEY_ALT;
+  case KEY_ENTER:
+  case KEY_KEYPAD_ENTER:
+    return DOOM_KEY_ENTER;
+  case KEY_ESCAPE:
+    return DOOM_KEY_ESCAPE;
+  case KEY_BACKSPACE:
+    return DOOM_KEY_BACKSPACE;
+  case KEY_TAB:
+    return DOOM_KEY_TAB;
+  case KEY_SPACE:
+    return DOOM_KEY_SPACE;
+  }
+
+  // letters and digits are mapped by the position of the key, so a key is
+  // released as the same Doom key it was pressed as regardless of the
+  // modifiers held meanwhile
+  char character = key_position_character(event->keycode);
+
+  switch (character) {
+  case 'w':
+    return DOOM_KEY_UP_ARROW;
+  case 'a':
+    return DOOM_KEY_LEFT_ARROW;
+  case 's':
+    return DOOM_KEY_DOWN_ARROW;
+  case 'd':
+    return DOOM_KEY_RIGHT_ARROW;
+  }
+
+  if (character != 0) {
+    return (doom_key_t)character;
+  }
+
+  return DOOM_KEY_UNKNOWN;
+}
+
 void _start() {
   mini_print("Hallo Carina\n");
 
@@ -54,44 +128,24 @@ void _start() {
     doom_update();
     draw_framebuffer(doom_get_framebuffer(1));
 
-    if (get_keystate(0)) {
-      doom_key_down(DOOM_KEY_UP_ARROW);
-    } else {
-      doom_key_up(DOOM_KEY_UP_ARROW);
-    }
-
-    if (get_keystate(1)) {
-      doom_key_down(DOOM_KEY_LEFT_ARROW);
-    } else {
-      doom_key_up(DOOM_KEY_LEFT_ARROW);
-    }
+    // Doom runs at 35 tics per second, there is no point in rendering more
+    // frames
+    sleep_ms(1000 / 35);
 
-    if (get_keystate(2)) {
-      doom_key_down(DOOM_KEY_DOWN_ARROW);
-    } else {
-      doom_key_up(DOOM_KEY_DOWN_ARROW);
-    }
+    struct key_event events[32];
+    int count = read_key_events(events, 32);
 
-    if (get_keystate(3)) {
-      doom_key_down(DOOM_KEY_RIGHT_ARROW);
-    } else {
-      doom_key_up(DOOM_KEY_RIGHT_ARROW);
-    }
-
-    if (get_keystate(4)) {
-      doom_key_down(DOOM_KEY_CTRL);
-    } else {
-      doom_key_up(DOOM_KEY_CTRL);
-    }
-
-    if (get_keystate(5)) {
-      doom_key_down(DOOM_KEY_SPACE);
-    } else {
-      doom_key_up(DOOM_KEY_SPACE);
-    }
+    for (int i = 0; i < count; i++) {
+      doom_key_t key = translate_key(&events[i]);
+      if (key == DOOM_KEY_UNKNOWN) {
+        continue;
+      }
 
-    if (get_keystate(6)) {
-      doom_key_down(DOOM_KEY_ENTER);
+      if (events[i].pressed) {
+        doom_key_down(key);
+      } else {
+        doom_key_up(key);
+      }
     }
   }
 }
\ No newline at end of file
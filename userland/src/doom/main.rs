@@ -40,7 +40,7 @@ pub fn _start() {
         printf!("ftell: {}\n", libc::ftell());
         printf!("feof: {}\n", libc::feof());
 
-        // busy loop to not only spend time in syscalls :-)
-        for i in 0..1000000 {}
+        // give the other processes some time
+        libc::nanosleep(10_000_000);
     }
 }
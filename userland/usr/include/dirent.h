@@ -1,3 +1,4 @@
+#include "stddef.h"
 #include "sys/stat.h"
 
 /* File types for `d_type'.  */
@@ -24,8 +25,26 @@ struct dirent64 {
   char d_name[256]; /* We must not include limits.h! */
 };
 
-typedef struct dirent64 DIR;
+/* Record as returned by getdents64, followed by the null terminated name.  */
+struct linux_dirent64 {
+  unsigned long long d_ino;
+  long long d_off;
+  unsigned short int d_reclen;
+  unsigned char d_type;
+  char d_name[];
+};
+
+/* Directory stream, buffering the records of one getdents64 call.  */
+typedef struct __dirstream {
+  int fd;
+  size_t pos;
+  size_t len;
+  char buf[4096];
+  struct dirent64 entry;
+} DIR;
+
+ssize_t getdents64(int fd, void *dirp, size_t count);
 
 DIR *opendir(const char *name);
-DIR *readdir64(DIR *dirp);
+struct dirent64 *readdir64(DIR *dirp);
 int closedir(DIR *dirp);
\ No newline at end of file
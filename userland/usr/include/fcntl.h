@@ -21,6 +21,7 @@
 #define O_SYNC 010000
 #define O_FSYNC O_SYNC
 #define O_ASYNC 020000
+#define O_DIRECTORY 0200000
 
 #define FD_CLOEXEC 1
 
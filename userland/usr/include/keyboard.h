@@ -0,0 +1,62 @@
+#ifndef __KEYBOARD_H__
+#define __KEYBOARD_H__
+
+#include "stdint.h"
+
+/* Key events of the kernel, see keyboard.rs */
+
+/* keycodes are the make codes of PS/2 scancode set 1, keys sent after the
+ * 0xE0 prefix are or'ed with KEY_EXTENDED */
+#define KEY_EXTENDED 0x80
+
+#define KEY_ESCAPE 0x01
+#define KEY_BACKSPACE 0x0e
+#define KEY_TAB 0x0f
+#define KEY_ENTER 0x1c
+#define KEY_LEFT_CTRL 0x1d
+#define KEY_LEFT_SHIFT 0x2a
+#define KEY_RIGHT_SHIFT 0x36
+#define KEY_LEFT_ALT 0x38
+#define KEY_SPACE 0x39
+#define KEY_CAPS_LOCK 0x3a
+#define KEY_F1 0x3b
+#define KEY_F10 0x44
+#define KEY_NUM_LOCK 0x45
+#define KEY_F11 0x57
+#define KEY_F12 0x58
+#define KEY_KEYPAD_ENTER (KEY_EXTENDED | 0x1c)
+#define KEY_RIGHT_CTRL (KEY_EXTENDED | 0x1d)
+#define KEY_RIGHT_ALT (KEY_EXTENDED | 0x38)
+#define KEY_HOME (KEY_EXTENDED | 0x47)
+#define KEY_UP (KEY_EXTENDED | 0x48)
+#define KEY_PAGE_UP (KEY_EXTENDED | 0x49)
+#define KEY_LEFT (KEY_EXTENDED | 0x4b)
+#define KEY_RIGHT (KEY_EXTENDED | 0x4d)
+#define KEY_END (KEY_EXTENDED | 0x4f)
+#define KEY_DOWN (KEY_EXTENDED | 0x50)
+#define KEY_PAGE_DOWN (KEY_EXTENDED | 0x51)
+#define KEY_INSERT (KEY_EXTENDED | 0x52)
+#define KEY_DELETE (KEY_EXTENDED | 0x53)
+
+/* modifiers */
+#define KEY_MODIFIER_SHIFT (1 << 0)
+#define KEY_MODIFIER_CTRL (1 << 1)
+#define KEY_MODIFIER_ALT (1 << 2)
+#define KEY_MODIFIER_ALT_GR (1 << 3)
+#define KEY_MODIFIER_CAPS_LOCK (1 << 4)
+#define KEY_MODIFIER_NUM_LOCK (1 << 5)
+
+struct key_event {
+  uint64_t timestamp_ns; /* time since boot */
+  uint8_t keycode;
+  uint8_t pressed; /* 0 when the key was released */
+  uint8_t modifiers;
+  uint8_t character; /* Latin-1 text of the key, 0 if there is none */
+  uint8_t _reserved[4];
+};
+
+/* Returns the events since the previous call without blocking, at most count.
+ * Events are only recorded for a process after its first call. */
+int read_key_events(struct key_event *events, int count);
+
+#endif
@@ -6,6 +6,7 @@
 #include "errno.h"
 #include "fcntl.h"
 #include "inttypes.h"
+#include "keyboard.h"
 #include "setjmp.h"
 #include "signal.h"
 #include "stdbool.h"
@@ -14,6 +15,8 @@
 #include "stdio.h"
 #include "stdlib.h"
 #include "string.h"
+#include "sys/mman.h"
+#include "sys/time.h"
 #include "sys/times.h"
 #include "termios.h"
 #include "wchar.h"
@@ -50,7 +53,6 @@ void draw_pixel(uint32_t x, uint32_t y, uint8_t color);
 
 uint64_t draw_framebuffer(const uint8_t *framebuffer);
 uint64_t switch_vga_mode(bool vga_on);
-bool get_keystate(int key);
 void get_time(int *sec, int *usec);
 
 #endif // __LIBC_H__
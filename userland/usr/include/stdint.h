@@ -1,3 +1,3 @@
-
 typedef unsigned long int uintptr_t;
+typedef long int intptr_t;
 typedef long long int intmax_t;
\ No newline at end of file
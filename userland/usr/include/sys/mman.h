@@ -1 +1,27 @@
-int memfd_create(const char *name, unsigned int flags);
\ No newline at end of file
+#ifndef __SYS_MMAN_H__
+#define __SYS_MMAN_H__
+
+#include "../stddef.h"
+#include "stat.h"
+
+#define PROT_NONE 0x0  /* Page can not be accessed.  */
+#define PROT_READ 0x1  /* Page can be read.  */
+#define PROT_WRITE 0x2 /* Page can be written.  */
+#define PROT_EXEC 0x4  /* Page can be executed.  */
+
+#define MAP_SHARED 0x01    /* Share changes.  */
+#define MAP_PRIVATE 0x02   /* Changes are private.  */
+#define MAP_FIXED 0x10     /* Interpret addr exactly.  */
+#define MAP_ANONYMOUS 0x20 /* Don't use a file.  */
+#define MAP_ANON MAP_ANONYMOUS
+
+#define MAP_FAILED ((void *)-1)
+
+void *mmap(void *addr, size_t length, int prot, int flags, int fd,
+           off_t offset);
+int munmap(void *addr, size_t length);
+int mprotect(void *addr, size_t length, int prot);
+
+int memfd_create(const char *name, unsigned int flags);
+
+#endif // __SYS_MMAN_H__
@@ -5,6 +5,8 @@ typedef unsigned long rlim_t;
 
 #define RLIM_INFINITY (~(rlim_t)0)
 
+#define RLIMIT_STACK 3 /* Maximum size of the stack, in bytes.  */
+
 struct rlimit {
     rlim_t rlim_cur; /* Soft limit: current limit */
     rlim_t rlim_max; /* Hard limit: maximum value for rlim_cur */
@@ -0,0 +1,15 @@
+#ifndef __SYS_TIME_H__
+#define __SYS_TIME_H__
+
+#include "../time.h"
+
+/* obsolete, the kernel clock runs in UTC */
+struct timezone
+{
+  int tz_minuteswest;
+  int tz_dsttime;
+};
+
+int gettimeofday(struct timeval *tv, struct timezone *tz);
+
+#endif
@@ -9,4 +9,25 @@ struct timeval
   long int tv_usec;
 };
 
+struct timespec
+{
+  time_t tv_sec;
+  long int tv_nsec;
+};
+
+typedef int clockid_t;
+
+#define CLOCK_REALTIME 0
+#define CLOCK_MONOTONIC 1
+
+/* flag of clock_nanosleep: the request is an absolute time of the clock */
+#define TIMER_ABSTIME 1
+
+int clock_gettime(clockid_t clockid, struct timespec *tp);
+time_t time(time_t *tloc);
+
+int nanosleep(const struct timespec *req, struct timespec *rem);
+int clock_nanosleep(clockid_t clockid, int flags, const struct timespec *req,
+                    struct timespec *rem);
+
 #endif
\ No newline at end of file
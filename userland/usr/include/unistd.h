@@ -2,6 +2,7 @@
 #include "ctype.h"
 #include "limits.h"
 #include "stddef.h"
+#include "stdint.h"
 #include "sys/stat.h"
 #include "termios.h"
 
@@ -46,6 +47,12 @@ gid_t getegid(void);
 
 ssize_t write(int fd, const void *buf, size_t count);
 
+int brk(void *addr);
+void *sbrk(intptr_t increment);
+
+int unlink(const char *pathname);
+int ftruncate(int fd, off_t length);
+
 /*  These may be OR'd together.  */
 #define R_OK 4 /* Test for read permission.  */
 #define W_OK 2 /* Test for write permission.  */
@@ -62,6 +69,9 @@ int faccessat(int dirfd, const char *pathname, int mode, int flags);
 
 long sysconf(int name);
 
+unsigned int sleep(unsigned int seconds);
+int usleep(unsigned int usec);
+
 #define _SC_CLK_TCK 100
 
 typedef long lseek_t;
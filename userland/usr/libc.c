@@ -8,13 +8,15 @@ void *_DYNAMIC = 0;
 
 __attribute__((naked)) void _start(void) {
   asm volatile(
-      // initialize with zero arguments
-      "xor %rdi, %rdi\n"
-      "xor %rsi, %rsi\n"
-      "xor %rdx, %rdx\n"
+      // the kernel puts argc, argv and envp onto the stack (System V ABI)
+      "mov (%rsp), %rdi\n"
+      "lea 8(%rsp), %rsi\n"
+      "lea 16(%rsp,%rdi,8), %rdx\n"
+      "mov %rdx, environ(%rip)\n"
       "call main\n"
-      "mov %rax, %rdi\n"
-      "mov $60, %rax\n"
+      // exit with the return value of main, see DO_SYSCALL for the registers
+      "mov %rax, %r8\n"
+      "mov $60, %rdi\n"
       "syscall\n");
 }
 
@@ -86,16 +88,214 @@ void draw_pixel(uint32_t x, uint32_t y, uint8_t color) {
   DO_SYSCALL(3, result, x, y, color);
 }
 
+// Heap blocks start with a header; free blocks are kept in a list sorted by
+// address, so that neighbouring free blocks can be merged again
+struct block_header {
+  size_t size; // including the header, MMAPPED_BLOCK marks blocks from mmap
+  struct block_header *next_free;
+};
+
+#define BLOCK_ALIGNMENT 16
+#define MMAPPED_BLOCK 1
+#define MMAP_THRESHOLD (128 * 1024) // larger blocks get their own mapping
+#define HEAP_INCREMENT (64 * 1024)  // minimum amount to move the break by
+
+static struct block_header *free_blocks = NULL;
+static void *current_break = NULL;
+
+// Set the program break
+int brk(void *addr) {
+  uint64_t result;
+  DO_SYSCALL(38, result, (uintptr_t)addr, 0, 0);
+
+  // the kernel returns the unchanged break on failure
+  if (result != (uintptr_t)addr) {
+    errno = ENOMEM;
+    return -1;
+  }
+
+  current_break = addr;
+  return 0;
+}
+
+// Move the program break, returns the previous one
+void *sbrk(intptr_t increment) {
+  if (current_break == NULL) {
+    uint64_t result;
+    DO_SYSCALL(38, result, 0, 0, 0);
+    current_break = (void *)result;
+  }
+
+  void *old_break = current_break;
+
+  if (increment != 0 && brk((char *)current_break + increment) != 0) {
+    return (void *)-1;
+  }
+
+  return old_break;
+}
+
+// Map memory, only private anonymous mappings are supported
+void *mmap(void *addr, size_t length, int prot, int flags, int fd,
+           off_t offset) {
+  // there are more arguments than syscall registers, see MmapArguments in
+  // the kernel
+  struct {
+    uint64_t address;
+    uint64_t length;
+    unsigned int prot;
+    unsigned int flags;
+    int fd;
+    long offset;
+  } arguments = {(uintptr_t)addr, length, prot, flags, fd, offset};
+
+  uint64_t result;
+  DO_SYSCALL(39, result, (uintptr_t)&arguments, 0, 0);
+
+  if (result == (uint64_t)-1) {
+    errno = ENOMEM;
+    return MAP_FAILED;
+  }
+
+  return (void *)result;
+}
+
+// Unmap memory
+int munmap(void *addr, size_t length) {
+  uint64_t result;
+  DO_SYSCALL(40, result, (uintptr_t)addr, length, 0);
+
+  if (result == (uint64_t)-1) {
+    errno = EINVAL;
+    return -1;
+  }
+
+  return 0;
+}
+
+// Change the protection of mapped memory
+int mprotect(void *addr, size_t length, int prot) {
+  uint64_t result;
+  DO_SYSCALL(41, result, (uintptr_t)addr, length, prot);
+
+  if (result == (uint64_t)-1) {
+    errno = ENOMEM;
+    return -1;
+  }
+
+  return 0;
+}
+
+static void insert_free_block(struct block_header *block) {
+  struct block_header *previous = NULL;
+  struct block_header *next = free_blocks;
+
+  while (next != NULL && next < block) {
+    previous = next;
+    next = next->next_free;
+  }
+
+  block->next_free = next;
+
+  if (next != NULL && (char *)block + block->size == (char *)next) {
+    block->size += next->size;
+    block->next_free = next->next_free;
+  }
+
+  if (previous == NULL) {
+    free_blocks = block;
+  } else if ((char *)previous + previous->size == (char *)block) {
+    previous->size += block->size;
+    previous->next_free = block->next_free;
+  } else {
+    previous->next_free = block;
+  }
+}
+
+// Removes the first free block large enough and splits off what is not needed
+static struct block_header *take_free_block(size_t block_size) {
+  struct block_header **link = &free_blocks;
+
+  while (*link != NULL) {
+    struct block_header *block = *link;
+
+    if (block->size >= block_size) {
+      if (block->size - block_size >= sizeof(struct block_header) * 2) {
+        struct block_header *rest =
+            (struct block_header *)((char *)block + block_size);
+        rest->size = block->size - block_size;
+        rest->next_free = block->next_free;
+        *link = rest;
+        block->size = block_size;
+      } else {
+        *link = block->next_free;
+      }
+
+      return block;
+    }
+
+    link = &block->next_free;
+  }
+
+  return NULL;
+}
+
 // Allocate memory
 void *malloc(long unsigned int size) {
-  uint64_t address;
-  DO_SYSCALL(4, address, size, 0, 0); // Only size is passed
-  return (void *)address;
+  if (size > (size_t)-1 / 2) {
+    errno = ENOMEM;
+    return NULL;
+  }
+
+  size_t block_size = (size + sizeof(struct block_header) + BLOCK_ALIGNMENT -
+                       1) & ~(size_t)(BLOCK_ALIGNMENT - 1);
+
+  if (block_size >= MMAP_THRESHOLD) {
+    struct block_header *block =
+        mmap(NULL, block_size, PROT_READ | PROT_WRITE,
+             MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (block == MAP_FAILED) {
+      return NULL;
+    }
+
+    block->size = block_size | MMAPPED_BLOCK;
+    return block + 1;
+  }
+
+  struct block_header *block = take_free_block(block_size);
+
+  if (block == NULL) {
+    size_t increment =
+        block_size < HEAP_INCREMENT ? HEAP_INCREMENT : block_size;
+
+    block = sbrk(increment);
+    if (block == (void *)-1) {
+      return NULL;
+    }
+
+    block->size = increment;
+    insert_free_block(block);
+
+    block = take_free_block(block_size);
+  }
+
+  return block + 1;
 }
 
-// Free memory (currently does nothing) --> leaks memory
+// Free memory
 void free(void *address) {
-  // TODO: Implement the free function
+  if (address == NULL) {
+    return;
+  }
+
+  struct block_header *block = (struct block_header *)address - 1;
+
+  if (block->size & MMAPPED_BLOCK) {
+    munmap(block, block->size & ~(size_t)MMAPPED_BLOCK);
+    return;
+  }
+
+  insert_free_block(block);
 }
 
 // Open a file
@@ -105,6 +305,10 @@ void *fopen(const char *filename, const char *options) {
 
   uint64_t handle;
   DO_SYSCALL(5, handle, filename, options, 0);
+
+  if (handle == (uint64_t)-1) {
+    return NULL;
+  }
   return (void *)handle;
 }
 
@@ -113,11 +317,18 @@ int fclose(FILE *stream) {
   // TODO: Implement the fclose function
 }
 
-// Write to a file (currently does nothing)
-long unsigned int fwrite(const void *, long unsigned int, long unsigned int,
-                         void *) {
-  write(1, "fwrite: ", strlen("fwrite: "));
-  return 0; // TODO: Implement fwrite
+// Write to a file
+long unsigned int fwrite(const void *ptr, long unsigned int size,
+                         long unsigned int nmemb, void *stream) {
+  if (size == 0 || nmemb == 0) {
+    return 0;
+  }
+
+  ssize_t written = write(((FILE *)stream)->fd, ptr, size * nmemb);
+  if (written < 0) {
+    return 0;
+  }
+  return written / size;
 }
 
 // Seek within a file
@@ -162,11 +373,14 @@ uint64_t switch_vga_mode(bool vga_on) {
   return result;
 }
 
-// Get the state of a key
-bool get_keystate(int key) {
-  uint64_t state;
-  DO_SYSCALL(12, state, key, 0, 0);
-  return (bool)state;
+int read_key_events(struct key_event *events, int count) {
+  uint64_t result;
+  DO_SYSCALL(12, result, (uintptr_t)events, count, 0);
+
+  if (result == (uint64_t)-1) {
+    return -1;
+  }
+  return (int)result;
 }
 
 void get_time(int *sec, int *usec) {
@@ -808,10 +1022,10 @@ char *strtok(char *str, const char *delim) {
   return start;
 }
 
-// Mock close
 int close(int fd) {
-  // TODO: Implement the close function
-  return 0;
+  uint64_t result;
+  DO_SYSCALL(27, result, fd, 0, 0);
+  return (int)result;
 }
 
 // implement bsearch
@@ -927,7 +1141,7 @@ int chdir(const char *path) {
 }
 
 char *getcwd(char *buf, size_t size) {
-  char result;
+  uint64_t result;
   DO_SYSCALL(16, result, buf, size, 0);
 
   if (result == 0) {
@@ -1025,6 +1239,95 @@ clock_t times(struct tms *buf) {
   return 0;
 }
 
+int clock_gettime(clockid_t clockid, struct timespec *tp) {
+  uint64_t result;
+  DO_SYSCALL(36, result, clockid, (uintptr_t)tp, 0);
+
+  if (result == (uint64_t)-1) {
+    errno_value = EINVAL;
+    return -1;
+  }
+  return 0;
+}
+
+int gettimeofday(struct timeval *tv, struct timezone *tz) {
+  uint64_t result;
+  DO_SYSCALL(37, result, (uintptr_t)tv, 0, 0);
+
+  if (tz) {
+    tz->tz_minuteswest = 0;
+    tz->tz_dsttime = 0;
+  }
+
+  if (result == (uint64_t)-1) {
+    errno_value = EFAULT;
+    return -1;
+  }
+  return 0;
+}
+
+time_t time(time_t *tloc) {
+  struct timespec now;
+
+  if (clock_gettime(CLOCK_REALTIME, &now) == -1) {
+    return (time_t)-1;
+  }
+
+  if (tloc) {
+    *tloc = now.tv_sec;
+  }
+  return now.tv_sec;
+}
+
+int nanosleep(const struct timespec *req, struct timespec *rem) {
+  uint64_t result;
+  DO_SYSCALL(34, result, (uintptr_t)req, 0, 0);
+
+  if (result == (uint64_t)-1) {
+    errno_value = EINVAL;
+    return -1;
+  }
+
+  // a sleep is never interrupted, so nothing remains
+  if (rem) {
+    rem->tv_sec = 0;
+    rem->tv_nsec = 0;
+  }
+  return 0;
+}
+
+// returns the error number instead of setting errno
+int clock_nanosleep(clockid_t clockid, int flags, const struct timespec *req,
+                    struct timespec *rem) {
+  uint64_t result;
+  DO_SYSCALL(35, result, clockid, flags, (uintptr_t)req);
+
+  if (result == (uint64_t)-1) {
+    return EINVAL;
+  }
+
+  if (rem && !(flags & TIMER_ABSTIME)) {
+    rem->tv_sec = 0;
+    rem->tv_nsec = 0;
+  }
+  return 0;
+}
+
+unsigned int sleep(unsigned int seconds) {
+  struct timespec req = {.tv_sec = seconds, .tv_nsec = 0};
+
+  if (nanosleep(&req, 0) == -1) {
+    return seconds;
+  }
+  return 0;
+}
+
+int usleep(unsigned int usec) {
+  struct timespec req = {.tv_sec = usec / 1000000,
+                         .tv_nsec = (usec % 1000000) * 1000};
+  return nanosleep(&req, 0);
+}
+
 long sysconf(int name) {
   // TODO implement
   char *msg = "TODO implement sysconf\n";
@@ -1033,17 +1336,15 @@ long sysconf(int name) {
 }
 
 int dup(int fildes) {
-  // TODO implement
-  char *msg = "TODO implement dup\n";
-  write(1, msg, strlen(msg));
-  return -1;
+  uint64_t result;
+  DO_SYSCALL(25, result, fildes, 0, 0);
+  return (int)result;
 }
 
 int dup2(int oldfd, int newfd) {
-  // TODO implement
-  char *msg = "TODO implement dup2\n";
-  write(1, msg, strlen(msg));
-  return -1;
+  uint64_t result;
+  DO_SYSCALL(26, result, oldfd, newfd, 0);
+  return (int)result;
 }
 
 int strcoll(const char *s1, const char *s2) {
@@ -1230,23 +1531,15 @@ int sigfillset(sigset_t *set) {
 }
 
 int fcntl(int fildes, int cmd, ...) {
-  char *msg = "TODO implement fcntl\n";
-  write(1, msg, strlen(msg));
-
-  if (cmd == F_DUPFD) {
-    // va_list args;
-    // va_start(args, cmd);
-    // int newfd = va_arg(args, int);
-    // va_end(args);
-
-    // For simplicity, just return newfd
-    // return newfd;
-
-    // shortcut: just return the same fildes
-    return fildes;
-  }
+  // all supported commands take at most one int argument
+  va_list args;
+  va_start(args, cmd);
+  int arg = va_arg(args, int);
+  va_end(args);
 
-  return -1;
+  uint64_t result;
+  DO_SYSCALL(28, result, fildes, cmd, arg);
+  return (int)result;
 }
 
 void *signal(int, void (*)(int)) {
@@ -1264,9 +1557,9 @@ lseek_t lseek(int fd, lseek_t offset, int whence) {
 }
 
 int pipe(int pipefd[2]) {
-  char *msg = "TODO implement pipe\n";
-  write(1, msg, strlen(msg));
-  return -1;
+  uint64_t result;
+  DO_SYSCALL(29, result, (uintptr_t)pipefd, 0, 0);
+  return (int)result;
 }
 
 int memfd_create(const char *name, unsigned int flags) {
@@ -1282,24 +1575,56 @@ int fstat(int fd, struct stat *buf) {
 }
 
 int open64(const char *pathname, int oflag, ...) {
-  // use open syscall
-  uint64_t handle;
-  DO_SYSCALL(5, handle, pathname, oflag, 0);
-  return (int)handle;
+  uint64_t fd;
+  DO_SYSCALL(30, fd, pathname, oflag, 0);
+
+  if (fd == (uint64_t)-1) {
+    return -1;
+  }
+  return (int)fd;
+}
+
+int unlink(const char *pathname) {
+  uint64_t result;
+  DO_SYSCALL(31, result, pathname, 0, 0);
+
+  if (result == (uint64_t)-1) {
+    return -1;
+  }
+  return 0;
 }
 
+int ftruncate(int fd, off_t length) {
+  uint64_t result;
+  DO_SYSCALL(32, result, fd, length, 0);
+
+  if (result == (uint64_t)-1) {
+    return -1;
+  }
+  return 0;
+}
+
+// Only RLIMIT_STACK is supported
 int getrlimit(int resource, struct rlimit *rlim) {
-  // TODO implement getrlimit
-  char *msg = "TODO implement getrlimit\n";
-  write(1, msg, strlen(msg));
-  return -1;
+  uint64_t result;
+  DO_SYSCALL(42, result, resource, rlim, 0);
+
+  if (result == (uint64_t)-1) {
+    errno = EINVAL;
+    return -1;
+  }
+  return 0;
 }
 
 int setrlimit(int resource, const struct rlimit *rlim) {
-  // TODO implement setrlimit
-  char *msg = "TODO implement setrlimit\n";
-  write(1, msg, strlen(msg));
-  return -1;
+  uint64_t result;
+  DO_SYSCALL(43, result, resource, rlim, 0);
+
+  if (result == (uint64_t)-1) {
+    errno = EINVAL;
+    return -1;
+  }
+  return 0;
 }
 
 mode_t umask(mode_t mask) {
@@ -1318,14 +1643,20 @@ void *realloc(void *ptr, size_t size) {
     return NULL;
   }
 
-  // For simplicity, we won't track the original size of ptr.
-  // We'll just allocate new memory and copy a fixed amount.
+  struct block_header *block = (struct block_header *)ptr - 1;
+  size_t capacity =
+      (block->size & ~(size_t)MMAPPED_BLOCK) - sizeof(struct block_header);
+
+  if (size <= capacity) {
+    return ptr;
+  }
+
   void *new_ptr = malloc(size);
   if (new_ptr == NULL) {
     return NULL;
   }
 
-  memcpy(new_ptr, ptr, size);
+  memcpy(new_ptr, ptr, capacity);
   free(ptr);
   return new_ptr;
 }
@@ -1351,13 +1682,33 @@ int sigsuspend(const sigset_t *sigmask) {
   return -1;
 }
 
-int wait3(int *status, int options, struct rusage *rusage) {
-  // TODO implement wait3
-  char *msg = "TODO implement wait3\n";
-  write(1, msg, strlen(msg));
-  return -1;
+pid_t wait4(pid_t pid, int *status, int options, struct rusage *rusage) {
+  uint64_t result;
+  DO_SYSCALL(24, result, pid, (uintptr_t)status, options);
+
+  if (result == (uint64_t)-1) {
+    errno_value = ECHILD;
+    return -1;
+  }
+
+  // resource usage is not tracked
+  if (rusage) {
+    memset(rusage, 0, sizeof(struct rusage));
+  }
+
+  return (pid_t)result;
+}
+
+pid_t wait3(int *status, int options, struct rusage *rusage) {
+  return wait4(-1, status, options, rusage);
 }
 
+pid_t waitpid(pid_t pid, int *status, int options) {
+  return wait4(pid, status, options, NULL);
+}
+
+pid_t wait(int *status) { return wait4(-1, status, 0, NULL); }
+
 int raise(int sig) { return kill(getpid(), sig); }
 
 pid_t vfork(void) {
@@ -1367,10 +1718,9 @@ pid_t vfork(void) {
 }
 
 pid_t fork(void) {
-  // TODO implement fork
-  char *msg = "TODO implement fork\n";
-  write(1, msg, strlen(msg));
-  return -1;
+  uint64_t result;
+  DO_SYSCALL(23, result, 0, 0, 0);
+  return (pid_t)result;
 }
 
 int setpgid(pid_t pid, pid_t pgid) {
@@ -1472,23 +1822,58 @@ void *memrchr(const void *s, int c, size_t n) {
   return NULL;
 }
 
+ssize_t getdents64(int fd, void *dirp, size_t count) {
+  uint64_t result;
+  DO_SYSCALL(33, result, fd, dirp, count);
+  return result;
+}
+
 DIR *opendir(const char *name) {
-  // TODO implement opendir
-  char *msg = "TODO implement opendir\n";
-  write(1, msg, strlen(msg));
-  return NULL;
+  int fd = open64(name, O_RDONLY | O_DIRECTORY);
+  if (fd < 0) {
+    errno_value = ENOTDIR;
+    return NULL;
+  }
+
+  DIR *dirp = malloc(sizeof(DIR));
+  if (dirp == NULL) {
+    close(fd);
+    return NULL;
+  }
+
+  dirp->fd = fd;
+  dirp->pos = 0;
+  dirp->len = 0;
+  return dirp;
 }
 
-DIR *readdir64(DIR *dirp) {
-  // TODO implement readdir64
-  char *msg = "TODO implement readdir64\n";
-  write(1, msg, strlen(msg));
-  return NULL;
+struct dirent64 *readdir64(DIR *dirp) {
+  if (dirp->pos >= dirp->len) {
+    uint64_t len = getdents64(dirp->fd, dirp->buf, sizeof(dirp->buf));
+    // 0 is the end of the directory, -1 an error
+    if (len == 0 || len == (uint64_t)-1) {
+      return NULL;
+    }
+    dirp->len = len;
+    dirp->pos = 0;
+  }
+
+  struct linux_dirent64 *record =
+      (struct linux_dirent64 *)(dirp->buf + dirp->pos);
+  dirp->pos += record->d_reclen;
+
+  dirp->entry.d_ino = record->d_ino;
+  dirp->entry.d_off = record->d_off;
+  dirp->entry.d_reclen = sizeof(struct dirent64);
+  dirp->entry.d_type = record->d_type;
+  strncpy(dirp->entry.d_name, record->d_name, sizeof(dirp->entry.d_name) - 1);
+  dirp->entry.d_name[sizeof(dirp->entry.d_name) - 1] = '\0';
+
+  return &dirp->entry;
 }
 
 int closedir(DIR *dirp) {
-  // TODO implement closedir
-  char *msg = "TODO implement closedir\n";
-  write(1, msg, strlen(msg));
-  return -1;
+  int result = close(dirp->fd);
+  free(dirp);
+  return result;
 }
\ No newline at end of file